@@ -11,6 +11,7 @@ archive() {
   mkdir -p "$dist"
   cp "target/$arch/release/rusmtpc" "$dist/"
   cp "target/$arch/release/rusmtpd" "$dist/"
+  cp "target/$arch/release/rusmtpq" "$dist/"
   cp distribution/rusmtprc.default "$dist/"
   cp distribution/install "$dist/"
   cp distribution/uninstall "$dist/"
@@ -20,6 +21,7 @@ archive() {
   cp README.md "$dist/"
   cp doc/rusmtpd.1 "$dist/"
   cp doc/rusmtpc.1 "$dist/"
+  cp doc/rusmtpq.1 "$dist/"
 
   tar -czf "archives/$dist.tar.gz" "$dist"
 }
@@ -0,0 +1,134 @@
+// Builds the emails that are handed to the daemon, refusing the ones that
+// cannot be serialized or sent.
+
+use common::Error;
+use common::mail::Mail;
+use protocol::dsn::{parse_envid, parse_notify, parse_ret};
+
+/// The fields of the email are serialized after their length, in one byte
+const MAX_FIELD_LENGTH: usize = 255;
+
+/// Builds an email, e.g.
+/// `MailBuilder::default().recipient("you@example.com").body(body).build()`
+#[derive(Debug, Default)]
+pub struct MailBuilder {
+    account: Option<String>,
+    recipients: Vec<String>,
+    dsn_notify: Option<String>,
+    dsn_ret: Option<String>,
+    envid: Option<String>,
+    body: Vec<u8>,
+}
+
+impl MailBuilder {
+    /// The account that sends the email, the default account of the client
+    /// otherwise
+    pub fn account(mut self, account: &str) -> Self {
+        self.account = Some(account.to_string());
+        self
+    }
+
+    pub fn recipient(mut self, recipient: &str) -> Self {
+        self.recipients.push(recipient.to_string());
+        self
+    }
+
+    pub fn recipients<I, S>(mut self, recipients: I) -> Self
+            where I: IntoIterator<Item = S>, S: Into<String> {
+        self.recipients.extend(recipients.into_iter().map(Into::into));
+        self
+    }
+
+    /// The whole message, with the headers
+    pub fn body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+
+    /// The NOTIFY parameter of the delivery status notifications, e.g.
+    /// SUCCESS,FAILURE
+    pub fn dsn_notify(mut self, notify: &str) -> Self {
+        self.dsn_notify = Some(notify.to_string());
+        self
+    }
+
+    /// The RET parameter of the delivery status notifications, HDRS or FULL
+    pub fn dsn_ret(mut self, ret: &str) -> Self {
+        self.dsn_ret = Some(ret.to_string());
+        self
+    }
+
+    /// The ENVID parameter of the delivery status notifications
+    pub fn envid(mut self, envid: &str) -> Self {
+        self.envid = Some(envid.to_string());
+        self
+    }
+
+    pub fn build(self) -> Result<Mail, Error> {
+        if self.recipients.is_empty() {
+            return Err(Error::Protocol("The email has no recipients".to_string()));
+        }
+        if let Some(account) = &self.account {
+            check_length("account name", account)?;
+        }
+        for recipient in &self.recipients {
+            check_length("recipient", recipient)?;
+        }
+
+        Ok(Mail {
+            account: self.account,
+            recipients: self.recipients,
+            dsn_notify: self.dsn_notify.as_ref().map(|notify| parse_notify(notify)).transpose()?,
+            dsn_ret: self.dsn_ret.as_ref().map(|ret| parse_ret(ret)).transpose()?,
+            envid: self.envid.as_ref().map(|envid| parse_envid(envid)).transpose()?,
+            body: self.body,
+        })
+    }
+}
+
+fn check_length(field: &str, value: &str) -> Result<(), Error> {
+    if value.is_empty() || value.len() > MAX_FIELD_LENGTH {
+        Err(Error::Protocol(format!("The {} should be 1 to {} bytes long: {}",
+                                    field, MAX_FIELD_LENGTH, value)))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build() {
+        let mail = MailBuilder::default()
+            .account("first")
+            .recipient("a@b.c")
+            .recipients(vec!["d@e.f", "g@h.i"])
+            .dsn_notify("failure,delay")
+            .dsn_ret("hdrs")
+            .envid("QQ314159")
+            .body(b"hi".to_vec())
+            .build()
+            .unwrap();
+        assert_eq!(Mail {
+            account: Some("first".to_string()),
+            recipients: vec!["a@b.c".to_string(), "d@e.f".to_string(), "g@h.i".to_string()],
+            dsn_notify: Some("FAILURE,DELAY".to_string()),
+            dsn_ret: Some("HDRS".to_string()),
+            envid: Some("QQ314159".to_string()),
+            body: b"hi".to_vec(),
+        }, mail);
+    }
+
+    #[test]
+    fn test_build_refuses_invalid_emails() {
+        assert!(MailBuilder::default().body(b"hi".to_vec()).build().is_err());
+        assert!(MailBuilder::default().recipient("").build().is_err());
+        assert!(MailBuilder::default().recipient(&"a".repeat(256)).build().is_err());
+        assert!(MailBuilder::default().account("").recipient("a@b.c").build().is_err());
+        assert!(MailBuilder::default().recipient("a@b.c").dsn_ret("ALL").build().is_err());
+        assert!(MailBuilder::default().recipient("a@b.c").dsn_notify("NEVER,DELAY")
+                .build().is_err());
+    }
+}
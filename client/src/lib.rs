@@ -0,0 +1,355 @@
+// A library to hand emails to a running rusmtpd, for the programs that
+// would rather not spawn rusmtpc for every email.
+
+mod builder;
+
+use common::get_socket_path;
+use common::config::{default_rc_path, read_config, Configuration};
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::net::Shutdown;
+use std::time::Duration;
+use std::error;
+use std::fmt;
+
+pub use crate::builder::MailBuilder;
+pub use common::Error;
+pub use common::mail::Mail;
+pub use common::response::{RecipientStatus, Response, Status};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Why an email was not sent to every recipient
+#[derive(Debug, PartialEq)]
+pub enum SendError {
+    /// Neither the email nor the client names an account
+    NoAccount,
+    /// The daemon is not running
+    Unavailable(Error),
+    /// The daemon stopped responding, or its response cannot be understood
+    Daemon(Error),
+    /// The daemon did not send the email to every recipient
+    Failed(Response),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SendError::NoAccount        =>
+                write!(f, "Please pass a valid account name or set a default account"),
+            SendError::Unavailable(msg) => write!(f, "The daemon is not running: {}", msg),
+            SendError::Daemon(msg)      => write!(f, "{}", msg),
+            SendError::Failed(response) => write!(f, "{}", response),
+        }
+    }
+}
+
+impl error::Error for SendError {}
+
+impl SendError {
+    /// The reply code of the server, if it got that far
+    pub fn code(&self) -> Option<u16> {
+        match self {
+            SendError::NoAccount          => None,
+            SendError::Unavailable(error) |
+            SendError::Daemon(error)      => error.code(),
+            SendError::Failed(response)   => response.code.or_else(||
+                response.rejected().first().map(|rejected| rejected.code)),
+        }
+    }
+}
+
+/// Sends the emails of the accounts of a rusmtprc through the daemon
+#[derive(Debug, Clone)]
+pub struct Client {
+    socket_root: String,
+    default_account: Option<String>,
+    timeout: Duration,
+}
+
+impl Client {
+    /// A client of the daemon that listens under the socket root, i.e. the
+    /// socket-root-path of the rusmtprc
+    pub fn new(socket_root: &str) -> Self {
+        Client {
+            socket_root: socket_root.to_string(),
+            default_account: None,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// A client of the daemon that runs with the configuration
+    pub fn from_config(conf: &Configuration) -> Self {
+        Client {
+            socket_root: conf.socket_root.clone(),
+            default_account: conf.accounts.iter()
+                .find(|account| account.default)
+                .map(|account| account.label.clone()),
+            timeout: Duration::from_secs(conf.timeout),
+        }
+    }
+
+    /// A client of the daemon that runs with the rusmtprc at the path
+    pub fn from_config_file(rc_path: &str) -> Result<Self, Error> {
+        Ok(Client::from_config(&read_config(rc_path)?))
+    }
+
+    /// A client of the daemon that runs with ~/.rusmtprc, like rusmtpc
+    /// without --rusmtprc
+    pub fn discover() -> Result<Self, Error> {
+        Client::from_config_file(&default_rc_path()?)
+    }
+
+    /// How long to wait for the daemon to send the email
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The account of the emails that do not name one
+    pub fn default_account(mut self, account: &str) -> Self {
+        self.default_account = Some(account.to_string());
+        self
+    }
+
+    /// Sends the email through the daemon of its account, or of the
+    /// default account
+    pub fn send(&self, mail: &Mail) -> Result<Response, SendError> {
+        let account = mail.account.as_ref()
+            .or(self.default_account.as_ref())
+            .ok_or(SendError::NoAccount)?;
+        send_to_daemon(mail, &self.socket_root, self.timeout, account)
+    }
+}
+
+/// The email that should be retried after the failure, if any
+pub fn to_retry(mail: &Mail, error: &SendError) -> Option<Mail> {
+    let response = match error {
+        SendError::NoAccount                             => return None,
+        SendError::Unavailable(_) | SendError::Daemon(_) =>
+            return Some(mail.with_recipients(mail.recipients.clone())),
+        SendError::Failed(response)                      => response,
+    };
+
+    let recipients: Vec<String> = match response.status {
+        Status::Sent | Status::PermanentFailure => return None,
+        Status::PartiallySent                   => response.rejected().iter()
+            .filter(|rejected| rejected.is_transient())
+            .map(|rejected| rejected.recipient.clone())
+            .collect(),
+        Status::TransientFailure | Status::Busy => mail.recipients.iter()
+            .filter(|&recipient| !response.rejected().iter().any(|rejected|
+                &rejected.recipient == recipient && !rejected.is_transient()))
+            .cloned()
+            .collect(),
+    };
+
+    if recipients.is_empty() {
+        None
+    } else {
+        Some(mail.with_recipients(recipients))
+    }
+}
+
+/// Sends the email through the daemon of the account, waiting for it at
+/// most the timeout at every read and write
+pub fn send_to_daemon(mail: &Mail, socket_root: &str, timeout: Duration, account: &str) ->
+        Result<Response, SendError> {
+    let socket_path = get_socket_path(socket_root, account);
+    let mut stream = UnixStream::connect(socket_path)
+        .map_err(|e| SendError::Unavailable(e.into()))?;
+    let _ = stream.set_read_timeout(Some(timeout));
+    let _ = stream.set_write_timeout(Some(timeout));
+    stream.write_all(mail.serialize().as_slice())
+        .map_err(|e| SendError::Daemon(e.into()))?;
+
+    let _ = stream.shutdown(Shutdown::Write);
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)
+        .map_err(|e| SendError::Daemon(e.into()))?;
+
+    let response = if Response::is_framed(&response) {
+        Response::deserialize(&response).map_err(SendError::Daemon)?
+    } else {
+        // The external clients, and older daemons, only send the signals
+        let response = String::from_utf8(response)
+            .map_err(|e| SendError::Daemon(Error::Protocol(e.to_string())))?;
+        Response::from_legacy_signal(&response).ok_or_else(|| SendError::Daemon(
+            Error::Protocol(format!("Unexpected response from the server: {}", response))))?
+    };
+
+    if response.status == Status::Sent {
+        Ok(response)
+    } else {
+        Err(SendError::Failed(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all, File};
+    use std::os::unix::net::UnixListener;
+    use std::process;
+    use std::thread::{self, JoinHandle};
+
+    fn rejected(recipient: &str, code: u16) -> RecipientStatus {
+        RecipientStatus {
+            recipient: recipient.to_string(),
+            code,
+            enhanced_status: None,
+            message: String::new(),
+        }
+    }
+
+    fn mail() -> Mail {
+        MailBuilder::default()
+            .recipient("a@b.c")
+            .body(b"hi".to_vec())
+            .build()
+            .unwrap()
+    }
+
+    /// An empty socket root, unique to the test
+    fn socket_root(test: &str) -> String {
+        let root = temp_dir().join(format!("rusmtp-client-{}-{}", test, process::id()));
+        let _ = remove_dir_all(&root);
+        create_dir_all(&root).unwrap();
+        root.display().to_string()
+    }
+
+    /// A daemon of the account that answers the first email with the
+    /// response, or never answers it when there is none
+    fn fake_daemon(socket_root: &str, account: &str, response: Option<Response>)
+            -> JoinHandle<Mail> {
+        let listener = UnixListener::bind(get_socket_path(socket_root, account)).unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut bytes = Vec::new();
+            stream.read_to_end(&mut bytes).unwrap();
+            let mail = Mail::deserialize(&mut bytes).unwrap();
+            match response {
+                Some(response) => stream.write_all(&response.serialize()).unwrap(),
+                None           => thread::sleep(Duration::from_secs(1)),
+            }
+            mail
+        })
+    }
+
+    #[test]
+    fn test_only_transient_rejections_are_retried() {
+        let mail = Mail {
+            account: Some("first".to_string()),
+            recipients: vec!["a@b.c".to_string(), "d@e.f".to_string(), "g@h.i".to_string()],
+            dsn_notify: None,
+            dsn_ret: None,
+            envid: None,
+            body: b"hi".to_vec(),
+        };
+
+        let partially_sent = Response {
+            recipients: vec![rejected("a@b.c", 550), rejected("d@e.f", 451),
+                             rejected("g@h.i", 250)],
+            ..Response::new(Status::PartiallySent, "")
+        };
+        assert_eq!(vec!["d@e.f".to_string()],
+                   to_retry(&mail, &SendError::Failed(partially_sent)).unwrap().recipients);
+
+        let transient = Response {
+            recipients: vec![rejected("a@b.c", 550), rejected("d@e.f", 250),
+                             rejected("g@h.i", 250)],
+            ..Response::new(Status::TransientFailure, "")
+        };
+        assert_eq!(vec!["d@e.f".to_string(), "g@h.i".to_string()],
+                   to_retry(&mail, &SendError::Failed(transient)).unwrap().recipients);
+
+        let permanent = Response::new(Status::PermanentFailure, "");
+        assert!(to_retry(&mail, &SendError::Failed(permanent)).is_none());
+        assert_eq!(mail, to_retry(&mail, &SendError::Daemon(Error::Io(String::new()))).unwrap());
+        assert!(to_retry(&mail, &SendError::NoAccount).is_none());
+    }
+
+    #[test]
+    fn test_send() {
+        let root = socket_root("send");
+        let sent = Response {
+            queue_id: Some("4AB3C2".to_string()),
+            ..Response::new(Status::Sent, "Email sent")
+        };
+        let daemon = fake_daemon(&root, "first", Some(sent.clone()));
+
+        let client = Client::new(&root).default_account("first");
+        assert_eq!(Ok(sent), client.send(&mail()));
+        assert_eq!(mail(), daemon.join().unwrap());
+        let _ = remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_send_failures() {
+        let root = socket_root("failures");
+        let busy = Response::new(Status::Busy, "The daemon is busy");
+        let daemon = fake_daemon(&root, "first", Some(busy.clone()));
+
+        let client = Client::new(&root).timeout(Duration::from_millis(100));
+        assert_eq!(Err(SendError::NoAccount), client.send(&mail()));
+
+        let client = client.default_account("first");
+        assert_eq!(Err(SendError::Failed(busy)), client.send(&mail()));
+        daemon.join().unwrap();
+
+        match client.send(&mail()) {
+            Err(SendError::Unavailable(error)) => assert!(error.is_transient()),
+            result                             => panic!("Unexpected {:?}", result),
+        }
+
+        let daemon = fake_daemon(&root, "second", None);
+        let mail = MailBuilder::default()
+            .account("second")
+            .recipient("a@b.c")
+            .build()
+            .unwrap();
+        match client.send(&mail) {
+            Err(SendError::Daemon(error)) => assert!(error.is_transient()),
+            result                        => panic!("Unexpected {:?}", result),
+        }
+        daemon.join().unwrap();
+        let _ = remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_from_config_file() {
+        let root = socket_root("config");
+        let rc_path = format!("{}/rusmtprc", root);
+        File::create(&rc_path).unwrap().write_all(format!("
+[App]
+socket-root-path={}
+
+[Client]
+timeout=5
+
+[first]
+host=smtp.example.com
+port=587
+username=me@example.com
+passwordeval=echo secret
+
+[second]
+default=true
+host=smtp.example.com
+port=587
+username=me@example.com
+passwordeval=echo secret
+", root).as_bytes()).unwrap();
+
+        let sent = Response::new(Status::Sent, "Email sent");
+        let daemon = fake_daemon(&root, "second", Some(sent.clone()));
+        let client = Client::from_config_file(&rc_path).unwrap();
+        assert_eq!(Duration::from_secs(5), client.timeout);
+        assert_eq!(Ok(sent), client.send(&mail()));
+        daemon.join().unwrap();
+
+        assert!(Client::from_config_file(&format!("{}/missing", root)).is_err());
+        let _ = remove_dir_all(&root);
+    }
+}
@@ -1,16 +1,43 @@
+use protocol::Authentication;
+use std::net::SocketAddr;
 use std::time::Duration;
 use crate::vault::Vault;
+use crate::oauth2::OAuth2;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum AuthMethod {
+    /// Authenticate with the username and the password, using the strongest
+    /// mechanism that both sides support
+    Login,
+    /// Authenticate with an OAuth 2.0 access token, using XOAUTH2
+    XOAuth2,
+    /// Authenticate with an OAuth 2.0 access token, using OAUTHBEARER (RFC 7628)
+    OAuthBearer,
+}
 
 pub struct Account {
     pub label: String,
     pub username: Option<String>,
-    pub passwordeval: String,
+    pub passwordeval: Option<String>,
     pub host: Option<String>,
     pub port: Option<u16>,
     pub tls: Option<bool>,
+    pub require_starttls: bool,
+    pub auth: AuthMethod,
+    /// The password mechanisms that can be used, in the order of preference
+    pub auth_mechanisms: Option<Vec<Authentication>>,
+    pub oauth2: Option<OAuth2>,
     pub default: bool,
     pub password: Option<Vec<u8>>,
     pub vault: Vault,
     pub timeout: Duration,
     pub cert_root: Option<String>,
+    /// How long a connection is kept open after an email, for the next one.
+    /// Zero closes the connection after every email.
+    pub idle_timeout: Duration,
+    /// How many clients are served at the same time
+    pub max_concurrent_connections: usize,
+    /// The loopback address where the daemon accepts emails over SMTP for
+    /// this account only
+    pub listen: Option<SocketAddr>,
 }
@@ -1,16 +1,36 @@
 use docopt::Docopt;
 use dirs::home_dir;
+use serde::de::DeserializeOwned;
 use std::process::exit;
+use crate::sysexits::EX_USAGE;
 
 
+/// The arguments of rusmtpd and rusmtpc
 #[derive(Deserialize, Debug)]
 pub struct Args {
     pub arg_recipients: Vec<String>,
     pub flag_account: Option<String>,
     pub flag_rusmtprc: String,
     pub flag_with_retry: Option<bool>,
-    flag_help: bool,
-    flag_version: bool,
+    pub flag_dsn_notify: Option<String>,
+    pub flag_dsn_ret: Option<String>,
+    pub flag_envid: Option<String>,
+}
+
+/// The arguments of rusmtpq
+#[derive(Deserialize, Debug)]
+pub struct QueueArgs {
+    pub cmd_list: bool,
+    pub cmd_show: bool,
+    pub cmd_delete: bool,
+    pub cmd_retry: bool,
+    pub cmd_hold: bool,
+    pub cmd_release: bool,
+    pub cmd_flush: bool,
+    pub arg_id: Option<String>,
+    pub flag_all: bool,
+    pub flag_account: Option<String>,
+    pub flag_rusmtprc: String,
 }
 
 pub fn rusmtpd_usage(app_name: &str) -> String {
@@ -48,31 +68,84 @@ pub fn rusmtpc_usage(app_name: &str) -> String {
             --rusmtprc=<string>      Path to the rusmtprc [default: {}/.rusmtprc]
             --with-retry             If set, {0} will retry to attempt sending
                                      email until it succeeds.
+            --dsn-notify=<string>    Request delivery status notifications, a comma
+                                     separated list of SUCCESS, FAILURE and DELAY,
+                                     or NEVER. Only if the server supports DSN.
+            --dsn-ret=<string>       Return either the headers (HDRS) or the full
+                                     email (FULL) in the failure notifications.
+            --envid=<string>         The envelope id to put in the notifications.
         Others:
             -h, --help               Show this help.
             -v, --version            Show the version.
         ", app_name, home_dir)
 }
 
-pub fn process_args(app_name: &str, usage: &str) -> Args {
+pub fn rusmtpq_usage(app_name: &str) -> String {
+    let home_dir = home_dir().expect("Cannot find the home directory");
+    let home_dir = home_dir.display();
+    format!("
+        {}
+
+        Usage: {0} [options] list
+               {0} [options] show <id>
+               {0} [options] delete <id>
+               {0} [options] retry (<id> | --all)
+               {0} [options] hold <id>
+               {0} [options] release <id>
+               {0} [options] flush --account=<string>
+               {0} --help
+               {0} --version
+
+        Commands:
+            list                     List the spooled emails.
+            show                     Show the envelope, the headers and the
+                                     attempts of the email.
+            delete                   Remove the email from the spool.
+            retry                    Retry the email on the next round of the
+                                     daemon, even if it is held or was given up
+                                     on, or every queued email with --all.
+            hold                     Keep the email from being retried.
+            release                  Let the held email be retried again.
+            flush                    Retry every queued email of the account on
+                                     the next round of the daemon.
+
+        Options:
+            --rusmtprc=<string>      Path to the rusmtprc [default: {}/.rusmtprc]
+            --all                    Every queued email.
+            --account=<string>       The account of the emails to flush.
+        Others:
+            -h, --help               Show this help.
+            -v, --version            Show the version.
+        ", app_name, home_dir)
+}
+
+pub fn process_args<T: DeserializeOwned>(app_name: &str, usage: &str) -> T {
 
     let app_version = env!("CARGO_PKG_VERSION");
 
-    let args: Args = Docopt::new(usage)
-        .and_then(|d| d.deserialize())
-        .unwrap_or_else(|e| e.exit());
+    let argv = Docopt::new(usage)
+        .and_then(|d| d.parse())
+        .unwrap_or_else(|e| exit_with(e));
 
-    if args.flag_version {
+    if argv.get_bool("--version") {
         println!("{}, v {}", app_name, app_version);
         exit(0);
     }
 
-    if args.flag_help {
+    if argv.get_bool("--help") {
         println!("{}", usage);
         exit(0);
     }
 
-    args
+    argv.deserialize().unwrap_or_else(|e| exit_with(e))
+}
+
+fn exit_with(e: docopt::Error) -> ! {
+    if e.fatal() {
+        eprintln!("{}", e);
+        exit(EX_USAGE);
+    }
+    e.exit()
 }
 
 
@@ -0,0 +1,278 @@
+// Bounces, the delivery status notifications of RFC 3464 that tell the
+// senders about the emails that the daemon gave up on.
+
+use fs2::FileExt;
+use rand::random;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::str::FromStr;
+use crate::Error;
+use crate::mail::Mail;
+use crate::message::{asctime, date, message_id};
+use crate::response::RecipientStatus;
+use crate::spool::Metadata;
+
+/// Where the bounces are delivered
+#[derive(Debug, PartialEq, Clone)]
+pub enum BounceTarget {
+    /// Sent to the username of the account, through the account
+    Sender,
+    /// Appended to the mbox file
+    Mbox(String),
+    /// Delivered to the Maildir directory
+    Maildir(String),
+}
+
+impl FromStr for BounceTarget {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once(':') {
+            None if value == "sender"                   => Ok(BounceTarget::Sender),
+            Some(("mbox", path)) if !path.is_empty()    => Ok(BounceTarget::Mbox(path.to_string())),
+            Some(("maildir", path)) if !path.is_empty() =>
+                Ok(BounceTarget::Maildir(path.to_string())),
+            _                                           => Err(Error::Config(
+                "Invalid bounce value in configuration \
+                 (valid: sender | mbox:/path | maildir:/path)".to_string())),
+        }
+    }
+}
+
+/// The bounce of the spooled email, from and to the address, i.e. the
+/// username of its account. The recipients that the server rejected come
+/// with their own replies, and the email failed for every recipient as
+/// the metadata tells otherwise.
+pub fn compose(mail: &Mail, metadata: &Metadata, rejected: &[RecipientStatus],
+               address: &str, now: u64) -> Vec<u8> {
+    let last_error = one_line(metadata.last_error.as_deref().unwrap_or("Unknown error"));
+    let failures: Vec<(String, String, String)> = if rejected.is_empty() {
+        let status = match metadata.last_code {
+            Some(code) if code / 100 == 5 => "5.0.0",
+            // Retried until the email expired
+            _                             => "4.4.7",
+        };
+        mail.recipients.iter()
+            .map(|recipient| (recipient.clone(), status.to_string(),
+                              format!("X-rusmtp; {}", last_error)))
+            .collect()
+    } else {
+        rejected.iter()
+            .map(|rejection| {
+                let status = rejection.enhanced_status.clone().unwrap_or_else(|| {
+                    format!("{}.0.0", rejection.code / 100)
+                });
+                let reply = format!("{} {}", rejection.code, one_line(&rejection.message));
+                (rejection.recipient.clone(), status, format!("smtp; {}", reply))
+            })
+            .collect()
+    };
+
+    let boundary = format!("rusmtp-{:x}", random::<u64>());
+    let mut bounce = format!(
+        "From: Mail Delivery System <{address}>\r\n\
+         To: <{address}>\r\n\
+         Date: {date}\r\n\
+         Message-ID: {message_id}\r\n\
+         Subject: Undelivered Mail Returned to Sender\r\n\
+         Auto-Submitted: auto-replied\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/report; report-type=delivery-status;\r\n \
+         boundary=\"{boundary}\"\r\n\r\n",
+        address = address, date = date(now), message_id = message_id(now, address),
+        boundary = boundary);
+
+    bounce.push_str(&format!("--{}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n", boundary));
+    bounce.push_str(&format!(
+        "Your email, queued on {}, could not be sent to the following recipients \
+         after {} attempts, and is given up on:\r\n\r\n", date(metadata.queued_at),
+        metadata.attempts));
+    for (recipient, _, diagnostic) in &failures {
+        let reason = diagnostic.split_once("; ").map_or(diagnostic.as_str(), |(_, reason)| reason);
+        bounce.push_str(&format!("  {}: {}\r\n", recipient, reason));
+    }
+
+    bounce.push_str(&format!("\r\n--{}\r\nContent-Type: message/delivery-status\r\n\r\n", boundary));
+    bounce.push_str(&format!("Reporting-MTA: dns; localhost\r\nArrival-Date: {}\r\n",
+                             date(metadata.queued_at)));
+    if let Some(envid) = &mail.envid {
+        bounce.push_str(&format!("Original-Envelope-Id: {}\r\n", envid));
+    }
+    for (recipient, status, diagnostic) in &failures {
+        bounce.push_str(&format!("\r\nFinal-Recipient: rfc822; {}\r\nAction: failed\r\n\
+                                  Status: {}\r\nDiagnostic-Code: {}\r\n\
+                                  Last-Attempt-Date: {}\r\n",
+                                 recipient, status, diagnostic, date(now)));
+    }
+
+    bounce.push_str(&format!("\r\n--{}\r\nContent-Type: text/rfc822-headers\r\n\r\n", boundary));
+    bounce.push_str(&headers(&mail.body));
+    bounce.push_str(&format!("\r\n--{}--\r\n", boundary));
+    bounce.into_bytes()
+}
+
+/// Appends the bounce to the mbox file, quoting its From lines as mboxrd
+/// does
+pub fn append_to_mbox(path: &str, bounce: &[u8], now: u64) -> Result<(), Error> {
+    let mut entry = format!("From MAILER-DAEMON {}\n", asctime(now));
+    for line in unix_lines(bounce).lines() {
+        if line.trim_start_matches('>').starts_with("From ") {
+            entry.push('>');
+        }
+        entry.push_str(line);
+        entry.push('\n');
+    }
+    entry.push('\n');
+
+    let mut mbox = OpenOptions::new().create(true).append(true).open(path)
+        .map_err(|e| Error::Io(format!("Cannot open {}: {}", path, e)))?;
+    mbox.lock_exclusive()
+        .map_err(|e| Error::Io(format!("Cannot lock {}: {}", path, e)))?;
+    let written = mbox.write_all(entry.as_bytes()).and_then(|_| mbox.sync_all());
+    let _ = FileExt::unlock(&mbox);
+    written.map_err(|e| Error::Io(format!("Cannot write to {}: {}", path, e)))
+}
+
+/// Delivers the bounce to the new directory of the Maildir, through its tmp
+/// directory, so that it never shows up half written
+pub fn deliver_to_maildir(path: &str, bounce: &[u8], now: u64) -> Result<(), Error> {
+    for directory in &["tmp", "new", "cur"] {
+        fs::create_dir_all(format!("{}/{}", path, directory))
+            .map_err(|e| Error::Io(format!("Cannot create the Maildir {}: {}", path, e)))?;
+    }
+    let name = format!("{}.R{:x}.rusmtpd", now, random::<u64>());
+    let tmp = format!("{}/tmp/{}", path, name);
+    File::create(&tmp)
+        .and_then(|mut file| {
+            file.write_all(unix_lines(bounce).as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, format!("{}/new/{}", path, name)))
+        .map_err(|e| {
+            let _ = fs::remove_file(&tmp);
+            Error::Io(format!("Cannot deliver to the Maildir {}: {}", path, e))
+        })
+}
+
+/// The header section of the email, the part of the bounce that tells which
+/// email it was
+fn headers(body: &[u8]) -> String {
+    let body = String::from_utf8_lossy(body);
+    let end = [body.find("\r\n\r\n"), body.find("\n\n")].iter()
+        .flatten()
+        .min()
+        .copied()
+        .unwrap_or(body.len());
+    let mut headers = String::new();
+    for line in body[..end].lines() {
+        headers.push_str(line);
+        headers.push_str("\r\n");
+    }
+    headers
+}
+
+/// The text on a single line, as the fields of the delivery status are
+fn one_line(text: &str) -> String {
+    text.split(['\r', '\n']).filter(|line| !line.is_empty()).collect::<Vec<_>>().join(" ")
+}
+
+/// The message with the line endings of the local mailboxes
+fn unix_lines(message: &[u8]) -> String {
+    String::from_utf8_lossy(message).replace("\r\n", "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::{read_dir, remove_dir_all};
+    use std::process;
+
+    fn mail() -> Mail {
+        Mail {
+            account: Some("first".to_string()),
+            recipients: vec!["a@b.c".to_string(), "d@e.f".to_string()],
+            dsn_notify: None,
+            dsn_ret: None,
+            envid: Some("42".to_string()),
+            body: b"Subject: hi\r\nFrom: me@example.com\r\n\r\nFrom here on\r\n".to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_bounce_target() {
+        assert_eq!(Ok(BounceTarget::Sender), "sender".parse());
+        assert_eq!(Ok(BounceTarget::Mbox("/var/mail/me".to_string())), "mbox:/var/mail/me".parse());
+        assert_eq!(Ok(BounceTarget::Maildir("/home/me/Maildir".to_string())),
+                   "maildir:/home/me/Maildir".parse());
+        assert!("mbox:".parse::<BounceTarget>().is_err());
+        assert!("/var/mail/me".parse::<BounceTarget>().is_err());
+    }
+
+    #[test]
+    fn test_compose_rejected() {
+        let rejected = vec![RecipientStatus {
+            recipient: "d@e.f".to_string(),
+            code: 550,
+            enhanced_status: Some("5.1.1".to_string()),
+            message: "No such user".to_string(),
+        }];
+        let bounce = compose(&mail(), &Metadata::failed("Rejected", Some(550)), &rejected,
+                             "me@example.com", 0);
+        let bounce = String::from_utf8(bounce).unwrap();
+        assert!(bounce.starts_with("From: Mail Delivery System <me@example.com>\r\n\
+                                    To: <me@example.com>\r\n\
+                                    Date: Thu, 1 Jan 1970 00:00:00 +0000\r\n"));
+        assert!(bounce.contains("Content-Type: multipart/report; report-type=delivery-status;"));
+        assert!(bounce.contains("  d@e.f: 550 No such user\r\n"));
+        assert!(bounce.contains("Original-Envelope-Id: 42\r\n\r\n\
+                                 Final-Recipient: rfc822; d@e.f\r\nAction: failed\r\n\
+                                 Status: 5.1.1\r\nDiagnostic-Code: smtp; 550 No such user\r\n"));
+        assert!(!bounce.contains("a@b.c"));
+        assert!(bounce.contains("Content-Type: text/rfc822-headers\r\n\r\n\
+                                 Subject: hi\r\nFrom: me@example.com\r\n\r\n--rusmtp-"));
+        assert!(!bounce.contains("From here on"));
+    }
+
+    #[test]
+    fn test_compose_expired() {
+        let metadata = Metadata::failed("Connection refused\r\nby the server", None);
+        let bounce = compose(&mail(), &metadata, &[], "me@example.com", 0);
+        let bounce = String::from_utf8(bounce).unwrap();
+        for recipient in &["a@b.c", "d@e.f"] {
+            assert!(bounce.contains(&format!(
+                "Final-Recipient: rfc822; {}\r\nAction: failed\r\nStatus: 4.4.7\r\n\
+                 Diagnostic-Code: X-rusmtp; Connection refused by the server\r\n", recipient)));
+        }
+    }
+
+    #[test]
+    fn test_append_to_mbox() {
+        let path = temp_dir().join(format!("rusmtp-bounce-{}.mbox", process::id()));
+        let _ = fs::remove_file(&path);
+        let path = path.display().to_string();
+        append_to_mbox(&path, b"Subject: a\r\n\r\nFrom me\r\n>From you\r\n", 0).unwrap();
+        append_to_mbox(&path, b"Subject: b\r\n\r\nbye\r\n", 0).unwrap();
+        assert_eq!("From MAILER-DAEMON Thu Jan  1 00:00:00 1970\n\
+                    Subject: a\n\n>From me\n>>From you\n\n\
+                    From MAILER-DAEMON Thu Jan  1 00:00:00 1970\n\
+                    Subject: b\n\nbye\n\n",
+                   fs::read_to_string(&path).unwrap());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_deliver_to_maildir() {
+        let path = temp_dir().join(format!("rusmtp-bounce-{}", process::id()));
+        let _ = remove_dir_all(&path);
+        let path = path.display().to_string();
+        deliver_to_maildir(&path, b"Subject: a\r\n\r\nhi\r\n", 0).unwrap();
+        assert_eq!(0, read_dir(format!("{}/tmp", path)).unwrap().count());
+        let delivered: Vec<_> = read_dir(format!("{}/new", path)).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(1, delivered.len());
+        assert_eq!("Subject: a\n\nhi\n", fs::read_to_string(&delivered[0]).unwrap());
+        let _ = remove_dir_all(&path);
+    }
+}
@@ -1,23 +1,64 @@
 use ini::Ini;
+use ini::ini::Properties;
+use protocol::Authentication;
+use std::net::SocketAddr;
+use std::str::FromStr;
 use std::time::Duration;
 use dirs::home_dir;
-use crate::account::Account;
+use crate::account::{Account, AuthMethod};
+use crate::bounce::BounceTarget;
+use crate::oauth2::OAuth2;
+use crate::spool::RetryPolicy;
 use crate::vault::Vault;
-use crate::log_and_panic;
+use crate::Error;
 
 pub struct Configuration {
     pub smtpclient: Option<String>,
+    /// Whether the daemon serves every account on a single asynchronous
+    /// runtime, rather than on threads of their own
+    pub async_runtime: bool,
     pub socket_root: String,
     pub flock_root: String,
     pub spool_root: String,
     pub timeout: u64,
+    /// The loopback address where the daemon accepts emails over SMTP for
+    /// every account
+    pub listen: Option<SocketAddr>,
+    /// The loopback address where the daemon accepts emails as JSON over
+    /// HTTP
+    pub http_listen: Option<SocketAddr>,
+    /// How long, and how many times, the spooled emails are retried
+    pub retry_policy: RetryPolicy,
+    /// Where the bounces of the emails that the daemon gives up on are
+    /// delivered, if anywhere
+    pub bounce: Option<BounceTarget>,
     pub accounts: Vec<Account>,
 }
 
-pub fn read_config(rc_path: &str) -> Configuration {
+/// Parses the value of the key, if it is set
+fn parse_value<T: FromStr>(section: &Properties, key: &str, error: &str)
+        -> Result<Option<T>, Error> {
+    section.get(key)
+        .map(|value| value.parse().map_err(|_| Error::Config(error.to_string())))
+        .transpose()
+}
+
+/// Parses the address of a listener, which is only allowed on loopback, as
+/// the listeners do not check the passwords
+fn parse_listen(section: &Properties, key: &str) -> Result<Option<SocketAddr>, Error> {
+    let listen: Option<SocketAddr> = parse_value(section, key,
+        &format!("Invalid {} value in configuration (e.g. 127.0.0.1:2525)", key))?;
+    match listen {
+        Some(addr) if !addr.ip().is_loopback() => Err(Error::Config(
+            format!("{} should be a loopback address, not {}", key, addr))),
+        listen                                 => Ok(listen),
+    }
+}
+
+pub fn read_config(rc_path: &str) -> Result<Configuration, Error> {
     debug!("Loading configuration from {}", rc_path);
     let conf = Ini::load_from_file(rc_path)
-        .unwrap_or_else(|e| log_and_panic(&format!("{}", e)));
+        .map_err(|e| Error::Config(format!("Cannot read {}: {}", rc_path, e)))?;
 
     let app = conf.section(Some("App".to_owned()));
     let socket_root = app.and_then(|app| {
@@ -36,7 +77,9 @@ pub fn read_config(rc_path: &str) -> Configuration {
         "".to_string()
     });
 
-    let home_dir = home_dir().expect("Cannot find the home directory").display().to_string();
+    let home_dir = home_dir()
+        .ok_or_else(|| Error::Config("Cannot find the home directory".to_string()))?
+        .display().to_string();
     let defualt_spool = format!("{}/.rusmtp/spool", home_dir);
     let spool_root = app.and_then(|app| {
         app.get("spool-root-path").map(|s| s.to_string())
@@ -46,18 +89,47 @@ pub fn read_config(rc_path: &str) -> Configuration {
         defualt_spool
     });
 
-    let smtp = conf.section(Some("Daemon".to_owned())).and_then(|section| {
+    let daemon = conf.section(Some("Daemon".to_owned()));
+    let smtp = daemon.and_then(|section| {
         section.get("smtp").map(|s| s.to_string())
     });
 
-    let timeout = conf.section(Some("Client")).and_then(|section| {
-        section.get("timeout").map(|s| {
-            let res: u64 = s.parse()
-                .unwrap_or_else(|_|
-                    log_and_panic("Invalid timeout value in configuration"));
-            res
-        })
-    }).unwrap_or_else(|| {
+    let runtime = daemon.and_then(|section| section.get("runtime")).map(|p| p.as_ref());
+    let async_runtime = match runtime {
+        None | Some("threads") => false,
+        Some("async")          => true,
+        Some(_)                => return Err(Error::Config(
+            "Invalid runtime value in configuration (valid: threads | async)".to_string())),
+    };
+
+    let (max_retry_age, max_attempts) = match daemon {
+        Some(section) => (
+            parse_value(section, "max-retry-age",
+                "Invalid max-retry-age value in configuration (valid: seconds)")?,
+            parse_value(section, "max-attempts",
+                "Invalid max-attempts value in configuration (valid: numbers, 0 for no limit)")?),
+        None          => (None, None),
+    };
+    let retry_policy = RetryPolicy {
+        max_age: Duration::from_secs(max_retry_age.unwrap_or(DEFAULT_MAX_RETRY_AGE_IN_SECONDS)),
+        max_attempts: max_attempts.filter(|&max_attempts| max_attempts > 0),
+    };
+
+    let bounce = daemon.and_then(|section| section.get("bounce"))
+        .map(|bounce| bounce.parse())
+        .transpose()?;
+
+    let (listen, http_listen) = match daemon {
+        Some(section) => (parse_listen(section, "listen")?,
+                          parse_listen(section, "http-listen")?),
+        None          => (None, None),
+    };
+
+    let timeout = match conf.section(Some("Client")) {
+        Some(section) =>
+            parse_value(section, "timeout", "Invalid timeout value in configuration")?,
+        None          => None,
+    }.unwrap_or_else(|| {
         warn!("No configuration was found for timeout, \
               using the default value, {}", DEFAULT_TIMEOUT_IN_SECONDS);
         DEFAULT_TIMEOUT_IN_SECONDS
@@ -73,77 +145,144 @@ pub fn read_config(rc_path: &str) -> Configuration {
             let label    = section_name.clone().unwrap();
             let host     = section.get("host").map(|s| s.to_string());
             let username = section.get("username").map(|s| s.to_string());
-            let port     = section.get("port").map(|p| {
-                let port: u16 = p.parse()
-                    .unwrap_or_else(|_|
-                        log_and_panic("Invalid port number value in configuration"));
-                port
-            });
-            let eval     = section.get("passwordeval").map(|s| s.to_string())
-                .unwrap_or_else(||
-                    log_and_panic("passwordeval is missing in the configuration"));
-
-            let tls      = section.get("tls").map(|p| {
-                let tls: bool = p.parse()
-                    .unwrap_or_else(|_|
-                        log_and_panic(
-                            "Invalid tls value in configuration (valid: false | true)"));
-                tls
-            });
-
-            let default      = section.get("default").map(|p| {
-                let default: bool = p.parse()
-                    .unwrap_or_else(|_|
-                        log_and_panic("Invalid bool value in configuration"));
-                default
-            }).unwrap_or(false);
+            let port: Option<u16> = parse_value(section, "port",
+                "Invalid port number value in configuration")?;
+            let oauth2   = match section.get("oauth2-token-endpoint") {
+                Some(endpoint) => Some(OAuth2 {
+                    token_endpoint: endpoint.to_string(),
+                    client_id: section.get("oauth2-client-id").map(|s| s.to_string())
+                        .ok_or_else(|| Error::Config(
+                            "oauth2-client-id is missing in the configuration".to_string()))?,
+                    client_secret_eval: section.get("oauth2-client-secret-eval")
+                        .map(|s| s.to_string()),
+                    refresh_token_eval: section.get("oauth2-refresh-token-eval")
+                        .map(|s| s.to_string())
+                        .ok_or_else(|| Error::Config(
+                            "oauth2-refresh-token-eval is missing in the configuration"
+                                .to_string()))?,
+                }),
+                None           => None,
+            };
+
+            let eval     = section.get("passwordeval").map(|s| s.to_string());
+            if eval.is_none() && oauth2.is_none() {
+                return Err(Error::Config(
+                    "passwordeval is missing in the configuration".to_string()));
+            }
+
+            let tls: Option<bool> = parse_value(section, "tls",
+                "Invalid tls value in configuration (valid: false | true)")?;
+
+            let require_starttls = parse_value(section, "require-starttls",
+                "Invalid require-starttls value in configuration (valid: false | true)")?
+                .unwrap_or(false);
+
+            let auth = match section.get("auth").map(|p| p.as_ref()) {
+                None                => AuthMethod::Login,
+                Some("login")       => AuthMethod::Login,
+                Some("xoauth2")     => AuthMethod::XOAuth2,
+                Some("oauthbearer") => AuthMethod::OAuthBearer,
+                Some(_)             => return Err(Error::Config(
+                    "Invalid auth value in configuration (valid: login | xoauth2 | oauthbearer)"
+                        .to_string())),
+            };
+
+            let auth_mechanisms = match section.get("auth-mechanisms") {
+                Some(p) => Some(p.split(',')
+                    .map(|mechanism| mechanism.trim())
+                    .filter(|mechanism| !mechanism.is_empty())
+                    .map(|mechanism| Authentication::from_name(mechanism)
+                        .filter(|auth| Authentication::PASSWORD_MECHANISMS.contains(auth))
+                        .ok_or_else(|| Error::Config(format!(
+                            "Invalid auth-mechanisms value in configuration: {} \
+                            (valid: scram-sha-256, cram-md5, plain, login)", mechanism))))
+                    .collect::<Result<Vec<Authentication>, Error>>()?),
+                None    => None,
+            };
+
+            let default = parse_value(section, "default", "Invalid bool value in configuration")?
+                .unwrap_or(false);
 
             let cert_root = section.get("cert-root").map(|s| s.to_owned());
 
-            let timeout = section.get("tcp-timeout").map(|s| {
-                let timeout: u8 = s.parse()
-                    .unwrap_or_else(|_|
-                        log_and_panic(
-                            "Invalid tcp-timeout value in configuration (valid: numbers between 0 to 255)"));
-                Duration::new(u64::from(timeout), 0)
-            }).unwrap_or_else(|| Duration::new(1, 0));
+            let timeout: Option<u8> = parse_value(section, "tcp-timeout",
+                "Invalid tcp-timeout value in configuration (valid: numbers between 0 to 255)")?;
+            let timeout = Duration::new(u64::from(timeout.unwrap_or(1)), 0);
+
+            let idle_timeout = parse_value(section, "idle-timeout",
+                "Invalid idle-timeout value in configuration (valid: seconds, 0 to disable)")?
+                .unwrap_or(DEFAULT_IDLE_TIMEOUT_IN_SECONDS);
+            let idle_timeout = Duration::from_secs(idle_timeout);
+
+            let max_concurrent_connections = parse_value(section, "max-concurrent-connections",
+                "Invalid max-concurrent-connections value in configuration \
+                (valid: numbers greater than 0)")?
+                .unwrap_or(DEFAULT_MAX_CONCURRENT_CONNECTIONS);
+            if max_concurrent_connections == 0 {
+                return Err(Error::Config(
+                    "max-concurrent-connections should be greater than 0".to_string()));
+            }
+
+            let listen = parse_listen(section, "listen")?;
 
             accounts.push(Account {
                 label,
                 host,
                 username,
-                passwordeval: eval.to_string(),
+                passwordeval: eval,
                 port,
                 tls,
+                require_starttls,
+                auth,
+                auth_mechanisms,
+                oauth2,
                 default,
                 password: None,
-                vault: Vault::new(),
+                vault: Vault::new()?,
                 timeout,
                 cert_root,
+                idle_timeout,
+                max_concurrent_connections,
+                listen,
             })
         }
     }
 
     if accounts.is_empty() {
-        let _: Configuration = log_and_panic("At least an account should be configured");
+        return Err(Error::Config("At least an account should be configured".to_string()));
     }
 
     let default_accounts = accounts.iter()
         .fold(0,|z,y| if y.default { z + 1 } else { z} );
 
     if default_accounts > 1 {
-        let _: Configuration = log_and_panic("At most one account can be set to default");
+        return Err(Error::Config("At most one account can be set to default".to_string()));
     }
 
 
-    Configuration {
+    Ok(Configuration {
         smtpclient: smtp,
+        async_runtime,
         socket_root,
         flock_root,
         spool_root,
         timeout,
+        listen,
+        http_listen,
+        retry_policy,
+        bounce,
         accounts,
-    }
+    })
+}
+
+/// The rusmtprc that is read when none is given, i.e. ~/.rusmtprc
+pub fn default_rc_path() -> Result<String, Error> {
+    home_dir()
+        .map(|home_dir| format!("{}/.rusmtprc", home_dir.display()))
+        .ok_or_else(|| Error::Config("Cannot find the home directory".to_string()))
 }
 
 const DEFAULT_TIMEOUT_IN_SECONDS: u64 = 30;
+const DEFAULT_IDLE_TIMEOUT_IN_SECONDS: u64 = 60;
+const DEFAULT_MAX_RETRY_AGE_IN_SECONDS: u64 = 5 * 24 * 60 * 60;
+const DEFAULT_MAX_CONCURRENT_CONNECTIONS: usize = 4;
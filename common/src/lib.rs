@@ -1,22 +1,41 @@
 #[macro_use]
 extern crate log;
 
-use std::u64;
+use std::process::{Command, Stdio};
 
 pub mod account;
+pub mod bounce;
 pub mod vault;
 pub mod config;
 pub mod args;
 pub mod mail;
+pub mod message;
+pub mod oauth2;
+pub mod response;
+pub mod spool;
+pub mod sysexits;
+
+pub use protocol::Error;
 
 #[macro_use]
 extern crate serde_derive;
 
 
-#[inline]
-pub fn log_and_panic<A>(msg: &str) -> A {
-    error!("{}", msg);
-    panic!("{}", msg)
+/// Runs a shell command, like passwordeval, and returns its trimmed output
+pub fn evaluate(command: &str) -> Result<String, Error> {
+    let output = Command::new("sh").arg("-c").arg(command)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| Error::Io(format!("Cannot run the command: {}", e)))?;
+
+    if !output.status.success() {
+        return Err(Error::Auth(format!("The command failed with {}", output.status)));
+    }
+
+    String::from_utf8(output.stdout)
+        .map(|value| value.trim().to_string())
+        .map_err(|_| Error::Auth("The output of the command is not valid UTF-8".to_string()))
 }
 
 pub fn get_lock_path(prefix: &str, account: &str) -> String {
@@ -35,10 +54,14 @@ pub fn get_socket_path(prefix: &str, account: &str) -> String {
   }
 }
 
-static FLOCK_PATH_PREFIX: &'static str = "rusmtp-daemon-flock";
-static SOCKET_PATH_PREFIX: &'static str = "rusmtp-daemon-socket";
-pub static OK_SIGNAL: &'static str = "OK";
-pub static ERROR_SIGNAL: &'static str = "ERROR";
+static FLOCK_PATH_PREFIX: &str = "rusmtp-daemon-flock";
+static SOCKET_PATH_PREFIX: &str = "rusmtp-daemon-socket";
+pub static OK_SIGNAL: &str = "OK";
+pub static ERROR_SIGNAL: &str = "ERROR";
+/// The email will never be accepted as it is, so it should not be retried
+pub static PERMANENT_ERROR_SIGNAL: &str = "PERMANENT_ERROR";
+/// Some recipients were rejected, they follow on the next lines
+pub static REJECTED_SIGNAL: &str = "REJECTED";
 
 fn transform_u64_to_array_of_u8(x: u64) -> [u8; 8] {
     let b1 : u8 = ((x >> 56) & 0xff) as u8;
@@ -1,17 +1,63 @@
 use std::str;
-use crate::{transform_u64_to_array_of_u8, transform_array_of_u8_to_u64};
+use crate::{Error, transform_u64_to_array_of_u8, transform_array_of_u8_to_u64};
 
 #[derive(Debug, PartialEq)]
 pub struct Mail {
     pub account: Option<String>,
     pub recipients: Vec<String>,
+    /// The NOTIFY parameter of the delivery status notifications, e.g.
+    /// SUCCESS,FAILURE
+    pub dsn_notify: Option<String>,
+    /// The RET parameter of the delivery status notifications, HDRS or FULL
+    pub dsn_ret: Option<String>,
+    /// The ENVID parameter of the delivery status notifications
+    pub envid: Option<String>,
     pub body: Vec<u8>,
 }
 
 impl Mail {
     const MAGIC_NUMBER: &'static str = "RUSMTP";
     const VERSION_MAJOR: u8 = 1;
-    const VERSION_MINOR: u8 = 0;
+    // 1.1 adds the delivery status notification parameters after the
+    // account, and the clients that write 1.2 understand the framed
+    // responses of the daemon
+    const VERSION_MINOR: u8 = 2;
+    const DETAILED_SIGNALS_MINOR: u8 = 1;
+    const FRAMED_RESPONSE_MINOR: u8 = 2;
+
+    /// The same email, but to other recipients
+    pub fn with_recipients(&self, recipients: Vec<String>) -> Self {
+        Mail {
+            account: self.account.clone(),
+            recipients,
+            dsn_notify: self.dsn_notify.clone(),
+            dsn_ret: self.dsn_ret.clone(),
+            envid: self.envid.clone(),
+            body: self.body.clone(),
+        }
+    }
+
+    /// Whether the client that serialized the email expects a framed
+    /// response, rather than a bare signal
+    pub fn expects_framed_response(bytes: &[u8]) -> bool {
+        Mail::minor_version(bytes).is_some_and(|minor| minor >= Mail::FRAMED_RESPONSE_MINOR)
+    }
+
+    /// Whether the client that serialized the email understands the
+    /// PERMANENT_ERROR and REJECTED signals, rather than only OK and ERROR
+    pub fn expects_detailed_signals(bytes: &[u8]) -> bool {
+        Mail::minor_version(bytes).is_some_and(|minor| minor >= Mail::DETAILED_SIGNALS_MINOR)
+    }
+
+    fn minor_version(bytes: &[u8]) -> Option<u8> {
+        if !bytes.starts_with(Mail::MAGIC_NUMBER.as_bytes()) {
+            return None;
+        }
+        match bytes.get(Mail::MAGIC_NUMBER.len()..Mail::MAGIC_NUMBER.len() + 2) {
+            Some(&[Mail::VERSION_MAJOR, minor]) => Some(minor),
+            _                                   => None,
+        }
+    }
 
     pub fn serialize(&self) -> Vec<u8> {
         let mut sink = Vec::new();
@@ -33,6 +79,13 @@ impl Mail {
             },
         };
 
+        // Write the delivery status notification parameters the same way
+        for field in &[&self.dsn_notify, &self.dsn_ret, &self.envid] {
+            let field_bytes = field.as_ref().map(|f| f.as_bytes()).unwrap_or(b"");
+            sink.push(field_bytes.len() as u8);
+            sink.extend_from_slice(field_bytes);
+        }
+
         // Write the length of the bytes in the recipients and
         // the actual bytes of the recipients
         for recipient in &self.recipients {
@@ -67,6 +120,18 @@ impl Mail {
             None         =>
                 return false,
         };
+        // add the length of the delivery status notification parameters
+        if matches!(bytes.get(Mail::MAGIC_NUMBER.len() + 1), Some(&minor) if minor > 0) {
+            for _ in 0..3 {
+                expected_length += 1;
+                match bytes.get(expected_length - 1) {
+                    Some(&value) =>
+                        expected_length += value as usize,
+                    None         =>
+                        return false,
+                };
+            }
+        }
         // add combined recipients length
         loop {
             // recipient length
@@ -98,26 +163,27 @@ impl Mail {
         true
     }
 
-    pub fn deserialize(bytes: &mut Vec<u8>) -> Result<Self, String> {
+    pub fn deserialize(bytes: &mut Vec<u8>) -> Result<Self, Error> {
         if ! Mail::sanity_check(bytes) {
-            return Err("Message unexpectedly truncated".to_string());
+            return Err(Error::Protocol("Message unexpectedly truncated".to_string()));
         }
 
         // Read and check magic number
         let magic_len = Mail::MAGIC_NUMBER.len();
         let possible_magic: Vec<u8> = bytes.drain(0..magic_len).collect();
         if possible_magic.as_slice() != Mail::MAGIC_NUMBER.as_bytes() {
-            return Err("Bad magic number for message".to_string());
+            return Err(Error::Protocol("Bad magic number for message".to_string()));
         }
 
         // Read and check major version
         if bytes.remove(0) != Mail::VERSION_MAJOR {
-            return Err("Bad major version number for message".to_string());
+            return Err(Error::Protocol("Bad major version number for message".to_string()));
         };
 
         // Read and check minor version
-        if bytes.remove(0) != Mail::VERSION_MINOR {
-            return Err("Bad minor version number for message".to_string());
+        let minor = bytes.remove(0);
+        if minor > Mail::VERSION_MINOR {
+            return Err(Error::Protocol("Bad minor version number for message".to_string()));
         };
 
         // Read account
@@ -128,11 +194,27 @@ impl Mail {
                 let acc: Vec<u8> = bytes.drain(0..size as usize).collect();
                 match str::from_utf8(acc.as_slice()) {
                     Ok(acc) => Some(acc.to_string()),
-                    Err(_)  => return Err("Invalid account name".to_string())
+                    Err(_)  => return Err(Error::Protocol("Invalid account name".to_string()))
                 }
             }
         };
 
+        // Read the delivery status notification parameters
+        let mut dsn = [None, None, None];
+        if minor > 0 {
+            for field in dsn.iter_mut() {
+                let next = bytes.remove(0);
+                if next > 0 {
+                    let value: Vec<u8> = bytes.drain(0..next as usize).collect();
+                    match String::from_utf8(value) {
+                        Ok(value) => *field = Some(value),
+                        Err(_)    => return Err(Error::Protocol("Invalid DSN parameter".to_string())),
+                    }
+                }
+            }
+        }
+        let [dsn_notify, dsn_ret, envid] = dsn;
+
         // Read recipients
         let mut recipients = Vec::new();
         loop {
@@ -144,20 +226,30 @@ impl Mail {
                     recipients.push(recipient.to_string());
                 },
                 Err(_)        =>
-                    return Err("Invalid recipient".to_string())
+                    return Err(Error::Protocol("Invalid recipient".to_string()))
             }
 
         }
 
         // Read the size of the body
-        let _ = bytes.drain(0..8);
+        if bytes.len() < 8 {
+            return Err(Error::Protocol("Message unexpectedly truncated".to_string()));
+        }
+        let body_length = transform_array_of_u8_to_u64(&bytes.drain(0..8).collect::<Vec<u8>>());
 
-        // Read the body of the message
+        // Read the body of the message, which is cut short when the
+        // message was not completely written
+        if (bytes.len() as u64) < body_length {
+            return Err(Error::Protocol("Message unexpectedly truncated".to_string()));
+        }
         let body = bytes.to_owned();
 
         Ok(Mail {
             account,
             recipients,
+            dsn_notify,
+            dsn_ret,
+            envid,
             body,
         })
     }
@@ -172,6 +264,9 @@ mod tests {
         let expected = Mail {
             account: None,
             recipients: vec!["f@s.s".to_string(), "s@t.f".to_string()],
+            dsn_notify: None,
+            dsn_ret: None,
+            envid: None,
             body: b"valuable email".to_vec(),
         };
 
@@ -185,6 +280,9 @@ mod tests {
         let expected = Mail {
             account: Some("first".to_string()),
             recipients: vec!["f@s.s".to_string(), "s@t.f".to_string()],
+            dsn_notify: None,
+            dsn_ret: None,
+            envid: None,
             body: b"valuable email".to_vec(),
         };
 
@@ -192,4 +290,57 @@ mod tests {
         let actual = Mail::deserialize(&mut serialized);
         assert_eq!(Ok(expected), actual);
     }
+
+    #[test]
+    fn test_email_with_dsn() {
+        let expected = Mail {
+            account: Some("first".to_string()),
+            recipients: vec!["f@s.s".to_string()],
+            dsn_notify: Some("SUCCESS,FAILURE".to_string()),
+            dsn_ret: None,
+            envid: Some("QQ314159".to_string()),
+            body: b"valuable email".to_vec(),
+        };
+
+        let mut serialized = expected.serialize();
+        assert!(Mail::expects_framed_response(&serialized));
+        let actual = Mail::deserialize(&mut serialized);
+        assert_eq!(Ok(expected), actual);
+    }
+
+    #[test]
+    fn test_version_1_0_email() {
+        let mut serialized = b"RUSMTP\x01\x00\x00\x05f@s.s\x00".to_vec();
+        serialized.extend_from_slice(&transform_u64_to_array_of_u8(2));
+        serialized.extend_from_slice(b"hi");
+        assert!(!Mail::expects_framed_response(&serialized));
+        assert!(!Mail::expects_detailed_signals(&serialized));
+        assert!(Mail::expects_detailed_signals(b"RUSMTP\x01\x01"));
+        assert!(!Mail::expects_detailed_signals(b"Hello"));
+        let mail = Mail::deserialize(&mut serialized).unwrap();
+        assert_eq!(vec!["f@s.s".to_string()], mail.recipients);
+        assert_eq!(None, mail.dsn_notify);
+        assert_eq!(b"hi".to_vec(), mail.body);
+    }
+
+    #[test]
+    fn test_truncated_dsn() {
+        let mut serialized = b"RUSMTP\x01\x01\x00\x05SUCC".to_vec();
+        assert!(Mail::deserialize(&mut serialized).is_err());
+    }
+
+    #[test]
+    fn test_truncated_body() {
+        let mail = Mail {
+            account: None,
+            recipients: vec!["f@s.s".to_string()],
+            dsn_notify: None,
+            dsn_ret: None,
+            envid: None,
+            body: b"hello".to_vec(),
+        };
+        let mut serialized = mail.serialize();
+        serialized.truncate(serialized.len() - 1);
+        assert!(Mail::deserialize(&mut serialized).is_err());
+    }
 }
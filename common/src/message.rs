@@ -0,0 +1,254 @@
+// Composes RFC 5322 messages, for the emails that are not written by a mail
+// user agent.
+
+use base64::encode;
+use rand::random;
+use std::slice;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::Error;
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+                            "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+/// The longest line of base64, RFC 2045 section 6.8
+const BASE64_LINE_LENGTH: usize = 76;
+/// The most bytes of text in an encoded word, which keeps it shorter than 75
+/// characters, RFC 2047 section 2
+const ENCODED_WORD_LENGTH: usize = 45;
+
+/// A message with a plain text body, an HTML body, or both as alternatives
+#[derive(Debug, Default)]
+pub struct Message {
+    pub from: String,
+    pub to: Vec<String>,
+    pub cc: Vec<String>,
+    pub subject: Option<String>,
+    pub text: Option<String>,
+    pub html: Option<String>,
+}
+
+impl Message {
+    /// The message in the form that is sent, the bodies are encoded in
+    /// base64, and the non-ASCII headers as encoded words
+    pub fn compose(&self) -> Result<Vec<u8>, Error> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)
+            .expect("Time went backwards").as_secs();
+
+        let mut headers = vec![
+            ("From", mailboxes(slice::from_ref(&self.from))?),
+            ("Date", date(now)),
+            ("Message-ID", message_id(now, &self.from)),
+        ];
+        if !self.to.is_empty() {
+            headers.push(("To", mailboxes(&self.to)?));
+        }
+        if !self.cc.is_empty() {
+            headers.push(("Cc", mailboxes(&self.cc)?));
+        }
+        if let Some(subject) = &self.subject {
+            headers.push(("Subject", encode_words(&check("Subject", subject)?)));
+        }
+        headers.push(("MIME-Version", "1.0".to_string()));
+
+        let mut message = String::new();
+        for (name, value) in headers {
+            message.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        match (&self.text, &self.html) {
+            (Some(text), Some(html)) => {
+                let boundary = format!("rusmtp-{:x}", random::<u64>());
+                message.push_str(&format!(
+                    "Content-Type: multipart/alternative; boundary=\"{}\"\r\n\r\n", boundary));
+                for (subtype, body) in &[("plain", text), ("html", html)] {
+                    message.push_str(&format!("--{}\r\n", boundary));
+                    message.push_str(&part(subtype, body));
+                }
+                message.push_str(&format!("--{}--\r\n", boundary));
+            },
+            (None, Some(html))       => message.push_str(&part("html", html)),
+            (text, None)             =>
+                message.push_str(&part("plain", text.as_ref().map_or("", |text| text))),
+        }
+        Ok(message.into_bytes())
+    }
+}
+
+/// The date in the form of RFC 5322 section 3.3, in UTC
+pub fn date(since_the_epoch: u64) -> String {
+    let (weekday, day, month, year, seconds) = civil(since_the_epoch);
+    format!("{}, {} {} {} {:02}:{:02}:{:02} +0000", weekday, day, month, year,
+            seconds / 3_600, seconds % 3_600 / 60, seconds % 60)
+}
+
+/// The date in the form of asctime, in UTC, as in the From lines of mbox
+pub fn asctime(since_the_epoch: u64) -> String {
+    let (weekday, day, month, year, seconds) = civil(since_the_epoch);
+    format!("{} {} {:2} {:02}:{:02}:{:02} {}", weekday, month, day,
+            seconds / 3_600, seconds % 3_600 / 60, seconds % 60, year)
+}
+
+/// The weekday, day, month, year and seconds of the day of the date
+fn civil(since_the_epoch: u64) -> (&'static str, u64, &'static str, u64, u64) {
+    let days = since_the_epoch / 86_400;
+    let seconds = since_the_epoch % 86_400;
+    // 1970-01-01 is a Thursday
+    let weekday = WEEKDAYS[((days + 4) % 7) as usize];
+
+    // The civil date of the days, counting the years from March, so that
+    // the leap day is the last day of the year
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524
+                       - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+
+    (weekday, day, MONTHS[month as usize - 1], year, seconds)
+}
+
+/// A new Message-ID, in the domain of the sender
+pub fn message_id(since_the_epoch: u64, from: &str) -> String {
+    let domain = address(from).rsplit('@').next().unwrap_or("localhost");
+    format!("<{}.{:x}@{}>", since_the_epoch, random::<u64>(), domain)
+}
+
+/// The address of a mailbox, e.g. me@example.com of
+/// `Me <me@example.com>`
+pub fn address(mailbox: &str) -> &str {
+    let mailbox = mailbox.trim();
+    match (mailbox.rfind('<'), mailbox.rfind('>')) {
+        (Some(start), Some(end)) if start < end => mailbox[start + 1..end].trim(),
+        _                                       => mailbox,
+    }
+}
+
+/// Refuses the values that would end the header, and so add others
+fn check(name: &str, value: &str) -> Result<String, Error> {
+    if value.contains(['\r', '\n']) {
+        Err(Error::Protocol(format!("The {} header cannot contain line breaks", name)))
+    } else {
+        Ok(value.to_string())
+    }
+}
+
+/// The mailboxes of an address header, with their non-ASCII display names
+/// encoded
+fn mailboxes(mailboxes: &[String]) -> Result<String, Error> {
+    let mut encoded = Vec::new();
+    for mailbox in mailboxes {
+        let mailbox = check("address", mailbox)?;
+        let mailbox = mailbox.trim();
+        match mailbox.rfind('<') {
+            Some(start) if !mailbox[..start].is_ascii() => encoded.push(
+                format!("{} {}", encode_words(mailbox[..start].trim().trim_matches('"')),
+                        &mailbox[start..])),
+            _                                           => encoded.push(mailbox.to_string()),
+        }
+    }
+    Ok(encoded.join(", "))
+}
+
+/// The text as it is if it is ASCII, or as encoded words otherwise,
+/// RFC 2047
+fn encode_words(text: &str) -> String {
+    if text.is_ascii() {
+        return text.to_string();
+    }
+
+    let mut words = Vec::new();
+    let mut start = 0;
+    while start < text.len() {
+        let mut end = (start + ENCODED_WORD_LENGTH).min(text.len());
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        words.push(format!("=?UTF-8?B?{}?=", encode(&text[start..end])));
+        start = end;
+    }
+    words.join("\r\n ")
+}
+
+/// A body part with its headers, encoded in base64
+fn part(subtype: &str, body: &str) -> String {
+    let mut part = format!("Content-Type: text/{}; charset=utf-8\r\n\
+                            Content-Transfer-Encoding: base64\r\n\r\n", subtype);
+    let encoded = encode(body);
+    for line in encoded.as_bytes().chunks(BASE64_LINE_LENGTH) {
+        part.push_str(&String::from_utf8_lossy(line));
+        part.push_str("\r\n");
+    }
+    part
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_date() {
+        assert_eq!("Thu, 1 Jan 1970 00:00:00 +0000", date(0));
+        assert_eq!("Tue, 29 Feb 2000 13:05:09 +0000", date(951_829_509));
+        assert_eq!("Sun, 18 Oct 2026 10:41:23 +0000", date(1_792_320_083));
+        assert_eq!("Thu Jan  1 00:00:00 1970", asctime(0));
+        assert_eq!("Sun Oct 18 10:41:23 2026", asctime(1_792_320_083));
+    }
+
+    #[test]
+    fn test_address() {
+        assert_eq!("me@example.com", address("me@example.com"));
+        assert_eq!("me@example.com", address("\"Me, Myself\" <me@example.com>"));
+        assert_eq!("me@example.com", address(" <me@example.com> "));
+    }
+
+    #[test]
+    fn test_compose() {
+        let message = Message {
+            from: "Jenkins <ci@example.com>".to_string(),
+            to: vec!["a@b.c".to_string(), "Bären <d@e.f>".to_string()],
+            subject: Some("Build #42 fixed".to_string()),
+            text: Some("All good".to_string()),
+            ..Message::default()
+        };
+        let message = String::from_utf8(message.compose().unwrap()).unwrap();
+        assert!(message.starts_with("From: Jenkins <ci@example.com>\r\nDate: "));
+        assert!(message.contains("\r\nMessage-ID: <"));
+        assert!(message.contains("@example.com>\r\nTo: a@b.c, =?UTF-8?B?QsOkcmVu?= <d@e.f>\r\n\
+                                  Subject: Build #42 fixed\r\nMIME-Version: 1.0\r\n\
+                                  Content-Type: text/plain; charset=utf-8\r\n\
+                                  Content-Transfer-Encoding: base64\r\n\r\nQWxsIGdvb2Q=\r\n"));
+        assert!(!message.contains("Cc:"));
+    }
+
+    #[test]
+    fn test_compose_alternatives() {
+        let message = Message {
+            from: "ci@example.com".to_string(),
+            subject: Some("Größe".to_string()),
+            text: Some("hi".to_string()),
+            html: Some("<p>hi</p>".to_string()),
+            ..Message::default()
+        };
+        let message = String::from_utf8(message.compose().unwrap()).unwrap();
+        assert!(message.contains("Subject: =?UTF-8?B?R3LDtsOfZQ==?=\r\n"));
+        assert!(message.contains("Content-Type: multipart/alternative; boundary=\"rusmtp-"));
+        assert!(message.contains("Content-Type: text/plain; charset=utf-8\r\n\
+                                  Content-Transfer-Encoding: base64\r\n\r\naGk=\r\n--rusmtp-"));
+        assert!(message.contains("Content-Type: text/html; charset=utf-8\r\n\
+                                  Content-Transfer-Encoding: base64\r\n\r\nPHA+aGk8L3A+\r\n"));
+        assert!(message.ends_with("--\r\n"));
+    }
+
+    #[test]
+    fn test_compose_refuses_header_injection() {
+        let message = Message {
+            from: "ci@example.com".to_string(),
+            subject: Some("hi\r\nBcc: everyone@example.com".to_string()),
+            ..Message::default()
+        };
+        assert!(message.compose().is_err());
+    }
+}
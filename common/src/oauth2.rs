@@ -0,0 +1,242 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use native_tls::TlsConnector;
+use crate::Error;
+
+/// The OAuth 2.0 settings of an account, used to obtain fresh access
+/// tokens with the refresh token grant (RFC 6749, section 6).
+pub struct OAuth2 {
+    pub token_endpoint: String,
+    pub client_id: String,
+    pub client_secret_eval: Option<String>,
+    pub refresh_token_eval: String,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct Token {
+    pub access_token: String,
+    /// Lifetime of the access token in seconds
+    pub expires_in: Option<u64>,
+    /// Some providers rotate the refresh token on every refresh
+    pub refresh_token: Option<String>,
+}
+
+struct Endpoint {
+    tls: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Endpoint {
+    fn parse(url: &str) -> Result<Self, Error> {
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(Error::Config(format!("Unsupported token endpoint {}", url)));
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None        => (rest, "/"),
+        };
+
+        let (host, port) = match authority.rfind(':') {
+            Some(index) => {
+                let port = authority[index + 1..].parse()
+                    .map_err(|_| Error::Config(format!("Invalid port in token endpoint {}", url)))?;
+                (&authority[..index], port)
+            },
+            None        => (authority, if tls { 443 } else { 80 }),
+        };
+
+        Ok(Endpoint {
+            tls,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// Exchanges the refresh token for a new access token at the token
+/// endpoint of the provider.
+pub fn refresh(oauth2: &OAuth2, refresh_token: &str, client_secret: Option<&str>,
+               timeout: Duration) -> Result<Token, Error> {
+    let endpoint = Endpoint::parse(&oauth2.token_endpoint)?;
+
+    let mut form = format!("grant_type=refresh_token&refresh_token={}&client_id={}",
+                           url_encode(refresh_token), url_encode(&oauth2.client_id));
+    if let Some(client_secret) = client_secret {
+        form.push_str(&format!("&client_secret={}", url_encode(client_secret)));
+    }
+
+    // HTTP/1.0 keeps the server from answering with a chunked body
+    let request = format!("POST {} HTTP/1.0\r\n\
+                           Host: {}\r\n\
+                           Content-Type: application/x-www-form-urlencoded\r\n\
+                           Accept: application/json\r\n\
+                           Content-Length: {}\r\n\
+                           Connection: close\r\n\r\n{}",
+                          endpoint.path, endpoint.host, form.len(), form);
+
+    debug!("Refreshing the access token at {}", oauth2.token_endpoint);
+    let stream = TcpStream::connect((endpoint.host.as_ref(), endpoint.port))
+        .map_err(|e| Error::Io(format!("Cannot connect to {}: {}", oauth2.token_endpoint, e)))?;
+    let _ = stream.set_read_timeout(Some(timeout));
+    let _ = stream.set_write_timeout(Some(timeout));
+
+    let response = if endpoint.tls {
+        let connector = TlsConnector::new()
+            .map_err(|e| Error::Tls(format!("Cannot create a TLS connector: {}", e)))?;
+        let mut stream = connector.connect(&endpoint.host, stream)
+            .map_err(|e| Error::Tls(format!("Establishing TLS connection with {} failed: {}",
+                                            endpoint.host, e)))?;
+        exchange(&mut stream, request.as_bytes())?
+    } else {
+        let mut stream = stream;
+        exchange(&mut stream, request.as_bytes())?
+    };
+
+    parse_response(&response)
+}
+
+fn exchange<S: Read + Write>(stream: &mut S, request: &[u8]) -> Result<Vec<u8>, Error> {
+    stream.write_all(request)
+        .map_err(|e| Error::Io(format!("Cannot send the token request: {}", e)))?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)
+        .map_err(|e| Error::Io(format!("Cannot read the token response: {}", e)))?;
+    Ok(response)
+}
+
+fn parse_response(response: &[u8]) -> Result<Token, Error> {
+    let response = String::from_utf8_lossy(response);
+    let (head, body) = match response.find("\r\n\r\n") {
+        Some(index) => (&response[..index], &response[index + 4..]),
+        None        => return Err(Error::Protocol(
+            "Malformed response from the token endpoint".to_string())),
+    };
+
+    let status = head.split_whitespace().nth(1).unwrap_or("");
+    if status.starts_with('5') {
+        // The endpoint is down, rather than the grant being refused
+        return Err(Error::Io(format!("The token endpoint is unavailable: {} {}",
+                                     status, body.trim())));
+    }
+    if status != "200" {
+        return Err(Error::Auth(format!("The token endpoint refused to refresh the token: {} {}",
+                                       status, body.trim())));
+    }
+
+    serde_json::from_str(body)
+        .map_err(|e| Error::Protocol(format!("Invalid response from the token endpoint: {}", e)))
+}
+
+fn url_encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' =>
+                encoded.push(byte as char),
+            _ =>
+                encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn serve_once(response: &'static str) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/token", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !is_complete(&String::from_utf8_lossy(&request)) {
+                let n = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..n]);
+            }
+            stream.write_all(response.as_bytes()).unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (url, handle)
+    }
+
+    fn is_complete(request: &str) -> bool {
+        match request.find("\r\n\r\n") {
+            None        => false,
+            Some(index) => {
+                let length: usize = request.lines()
+                    .find(|line| line.starts_with("Content-Length: "))
+                    .and_then(|line| line["Content-Length: ".len()..].parse().ok())
+                    .unwrap_or(0);
+                request.len() >= index + 4 + length
+            },
+        }
+    }
+
+    fn oauth2(token_endpoint: String) -> OAuth2 {
+        OAuth2 {
+            token_endpoint,
+            client_id: "rusmtp client".to_string(),
+            client_secret_eval: None,
+            refresh_token_eval: "echo refresh".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_refresh_access_token() {
+        let (url, server) = serve_once(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n\
+             {\"access_token\":\"ya29.new\",\"expires_in\":3599,\"token_type\":\"Bearer\"}");
+        let token = refresh(&oauth2(url), "1/refresh+token", Some("s3cret"),
+                            Duration::new(5, 0)).unwrap();
+        assert_eq!(Token {
+            access_token: "ya29.new".to_string(),
+            expires_in: Some(3599),
+            refresh_token: None,
+        }, token);
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /token HTTP/1.0\r\n"));
+        assert!(request.ends_with("grant_type=refresh_token&refresh_token=1%2Frefresh%2Btoken\
+                                   &client_id=rusmtp%20client&client_secret=s3cret"));
+    }
+
+    #[test]
+    fn test_refresh_rejected_by_endpoint() {
+        let (url, server) = serve_once(
+            "HTTP/1.1 400 Bad Request\r\n\r\n{\"error\":\"invalid_grant\"}");
+        let res = refresh(&oauth2(url), "revoked", None, Duration::new(5, 0));
+        let error = res.unwrap_err();
+        assert!(!error.is_transient());
+        assert!(error.to_string().contains("invalid_grant"));
+        let _ = server.join();
+    }
+
+    #[test]
+    fn test_parse_endpoint() {
+        let endpoint = Endpoint::parse("https://oauth2.googleapis.com/token").unwrap();
+        assert!(endpoint.tls);
+        assert_eq!("oauth2.googleapis.com", endpoint.host);
+        assert_eq!(443, endpoint.port);
+        assert_eq!("/token", endpoint.path);
+
+        let endpoint = Endpoint::parse("http://127.0.0.1:8080").unwrap();
+        assert!(!endpoint.tls);
+        assert_eq!(8080, endpoint.port);
+        assert_eq!("/", endpoint.path);
+
+        assert!(Endpoint::parse("ftp://example.com").is_err());
+    }
+}
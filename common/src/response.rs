@@ -0,0 +1,381 @@
+use std::fmt;
+use crate::{Error, OK_SIGNAL, ERROR_SIGNAL, PERMANENT_ERROR_SIGNAL, REJECTED_SIGNAL};
+
+/// What happened to an email that was handed to the daemon
+#[derive(Serialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    /// The email is sent to every recipient
+    Sent,
+    /// The email is sent, but some of the recipients were rejected
+    PartiallySent,
+    /// The email is not sent, but it may be if it is retried later
+    TransientFailure,
+    /// The email is not sent, and it never will be as it is
+    PermanentFailure,
+    /// The daemon is serving as many clients as it can, the email was not
+    /// even tried. Older clients cannot read it, and so retry it anyway.
+    Busy,
+}
+
+impl Status {
+    fn to_byte(self) -> u8 {
+        match self {
+            Status::Sent             => 0,
+            Status::PartiallySent    => 1,
+            Status::TransientFailure => 2,
+            Status::PermanentFailure => 3,
+            Status::Busy             => 4,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Status::Sent),
+            1 => Some(Status::PartiallySent),
+            2 => Some(Status::TransientFailure),
+            3 => Some(Status::PermanentFailure),
+            4 => Some(Status::Busy),
+            _ => None,
+        }
+    }
+}
+
+/// The reply of the SMTP server to one of the recipients
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct RecipientStatus {
+    pub recipient: String,
+    pub code: u16,
+    pub enhanced_status: Option<String>,
+    pub message: String,
+}
+
+impl RecipientStatus {
+    pub fn is_accepted(&self) -> bool {
+        self.code / 100 == 2
+    }
+
+    /// The server may accept the recipient if it is retried later
+    pub fn is_transient(&self) -> bool {
+        self.code / 100 == 4
+    }
+}
+
+impl fmt::Display for RecipientStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.recipient, self.code)?;
+        if let Some(enhanced_status) = &self.enhanced_status {
+            write!(f, " {}", enhanced_status)?;
+        }
+        write!(f, " {}", self.message)
+    }
+}
+
+/// The response of the daemon to an email
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct Response {
+    pub status: Status,
+    /// The reply code of the SMTP server, if the transaction got that far
+    pub code: Option<u16>,
+    pub enhanced_status: Option<String>,
+    /// What happened, in a human readable form
+    pub message: String,
+    pub recipients: Vec<RecipientStatus>,
+    /// The id of the email in the queue of the SMTP server
+    pub queue_id: Option<String>,
+}
+
+impl Response {
+    // Version 1 of the responses is the bare signals, OK and ERROR
+    const MAGIC_NUMBER: &'static str = "RUSMTR";
+    const VERSION_MAJOR: u8 = 2;
+    const VERSION_MINOR: u8 = 0;
+
+    /// A response without any reply from the SMTP server
+    pub fn new(status: Status, message: &str) -> Self {
+        Response {
+            status,
+            code: None,
+            enhanced_status: None,
+            message: message.to_string(),
+            recipients: Vec::new(),
+            queue_id: None,
+        }
+    }
+
+    pub fn rejected(&self) -> Vec<&RecipientStatus> {
+        self.recipients.iter().filter(|recipient| !recipient.is_accepted()).collect()
+    }
+
+    fn every_recipient_rejected(&self) -> bool {
+        !self.recipients.is_empty() && self.recipients.iter().all(|r| !r.is_accepted())
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        payload.push(self.status.to_byte());
+        push_u16(&mut payload, self.code.unwrap_or(0));
+        push_string(&mut payload, self.enhanced_status.as_ref().map_or("", |s| s));
+        push_string(&mut payload, &self.message);
+        push_string(&mut payload, self.queue_id.as_ref().map_or("", |s| s));
+        push_u16(&mut payload, self.recipients.len() as u16);
+        for recipient in &self.recipients {
+            push_string(&mut payload, &recipient.recipient);
+            push_u16(&mut payload, recipient.code);
+            push_string(&mut payload, recipient.enhanced_status.as_ref().map_or("", |s| s));
+            push_string(&mut payload, &recipient.message);
+        }
+
+        let mut sink = Vec::new();
+        sink.extend_from_slice(Response::MAGIC_NUMBER.as_bytes());
+        sink.push(Response::VERSION_MAJOR);
+        sink.push(Response::VERSION_MINOR);
+        sink.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        sink.extend(payload);
+        sink
+    }
+
+    /// Whether the response is framed, rather than a bare signal
+    pub fn is_framed(bytes: &[u8]) -> bool {
+        bytes.starts_with(Response::MAGIC_NUMBER.as_bytes())
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader { bytes, position: 0 };
+        if reader.take(Response::MAGIC_NUMBER.len())? != Response::MAGIC_NUMBER.as_bytes() {
+            return Err(Error::Protocol("Bad magic number for response".to_string()));
+        }
+        if reader.u8()? != Response::VERSION_MAJOR {
+            return Err(Error::Protocol("Bad major version number for response".to_string()));
+        }
+        // Newer minor versions only append to the payload
+        let _ = reader.u8()?;
+
+        let mut length = [0u8; 8];
+        length.copy_from_slice(reader.take(8)?);
+        let length = u64::from_be_bytes(length) as usize;
+        let mut reader = Reader { bytes: reader.take(length)?, position: 0 };
+
+        let status = Status::from_byte(reader.u8()?)
+            .ok_or_else(|| Error::Protocol("Unknown status in response".to_string()))?;
+        let code = Some(reader.u16()?).filter(|&code| code != 0);
+        let enhanced_status = reader.optional_string()?;
+        let message = reader.string()?;
+        let queue_id = reader.optional_string()?;
+        let mut recipients = Vec::new();
+        for _ in 0..reader.u16()? {
+            recipients.push(RecipientStatus {
+                recipient: reader.string()?,
+                code: reader.u16()?,
+                enhanced_status: reader.optional_string()?,
+                message: reader.string()?,
+            });
+        }
+
+        Ok(Response { status, code, enhanced_status, message, recipients, queue_id })
+    }
+
+    /// The response for the clients that only understand the bare signals.
+    /// Unless they understand the detailed signals too, the clients only know
+    /// OK and ERROR, and so the email is sent as far as they can tell when
+    /// only some of its recipients were rejected.
+    pub fn legacy_signal(&self, detailed: bool) -> String {
+        if !detailed {
+            return match self.status {
+                Status::Sent | Status::PartiallySent => OK_SIGNAL.to_string(),
+                _                                    => ERROR_SIGNAL.to_string(),
+            };
+        }
+        let rejected = self.status == Status::PartiallySent || self.every_recipient_rejected();
+        if rejected {
+            let mut response = REJECTED_SIGNAL.to_string();
+            for recipient in self.rejected() {
+                let message = match &recipient.enhanced_status {
+                    Some(enhanced_status) => format!("{} {}", enhanced_status, recipient.message),
+                    None                  => recipient.message.clone(),
+                };
+                response.push_str(&format!("\n{} {} {}", recipient.code, recipient.recipient,
+                                           message.replace('\n', " ")));
+            }
+            response
+        } else {
+            match self.status {
+                Status::Sent             => OK_SIGNAL.to_string(),
+                Status::PermanentFailure => PERMANENT_ERROR_SIGNAL.to_string(),
+                _                        => ERROR_SIGNAL.to_string(),
+            }
+        }
+    }
+
+    /// Reads the response of a daemon that only sends the bare signals
+    pub fn from_legacy_signal(signal: &str) -> Option<Self> {
+        if signal == OK_SIGNAL {
+            return Some(Response::new(Status::Sent, "Sent"));
+        } else if signal == ERROR_SIGNAL {
+            return Some(Response::new(Status::TransientFailure,
+                                      "Something is not right in the server"));
+        } else if signal == PERMANENT_ERROR_SIGNAL {
+            return Some(Response::new(Status::PermanentFailure,
+                                      "The server will never accept this email"));
+        }
+
+        let mut lines = signal.lines();
+        if lines.next() != Some(REJECTED_SIGNAL) {
+            return None;
+        }
+        let recipients = lines.map(|line| {
+            let mut parts = line.splitn(3, ' ');
+            let code = parts.next()?.parse().ok()?;
+            let recipient = parts.next()?.to_string();
+            let message = parts.next().unwrap_or("").to_string();
+            Some(RecipientStatus { recipient, code, enhanced_status: None, message })
+        }).collect::<Option<Vec<RecipientStatus>>>()?;
+
+        Some(Response {
+            recipients,
+            ..Response::new(Status::PartiallySent, "Some of the recipients were rejected")
+        })
+    }
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for recipient in self.rejected() {
+            write!(f, "; {}", recipient)?;
+        }
+        Ok(())
+    }
+}
+
+fn push_u16(sink: &mut Vec<u8>, value: u16) {
+    sink.extend_from_slice(&value.to_be_bytes());
+}
+
+fn push_string(sink: &mut Vec<u8>, value: &str) {
+    let bytes = value.as_bytes();
+    let bytes = &bytes[..bytes.len().min(u16::MAX as usize)];
+    push_u16(sink, bytes.len() as u16);
+    sink.extend_from_slice(bytes);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], Error> {
+        let slice = self.bytes.get(self.position..self.position + length)
+            .ok_or_else(|| Error::Protocol("Response unexpectedly truncated".to_string()))?;
+        self.position += length;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let length = self.u16()? as usize;
+        // Truncating a long message may split a character
+        Ok(String::from_utf8_lossy(self.take(length)?).into_owned())
+    }
+
+    fn optional_string(&mut self) -> Result<Option<String>, Error> {
+        Ok(Some(self.string()?).filter(|value| !value.is_empty()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partially_sent() -> Response {
+        Response {
+            status: Status::PartiallySent,
+            code: Some(250),
+            enhanced_status: Some("2.0.0".to_string()),
+            message: "Ok: queued as 4F1A2B".to_string(),
+            recipients: vec![
+                RecipientStatus { recipient: "a@b.c".to_string(), code: 250,
+                                  enhanced_status: Some("2.1.5".to_string()),
+                                  message: "Ok".to_string() },
+                RecipientStatus { recipient: "d@e.f".to_string(), code: 550,
+                                  enhanced_status: Some("5.1.1".to_string()),
+                                  message: "no such user".to_string() },
+            ],
+            queue_id: Some("4F1A2B".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_serializing_deserializing_response() {
+        let expected = partially_sent();
+        let serialized = expected.serialize();
+        assert!(Response::is_framed(&serialized));
+        assert_eq!(Ok(expected), Response::deserialize(&serialized));
+
+        let expected = Response::new(Status::TransientFailure, "Cannot connect");
+        assert_eq!(Ok(expected.clone()), Response::deserialize(&expected.serialize()));
+
+        let expected = Response::new(Status::Busy, "Busy");
+        assert_eq!(Ok(expected.clone()), Response::deserialize(&expected.serialize()));
+    }
+
+    #[test]
+    fn test_truncated_response() {
+        let serialized = partially_sent().serialize();
+        for length in 0..serialized.len() {
+            assert!(Response::deserialize(&serialized[..length]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_legacy_signals() {
+        assert_eq!("OK", Response::new(Status::Sent, "Sent").legacy_signal(true));
+        assert_eq!("ERROR", Response::new(Status::TransientFailure, "").legacy_signal(true));
+        assert_eq!("ERROR", Response::new(Status::Busy, "").legacy_signal(true));
+        assert_eq!("PERMANENT_ERROR",
+                   Response::new(Status::PermanentFailure, "").legacy_signal(true));
+
+        let legacy = partially_sent().legacy_signal(true);
+        assert_eq!("REJECTED\n550 d@e.f 5.1.1 no such user", legacy);
+        let response = Response::from_legacy_signal(&legacy).unwrap();
+        assert_eq!(Status::PartiallySent, response.status);
+        assert_eq!("d@e.f", response.rejected()[0].recipient);
+
+        assert_eq!(Status::Sent, Response::from_legacy_signal("OK").unwrap().status);
+        assert_eq!(None, Response::from_legacy_signal("REJECTED\nfive a@b.c oops"));
+        assert_eq!(None, Response::from_legacy_signal("Hello"));
+    }
+
+    #[test]
+    fn test_legacy_signals_of_the_first_clients() {
+        let mut every_recipient_rejected = partially_sent();
+        every_recipient_rejected.status = Status::PermanentFailure;
+        every_recipient_rejected.recipients.remove(0);
+        let responses = vec![
+            (Response::new(Status::Sent, "Sent"), OK_SIGNAL),
+            (partially_sent(), OK_SIGNAL),
+            (Response::new(Status::TransientFailure, ""), ERROR_SIGNAL),
+            (Response::new(Status::PermanentFailure, ""), ERROR_SIGNAL),
+            (Response::new(Status::Busy, ""), ERROR_SIGNAL),
+            (every_recipient_rejected, ERROR_SIGNAL),
+        ];
+        for (response, expected) in responses {
+            assert_eq!(expected, response.legacy_signal(false), "{:?}", response.status);
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!("Ok: queued as 4F1A2B; d@e.f: 550 5.1.1 no such user",
+                   partially_sent().to_string());
+    }
+}
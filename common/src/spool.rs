@@ -0,0 +1,610 @@
+// The spool, where the emails wait until the daemon retries them, along
+// with what happened to them so far. Like a Maildir, the emails are written
+// to tmp, and renamed to new once they are complete, so that a crash never
+// leaves a truncated email behind. The daemon moves them to cur after it
+// tries them, and to failed when it gives up on them. The emails in hold are
+// kept until they are released.
+
+use fs2::FileExt;
+use rand::random;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::{Error, get_lock_path};
+use crate::mail::Mail;
+
+/// The delay before the first retry, which doubles after every attempt
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// The longest delay between two attempts
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+/// The extension of the metadata files, next to the emails
+pub const METADATA_EXTENSION: &str = "meta";
+/// The directory where the emails are written, before they are queued
+const TMP_DIRECTORY: &str = "tmp";
+
+/// Seconds since the epoch
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// What happened to a spooled email so far
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Metadata {
+    /// When the email was queued, in seconds since the epoch
+    pub queued_at: u64,
+    /// How many times sending the email failed
+    pub attempts: u32,
+    /// When the email is retried next, in seconds since the epoch
+    pub next_attempt_at: u64,
+    /// Why sending the email failed the last time
+    pub last_error: Option<String>,
+    /// The reply code of the server the last time, if it got that far
+    pub last_code: Option<u16>,
+}
+
+impl Metadata {
+    /// The metadata of an email that is queued now, and retried right away
+    pub fn new() -> Self {
+        let now = now();
+        Metadata {
+            queued_at: now,
+            attempts: 0,
+            next_attempt_at: now,
+            last_error: None,
+            last_code: None,
+        }
+    }
+
+    /// The metadata of an email that is queued after sending it failed
+    pub fn failed(error: &str, code: Option<u16>) -> Self {
+        let mut metadata = Metadata::new();
+        metadata.record_failure(error, code);
+        metadata
+    }
+
+    pub fn record_failure(&mut self, error: &str, code: Option<u16>) {
+        self.attempts += 1;
+        self.last_error = Some(error.to_string());
+        self.last_code = code;
+    }
+}
+
+impl Default for Metadata {
+    fn default() -> Self {
+        Metadata::new()
+    }
+}
+
+/// How long, and how many times, the spooled emails are retried
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RetryPolicy {
+    /// How long after an email is queued it is given up on
+    pub max_age: Duration,
+    /// How many attempts an email is given up on after, if there is a limit
+    pub max_attempts: Option<u32>,
+}
+
+impl RetryPolicy {
+    /// How long to wait after the attempts, doubling the delay after every
+    /// attempt up to an hour, and taking up to half of it off at random, so
+    /// that the emails that failed together are not retried together
+    pub fn backoff(&self, attempts: u32) -> Duration {
+        let exponent = attempts.saturating_sub(1).min(16);
+        let delay = (INITIAL_RETRY_DELAY * 2u32.pow(exponent)).min(MAX_RETRY_DELAY);
+        delay - delay.mul_f64(random::<f64>() / 2.0)
+    }
+
+    /// Whether the email should not be retried anymore
+    pub fn is_exhausted(&self, metadata: &Metadata, now: u64) -> bool {
+        now.saturating_sub(metadata.queued_at) >= self.max_age.as_secs() ||
+            self.max_attempts.is_some_and(|max_attempts| metadata.attempts >= max_attempts)
+    }
+}
+
+/// Where a spooled email is
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Queue {
+    /// Queued, and not tried by the daemon yet
+    New,
+    /// Tried by the daemon, and retried later
+    Cur,
+    /// Not retried until it is released
+    Held,
+    /// Given up on, i.e. the dead letters
+    Failed,
+}
+
+impl Queue {
+    pub const ALL: [Queue; 4] = [Queue::New, Queue::Cur, Queue::Held, Queue::Failed];
+
+    pub fn directory(self) -> &'static str {
+        match self {
+            Queue::New    => "new",
+            Queue::Cur    => "cur",
+            Queue::Held   => "hold",
+            Queue::Failed => "failed",
+        }
+    }
+}
+
+/// Takes the flock of the account exclusively, which keeps the clients from
+/// sending with it, and the others from changing its spooled emails
+pub fn lock(flock_root: &str, account: &str) -> Result<File, Error> {
+    let flock_path = get_lock_path(flock_root, account);
+    let lock_file = OpenOptions::new().create(true).append(true).open(&flock_path)
+        .map_err(|e| Error::Io(format!("Cannot open flock {}: {}", flock_path, e)))?;
+    lock_file.lock_exclusive()
+        .map_err(|e| Error::Io(format!("Cannot lock {}: {}", flock_path, e)))?;
+    Ok(lock_file)
+}
+
+/// The id of an email that is queued now for the account. It is the time it
+/// is queued at, a random part and the account, separated by dots, so that
+/// the label of the account can contain anything but a slash.
+pub fn new_id(account: &str, queued_at: u64) -> String {
+    format!("{}.{:016x}.{}", queued_at, random::<u64>(), account)
+}
+
+/// The account of the spooled email, if the id is one
+pub fn account_of(id: &str) -> Option<&str> {
+    let mut parts = id.splitn(3, '.');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(queued_at), Some(rand), Some(account))
+            if queued_at.parse::<u64>().is_ok() &&
+                rand.len() == 16 && u64::from_str_radix(rand, 16).is_ok() &&
+                !account.is_empty() && !account.contains('/') => Some(account),
+        _                                                      => None,
+    }
+}
+
+/// The time the email was queued at, as its id tells
+pub fn queued_at_of(id: &str) -> Option<u64> {
+    id.split('.').next().and_then(|queued_at| queued_at.parse().ok())
+}
+
+/// The account, random part and time of an email that was spooled before
+/// the queues existed, `{account}-{rand}-{queued_at}` in the spool root
+fn legacy_id(name: &str) -> Option<(&str, u64, u64)> {
+    let mut parts = name.rsplitn(3, '-');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(queued_at), Some(rand), Some(account)) if !account.is_empty() =>
+            Some((account, rand.parse().ok()?, queued_at.parse().ok()?)),
+        _                                                                  => None,
+    }
+}
+
+/// The spool of a rusmtprc
+pub struct Spool {
+    root: String,
+}
+
+impl Spool {
+    pub fn new(root: &str) -> Self {
+        Spool {
+            root: root.to_string(),
+        }
+    }
+
+    /// The path of the file in the queue
+    pub fn path(&self, queue: Queue, name: &str) -> String {
+        format!("{}/{}/{}", self.root, queue.directory(), name)
+    }
+
+    fn metadata_path(&self, queue: Queue, id: &str) -> String {
+        self.path(queue, &format!("{}.{}", id, METADATA_EXTENSION))
+    }
+
+    fn create_directories(&self) -> Result<(), Error> {
+        let queues = Queue::ALL.iter().map(|queue| queue.directory());
+        for directory in Some(TMP_DIRECTORY).into_iter().chain(queues) {
+            let path = format!("{}/{}", self.root, directory);
+            fs::create_dir_all(&path)
+                .map_err(|e| Error::Io(format!("Cannot create {}: {}", path, e)))?;
+        }
+        Ok(())
+    }
+
+    /// Writes the file through the tmp directory, and syncs it to the disk,
+    /// so that it is either there completely or not at all
+    fn write(&self, path: &str, bytes: &[u8]) -> Result<(), Error> {
+        let tmp = format!("{}/{}/{}.{:x}", self.root, TMP_DIRECTORY, now(), random::<u64>());
+        let written = File::create(&tmp)
+            .and_then(|mut file| {
+                file.write_all(bytes)?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp, path))
+            .and_then(|_| sync_parent(path));
+        written.map_err(|e| {
+            let _ = fs::remove_file(&tmp);
+            Error::Io(format!("Cannot write {}: {}", path, e))
+        })
+    }
+
+    /// Queues the email, for the daemon to retry it with the account, and
+    /// returns its id
+    pub fn enqueue(&self, mail: &Mail, account: &str, metadata: &Metadata)
+            -> Result<String, Error> {
+        self.create_directories()
+            .map_err(|e| Error::Io(format!("Cannot archive the email: {}", e)))?;
+        let id = new_id(account, metadata.queued_at);
+        // The metadata comes first, so that the email is never without it
+        self.save_metadata(Queue::New, &id, metadata)?;
+        self.write(&self.path(Queue::New, &id), mail.serialize().as_slice())
+            .map_err(|e| Error::Io(format!("Cannot archive the email: {}", e)))?;
+        Ok(id)
+    }
+
+    /// The ids of the emails in the queue, oldest first, and the names of
+    /// the files there that are not spooled emails
+    pub fn ids(&self, queue: Queue) -> Result<(Vec<String>, Vec<String>), Error> {
+        let path = format!("{}/{}", self.root, queue.directory());
+        let entries = match fs::read_dir(&path) {
+            Ok(entries)                                       => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok((vec![], vec![])),
+            Err(e)                                            =>
+                return Err(Error::Io(format!("Cannot read {}: {}", path, e))),
+        };
+        let (mut ids, mut unknown) = (Vec::new(), Vec::new());
+        for entry in entries {
+            let entry = entry.map_err(|e| Error::Io(format!("Cannot read {}: {}", path, e)))?;
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_)   => continue,
+            };
+            if name.ends_with(&format!(".{}", METADATA_EXTENSION)) {
+                continue;
+            }
+            match account_of(&name) {
+                Some(_) => ids.push(name),
+                None    => unknown.push(name),
+            }
+        }
+        ids.sort_by_key(|id| queued_at_of(id));
+        Ok((ids, unknown))
+    }
+
+    /// The queue of the spooled email, if it is still spooled
+    pub fn locate(&self, id: &str) -> Option<Queue> {
+        Queue::ALL.iter().copied().find(|&queue| Path::new(&self.path(queue, id)).is_file())
+    }
+
+    pub fn read(&self, queue: Queue, id: &str) -> Result<Mail, Error> {
+        let mut bytes = fs::read(self.path(queue, id))
+            .map_err(|e| Error::Io(format!("Cannot read {}: {}", id, e)))?;
+        Mail::deserialize(&mut bytes)
+    }
+
+    /// Replaces the spooled email, e.g. with the recipients that are left
+    pub fn rewrite(&self, queue: Queue, id: &str, mail: &Mail) -> Result<(), Error> {
+        self.write(&self.path(queue, id), mail.serialize().as_slice())
+    }
+
+    /// The metadata of the spooled email, if it has any
+    pub fn metadata(&self, queue: Queue, id: &str) -> Result<Option<Metadata>, Error> {
+        match fs::read(self.metadata_path(queue, id)) {
+            Ok(bytes)                                         => serde_json::from_slice(&bytes)
+                .map(Some)
+                .map_err(|e| Error::Protocol(format!("Invalid metadata of {}: {}", id, e))),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e)                                            =>
+                Err(Error::Io(format!("Cannot read the metadata of {}: {}", id, e))),
+        }
+    }
+
+    /// The metadata of the spooled email, starting over when it is lost or
+    /// cannot be read
+    pub fn metadata_or_new(&self, queue: Queue, id: &str) -> Metadata {
+        let metadata = self.metadata(queue, id).unwrap_or_else(|error| {
+            warn!("{}, starting over", error);
+            None
+        });
+        metadata.unwrap_or_else(|| Metadata {
+            queued_at: queued_at_of(id).unwrap_or_else(now),
+            ..Metadata::new()
+        })
+    }
+
+    pub fn save_metadata(&self, queue: Queue, id: &str, metadata: &Metadata)
+            -> Result<(), Error> {
+        let json = serde_json::to_vec(metadata)
+            .map_err(|e| Error::Protocol(format!("Cannot encode the metadata of {}: {}", id, e)))?;
+        self.write(&self.metadata_path(queue, id), &json)
+            .map_err(|e| Error::Io(format!("Cannot write the metadata of {}: {}", id, e)))
+    }
+
+    /// Moves the spooled email to the other queue, along with its metadata
+    pub fn move_to(&self, id: &str, from: Queue, to: Queue, metadata: &Metadata)
+            -> Result<(), Error> {
+        self.create_directories()?;
+        self.save_metadata(to, id, metadata)?;
+        let path = self.path(to, id);
+        fs::rename(self.path(from, id), &path)
+            .and_then(|_| sync_parent(&path))
+            .map_err(|e| Error::Io(format!("Cannot move {} to {}: {}", id, to.directory(), e)))?;
+        match fs::remove_file(self.metadata_path(from, id)) {
+            Err(ref e) if e.kind() != io::ErrorKind::NotFound =>
+                Err(Error::Io(format!("Cannot remove the metadata of {}: {}", id, e))),
+            _                                                 => Ok(()),
+        }
+    }
+
+    /// Moves the spooled email to the failed queue, along with its metadata,
+    /// which tells why it failed the last time
+    pub fn fail(&self, queue: Queue, id: &str, metadata: &Metadata) -> Result<(), Error> {
+        self.move_to(id, queue, Queue::Failed, metadata)
+    }
+
+    /// Removes the spooled email, along with its metadata
+    pub fn remove(&self, queue: Queue, id: &str) -> Result<(), Error> {
+        fs::remove_file(self.path(queue, id))
+            .map_err(|e| Error::Io(format!("Cannot remove {}: {}", id, e)))?;
+        match fs::remove_file(self.metadata_path(queue, id)) {
+            Err(ref e) if e.kind() != io::ErrorKind::NotFound =>
+                Err(Error::Io(format!("Cannot remove the metadata of {}: {}", id, e))),
+            _                                                 => Ok(()),
+        }
+    }
+
+    /// Removes what was left in the tmp directory for longer than the age,
+    /// by the writers that crashed
+    pub fn clean_tmp(&self, age: Duration) -> Result<(), Error> {
+        let path = format!("{}/{}", self.root, TMP_DIRECTORY);
+        let entries = match fs::read_dir(&path) {
+            Ok(entries) => entries,
+            Err(_)      => return Ok(()),
+        };
+        for entry in entries.flatten() {
+            let stale = entry.metadata().and_then(|metadata| metadata.modified())
+                .map(|modified| modified.elapsed().unwrap_or_default() >= age)
+                .unwrap_or(false);
+            if stale {
+                warn!("Removing {}, which was never completely written", entry.path().display());
+                fs::remove_file(entry.path())
+                    .map_err(|e| Error::Io(format!("Cannot remove {}: {}", path, e)))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The names of the emails that were spooled before the queues existed,
+    /// along with their accounts
+    pub fn legacy_ids(&self) -> Result<Vec<(String, String)>, Error> {
+        let entries = match fs::read_dir(&self.root) {
+            Ok(entries)                                       => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e)                                            =>
+                return Err(Error::Io(format!("Cannot read {}: {}", self.root, e))),
+        };
+        let mut legacy = Vec::new();
+        for entry in entries.flatten() {
+            if !entry.path().is_file() {
+                continue;
+            }
+            if let Ok(name) = entry.file_name().into_string() {
+                if let Some((account, _, _)) = legacy_id(&name) {
+                    legacy.push((name.clone(), account.to_string()));
+                }
+            }
+        }
+        Ok(legacy)
+    }
+
+    /// Queues the email that was spooled before the queues existed, and
+    /// returns its new id and queue. The writers of that spool did not write
+    /// the emails atomically, so the ones that are truncated go to the
+    /// failed queue.
+    pub fn migrate(&self, name: &str) -> Result<(String, Queue), Error> {
+        let (account, rand, queued_at) = legacy_id(name)
+            .ok_or_else(|| Error::Protocol(format!("{} is not a spooled email", name)))?;
+        self.create_directories()?;
+        let id = format!("{}.{:016x}.{}", queued_at, rand, account);
+        let path = format!("{}/{}", self.root, name);
+        let metadata_path = format!("{}.{}", path, METADATA_EXTENSION);
+        let mut metadata = match fs::read(&metadata_path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).ok(),
+            Err(_)    => None,
+        }.unwrap_or_else(|| Metadata {
+            queued_at,
+            ..Metadata::new()
+        });
+
+        let mut bytes = fs::read(&path)
+            .map_err(|e| Error::Io(format!("Cannot read {}: {}", name, e)))?;
+        let queue = match Mail::deserialize(&mut bytes) {
+            Ok(_)      => Queue::New,
+            Err(error) => {
+                metadata.last_error = Some(format!("Cannot read the spooled email: {}", error));
+                Queue::Failed
+            },
+        };
+        self.save_metadata(queue, &id, &metadata)?;
+        let new_path = self.path(queue, &id);
+        fs::rename(&path, &new_path)
+            .and_then(|_| sync_parent(&new_path))
+            .map_err(|e| Error::Io(format!("Cannot move {} to {}: {}", name, id, e)))?;
+        match fs::remove_file(&metadata_path) {
+            Err(ref e) if e.kind() != io::ErrorKind::NotFound =>
+                Err(Error::Io(format!("Cannot remove the metadata of {}: {}", name, e))),
+            _                                                 => Ok((id, queue)),
+        }
+    }
+}
+
+/// Syncs the directory of the path, so that a rename in it survives a crash
+fn sync_parent(path: &str) -> io::Result<()> {
+    match Path::new(path).parent() {
+        Some(parent) => File::open(parent)?.sync_all(),
+        None         => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, read_dir, remove_dir_all};
+    use std::process;
+
+    fn policy(max_attempts: Option<u32>) -> RetryPolicy {
+        RetryPolicy {
+            max_age: Duration::from_secs(24 * 60 * 60),
+            max_attempts,
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = policy(None);
+        for _ in 0..100 {
+            let first = policy.backoff(1);
+            assert!(first >= Duration::from_secs(30) && first <= Duration::from_secs(60));
+            let third = policy.backoff(3);
+            assert!(third >= Duration::from_secs(120) && third <= Duration::from_secs(240));
+            let late = policy.backoff(1000);
+            assert!(late >= Duration::from_secs(30 * 60) && late <= Duration::from_secs(60 * 60));
+        }
+    }
+
+    #[test]
+    fn test_is_exhausted() {
+        let mut metadata = Metadata::failed("Connection refused", None);
+        let now = metadata.queued_at;
+        assert!(!policy(None).is_exhausted(&metadata, now));
+        assert!(!policy(Some(2)).is_exhausted(&metadata, now));
+        assert!(policy(None).is_exhausted(&metadata, now + 24 * 60 * 60));
+
+        metadata.record_failure("451 4.3.0 Try again later", Some(451));
+        assert_eq!(2, metadata.attempts);
+        assert_eq!(Some(451), metadata.last_code);
+        assert!(policy(Some(2)).is_exhausted(&metadata, now));
+        assert!(!policy(Some(3)).is_exhausted(&metadata, now));
+    }
+
+    fn spool(name: &str) -> (String, Spool) {
+        let root = temp_dir().join(format!("rusmtp-spool-{}-{}", name, process::id()));
+        let _ = remove_dir_all(&root);
+        create_dir_all(&root).unwrap();
+        let root = root.display().to_string();
+        let spool = Spool::new(&root);
+        (root, spool)
+    }
+
+    fn mail() -> Mail {
+        Mail {
+            account: Some("first".to_string()),
+            recipients: vec!["a@b.c".to_string()],
+            dsn_notify: None,
+            dsn_ret: None,
+            envid: None,
+            body: b"hi".to_vec(),
+        }
+    }
+
+    fn names(path: &str) -> Vec<String> {
+        let mut names: Vec<_> = read_dir(path).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_ids() {
+        let id = new_id("my-work.account", 1_792_320_083);
+        assert!(id.starts_with("1792320083."));
+        assert_eq!(Some("my-work.account"), account_of(&id));
+        assert_eq!(Some(1_792_320_083), queued_at_of(&id));
+        assert_eq!(None, account_of("1792320083.0123456789abcdef."));
+        assert_eq!(None, account_of("1792320083.xyz.first"));
+        assert_eq!(None, account_of("first-42-1792320083"));
+
+        assert_eq!(Some(("my-work", 42, 1_792_320_083)), legacy_id("my-work-42-1792320083"));
+        assert_eq!(None, legacy_id("first-42"));
+        assert_eq!(None, legacy_id("first-x-1792320083"));
+    }
+
+    #[test]
+    fn test_enqueue_and_remove() {
+        let (root, spool) = spool("enqueue");
+        let metadata = Metadata::failed("Connection refused", None);
+        let id = spool.enqueue(&mail(), "first", &metadata).unwrap();
+        assert_eq!(Some("first"), account_of(&id));
+        assert_eq!((vec![id.clone()], vec![]), spool.ids(Queue::New).unwrap());
+        assert_eq!(mail(), spool.read(Queue::New, &id).unwrap());
+        assert_eq!(Some(metadata), spool.metadata(Queue::New, &id).unwrap());
+        assert!(names(&format!("{}/tmp", root)).is_empty());
+
+        spool.remove(Queue::New, &id).unwrap();
+        assert!(names(&format!("{}/new", root)).is_empty());
+        assert_eq!(None, spool.metadata(Queue::New, &id).unwrap());
+        let _ = remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_fail() {
+        let (root, spool) = spool("fail");
+        let mut metadata = Metadata::failed("Connection refused", None);
+        let id = spool.enqueue(&mail(), "first", &metadata).unwrap();
+        metadata.record_failure("550 5.1.1 No such user", Some(550));
+        spool.fail(Queue::New, &id, &metadata).unwrap();
+
+        assert!(names(&format!("{}/new", root)).is_empty());
+        assert_eq!((vec![id.clone()], vec![]), spool.ids(Queue::Failed).unwrap());
+        assert_eq!(mail(), spool.read(Queue::Failed, &id).unwrap());
+        assert_eq!(Some(metadata), spool.metadata(Queue::Failed, &id).unwrap());
+        let _ = remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_migrate() {
+        let (root, spool) = spool("migrate");
+        fs::write(format!("{}/my-work-42-1792320083", root), mail().serialize()).unwrap();
+        let metadata = Metadata::failed("Connection refused", None);
+        fs::write(format!("{}/my-work-42-1792320083.meta", root),
+                  serde_json::to_vec(&metadata).unwrap()).unwrap();
+        let mut truncated = mail().serialize();
+        truncated.truncate(truncated.len() - 1);
+        fs::write(format!("{}/first-7-1792320084", root), truncated).unwrap();
+
+        let mut legacy = spool.legacy_ids().unwrap();
+        legacy.sort();
+        assert_eq!(vec![("first-7-1792320084".to_string(), "first".to_string()),
+                        ("my-work-42-1792320083".to_string(), "my-work".to_string())],
+                   legacy);
+        assert_eq!(("1792320083.000000000000002a.my-work".to_string(), Queue::New),
+                   spool.migrate("my-work-42-1792320083").unwrap());
+        assert_eq!(("1792320084.0000000000000007.first".to_string(), Queue::Failed),
+                   spool.migrate("first-7-1792320084").unwrap());
+
+        assert_eq!(vec!["cur", "failed", "hold", "new", "tmp"], names(&root));
+        assert_eq!(mail(), spool.read(Queue::New, "1792320083.000000000000002a.my-work").unwrap());
+        assert_eq!(Some(metadata),
+                   spool.metadata(Queue::New, "1792320083.000000000000002a.my-work").unwrap());
+        let failed = spool.metadata(Queue::Failed, "1792320084.0000000000000007.first")
+            .unwrap().unwrap();
+        assert_eq!(1_792_320_084, failed.queued_at);
+        assert!(failed.last_error.unwrap().contains("truncated"));
+        let _ = remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_unknown_files_and_stale_tmp() {
+        let (root, spool) = spool("unknown");
+        create_dir_all(format!("{}/new", root)).unwrap();
+        create_dir_all(format!("{}/tmp", root)).unwrap();
+        fs::write(format!("{}/new/notes.txt", root), b"").unwrap();
+        fs::write(format!("{}/tmp/1.2", root), b"").unwrap();
+        assert_eq!((vec![], vec!["notes.txt".to_string()]), spool.ids(Queue::New).unwrap());
+        assert_eq!((vec![], vec![]), spool.ids(Queue::Cur).unwrap());
+
+        spool.clean_tmp(Duration::from_secs(60)).unwrap();
+        assert_eq!(vec!["1.2"], names(&format!("{}/tmp", root)));
+        spool.clean_tmp(Duration::from_secs(0)).unwrap();
+        assert!(names(&format!("{}/tmp", root)).is_empty());
+        let _ = remove_dir_all(&root);
+    }
+}
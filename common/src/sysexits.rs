@@ -0,0 +1,11 @@
+// The exit codes of sysexits.h, so that mail user agents and cron can tell
+// the failures apart.
+
+pub const EX_USAGE: i32 = 64;
+pub const EX_NOINPUT: i32 = 66;
+pub const EX_NOUSER: i32 = 67;
+pub const EX_UNAVAILABLE: i32 = 69;
+pub const EX_IOERR: i32 = 74;
+pub const EX_CANTCREAT: i32 = 73;
+pub const EX_TEMPFAIL: i32 = 75;
+pub const EX_CONFIG: i32 = 78;
@@ -0,0 +1,54 @@
+// Delivers the bounces of the emails, or of the recipients of them, that the
+// daemon gives up on, to where the rusmtprc asks for.
+
+use client::send_to_daemon;
+use common::Error;
+use common::bounce::{self, BounceTarget};
+use common::mail::Mail;
+use common::response::RecipientStatus;
+use common::spool::{now, Metadata};
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[derive(Clone)]
+pub struct Bouncer {
+    pub target: BounceTarget,
+    pub socket_root: String,
+    pub timeout: Duration,
+    /// The usernames of the accounts, by their labels, which the bounces
+    /// are sent from and to
+    pub usernames: HashMap<String, String>,
+}
+
+impl Bouncer {
+    /// Bounces the email of the account for the rejected recipients, or for
+    /// every recipient when there are none
+    pub fn bounce(&self, account: &str, mail: &Mail, metadata: &Metadata,
+                  rejected: &[RecipientStatus]) -> Result<(), Error> {
+        let username = self.usernames.get(account);
+        let address = username.map_or("MAILER-DAEMON@localhost", |username| username.as_str());
+        let now = now();
+        let bounce = bounce::compose(mail, metadata, rejected, address, now);
+        match &self.target {
+            BounceTarget::Mbox(path)    => bounce::append_to_mbox(path, &bounce, now),
+            BounceTarget::Maildir(path) => bounce::deliver_to_maildir(path, &bounce, now),
+            BounceTarget::Sender        => {
+                let username = username.ok_or_else(|| Error::Config(
+                    format!("{} has no username to send the bounce to", account)))?;
+                let bounce = Mail {
+                    account: Some(account.to_string()),
+                    recipients: vec![username.clone()],
+                    dsn_notify: None,
+                    dsn_ret: None,
+                    envid: None,
+                    body: bounce,
+                };
+                // Not spooled when it fails, so that the bounces never
+                // bounce themselves
+                send_to_daemon(&bounce, &self.socket_root, self.timeout, account)
+                    .map(|_| ())
+                    .map_err(|error| Error::Io(error.to_string()))
+            },
+        }
+    }
+}
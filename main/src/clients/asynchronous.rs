@@ -0,0 +1,366 @@
+use protocol::{Authentication, Capabilities, Delivery, Dsn, Reply, negotiate};
+use protocol::async_raven::{AsyncRaven, AsyncStartTls, AsyncTcpStream, AsyncTlsStream};
+use common::{Error, get_socket_path};
+use common::mail::Mail;
+use common::response::{Response, Status};
+use common::account::{Account, AuthMethod};
+use crate::clients::default::{DefaultClient, delivery_response, failure_response};
+use crate::clients::pool::{TURN_AWAY_TIMEOUT, busy_response};
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{Mutex, Semaphore};
+use tokio::task::block_in_place;
+
+/// The counterpart of DefaultClient that runs on the tokio runtime, so
+/// that the accounts do not need a thread each
+pub struct AsyncClient {
+    /// The account, and its OAuth 2.0 tokens
+    client: DefaultClient,
+    idle: Mutex<Option<(AsyncMailer, Instant)>>,
+}
+
+/// An authenticated connection, along with the capabilities that the
+/// server advertised on it
+enum AsyncMailer {
+    Plain(AsyncTcpStream, Capabilities),
+    Secured(AsyncTlsStream, Capabilities),
+}
+
+impl AsyncMailer {
+    async fn send_mail(&mut self, from: &str, recipients: &[&str], dsn: &Dsn, body: &[u8])
+            -> Result<Delivery, Error> {
+        match self {
+            AsyncMailer::Plain(mailer, capabilities)   =>
+                mailer.send_mail(capabilities, from, recipients, dsn, body).await,
+            AsyncMailer::Secured(mailer, capabilities) =>
+                mailer.send_mail(capabilities, from, recipients, dsn, body).await,
+        }
+    }
+
+    async fn reset(&mut self) -> Result<Reply, Error> {
+        match self {
+            AsyncMailer::Plain(mailer, _)   => mailer.reset().await,
+            AsyncMailer::Secured(mailer, _) => mailer.reset().await,
+        }
+    }
+
+    async fn noop(&mut self) -> Result<Reply, Error> {
+        match self {
+            AsyncMailer::Plain(mailer, _)   => mailer.noop().await,
+            AsyncMailer::Secured(mailer, _) => mailer.noop().await,
+        }
+    }
+
+    async fn quit(&mut self) -> Result<Reply, Error> {
+        match self {
+            AsyncMailer::Plain(mailer, _)   => mailer.quit().await,
+            AsyncMailer::Secured(mailer, _) => mailer.quit().await,
+        }
+    }
+
+    async fn close(&mut self) {
+        match self {
+            AsyncMailer::Plain(mailer, _)   => mailer.close().await,
+            AsyncMailer::Secured(mailer, _) => mailer.close().await,
+        }
+    }
+}
+
+impl AsyncClient {
+    fn account(&self) -> &Account {
+        &self.client.account
+    }
+
+    async fn open_connection<R: AsyncRaven>(&self) -> Result<(R, Capabilities), Error> {
+        let account = self.account();
+        let label = &account.label;
+
+        let (host, port) = match (&account.host, account.port) {
+            (Some(host), Some(port)) => (host, port),
+            (None, _)                => return Err(Error::Config(
+                format!("Please configure the host for {}", label))),
+            (_, None)                => return Err(Error::Config(
+                format!("Please configure the port for {}", label))),
+        };
+
+        let cert_root = account.cert_root.as_ref().map(|c| c.to_owned());
+        let mut mailer = R::create_connection(host, port, account.timeout, cert_root).await?;
+        let capabilities = mailer.hand_shake(host).await?;
+        Ok((mailer, capabilities))
+    }
+
+    async fn authenticate<R: AsyncRaven>(&self, mailer: &mut R, capabilities: &Capabilities,
+                                         passwd: &[u8]) -> Result<(), Error> {
+        let account = self.account();
+        let vault = &account.vault;
+        let username = account.username.as_ref().ok_or_else(||
+            Error::Config(format!("Please configure the username for {}", account.label)))?;
+        let auths = &capabilities.auths;
+        match account.auth {
+            AuthMethod::Login if auths == &[Authentication::None] => return Ok(()),
+            AuthMethod::Login => {
+                let preferred = self.client.password_mechanisms();
+                match negotiate(auths, &preferred) {
+                    Some(auth) => {
+                        debug!("Authenticating {} with {:?}", account.label, auth);
+                        mailer.authenticate_with_password(auth, username,
+                            vault.decrypt(passwd)?.as_bytes()).await
+                    },
+                    None       => Err(Error::Auth(format!(
+                        "The server of {} supports none of the mechanisms {:?}",
+                        account.label, preferred))),
+                }
+            },
+            AuthMethod::XOAuth2 if auths.contains(&Authentication::XAuth2) =>
+                mailer.authenticate_with_xoauth2(username, &vault.decrypt(passwd)?).await,
+            AuthMethod::OAuthBearer if auths.contains(&Authentication::OAuthBearer) => {
+                let host = account.host.as_ref().unwrap();
+                let port = account.port.unwrap();
+                mailer.authenticate_with_oauthbearer(username, host, port,
+                    &vault.decrypt(passwd)?).await
+            },
+            auth => Err(Error::Auth(format!("The server of {} does not support {:?}",
+                                            account.label, auth))),
+        }.map(|_| ())
+    }
+
+    async fn get_mailer(&self, passwd: &[u8]) -> Result<AsyncMailer, Error> {
+        let account = self.account();
+
+        if account.tls.unwrap_or(false) {
+            let (mut mailer, capabilities) = self.open_connection::<AsyncTlsStream>().await?;
+            self.authenticate(&mut mailer, &capabilities, passwd).await?;
+            return Ok(AsyncMailer::Secured(mailer, capabilities));
+        }
+
+        let (mut mailer, capabilities) = self.open_connection::<AsyncTcpStream>().await?;
+        let host = account.host.as_ref().unwrap();
+
+        if capabilities.starttls {
+            let cert_root = account.cert_root.as_ref().map(|c| c.to_owned());
+            let mut mailer = mailer.start_tls(host, cert_root).await?;
+
+            debug!("Shaking hands with the server again, but this time over TLS");
+            let capabilities = mailer.ehlo(host).await?;
+
+            self.authenticate(&mut mailer, &capabilities, passwd).await?;
+            Ok(AsyncMailer::Secured(mailer, capabilities))
+        } else if account.require_starttls {
+            Err(Error::Tls(format!(
+                "{} does not support STARTTLS, refusing to send over a plain connection", host)))
+        } else {
+            self.authenticate(&mut mailer, &capabilities, passwd).await?;
+            Ok(AsyncMailer::Plain(mailer, capabilities))
+        }
+    }
+
+    /// Refreshing the OAuth 2.0 tokens blocks, so the runtime is told to
+    /// move the other tasks off this thread meanwhile
+    fn password(&self) -> Result<Vec<u8>, Error> {
+        block_in_place(|| self.client.password(&self.account().vault))
+    }
+
+    async fn connect(&self) -> Result<AsyncMailer, Error> {
+        let account = self.account();
+        let password = self.password()?;
+        match self.get_mailer(&password).await {
+            Err(Error::Auth(error)) if account.oauth2.is_some() => {
+                // The access token might have been revoked or expired early
+                info!("Refreshing the access token of {} again: {}", account.label, error);
+                block_in_place(|| self.client.refresh_access_token(&account.vault))?;
+                let password = self.password()?;
+                self.get_mailer(&password).await
+            },
+            result                                              => result,
+        }
+    }
+
+    /// Takes the idle connection if the server still answers on it,
+    /// otherwise opens a new one
+    async fn checkout(&self) -> Result<AsyncMailer, Error> {
+        let label = &self.account().label;
+        let idle = self.idle.lock().await.take();
+        if let Some((mut mailer, _)) = idle {
+            match mailer.noop().await {
+                Ok(_)      => {
+                    debug!("Reusing the connection of {}", label);
+                    return Ok(mailer);
+                },
+                Err(error) => {
+                    info!("Reconnecting {}: {}", label, error);
+                    mailer.close().await;
+                },
+            }
+        }
+        self.connect().await
+    }
+
+    /// Keeps the connection open for the next email, and ends the session
+    /// once it is idle for too long
+    async fn release(self: &Arc<Self>, mut mailer: AsyncMailer) {
+        let label = &self.account().label;
+        let idle_timeout = self.account().idle_timeout;
+        if idle_timeout == Duration::from_secs(0) {
+            if let Err(error) = mailer.quit().await {
+                debug!("{}: {}", label, error);
+            }
+            return;
+        }
+
+        if let Err(error) = mailer.reset().await {
+            info!("Closing the connection of {}: {}", label, error);
+            mailer.close().await;
+            return;
+        }
+
+        let replaced = self.idle.lock().await.replace((mailer, Instant::now()));
+        if let Some((mut mailer, _)) = replaced {
+            if let Err(error) = mailer.quit().await {
+                debug!("{}: {}", label, error);
+            }
+        }
+
+        let client = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(idle_timeout).await;
+            let mut idle = client.idle.lock().await;
+            let expired = matches!(&*idle,
+                Some((_, last_used)) if last_used.elapsed() >= idle_timeout);
+            if expired {
+                if let Some((mut mailer, _)) = idle.take() {
+                    debug!("Closing the idle connection of {}", client.account().label);
+                    let _ = mailer.quit().await;
+                }
+            }
+        });
+    }
+
+    async fn deliver(self: &Arc<Self>, bytes: &mut Vec<u8>) -> Response {
+        let mail = match Mail::deserialize(bytes) {
+            Ok(mail) => mail,
+            Err(e)   => return Response::new(Status::PermanentFailure,
+                &format!("Error happened while reading the incoming email {}", e)),
+        };
+
+        let account = self.account();
+        let mut mailer = match self.checkout().await {
+            Ok(mailer) => mailer,
+            Err(error) => return failure_response(&error),
+        };
+        let username = account.username.as_ref().unwrap();
+
+        let recipients: Vec<&str> = mail.recipients.iter()
+            .filter(|&s| s != "--").map(|s| s.deref()).collect();
+        let dsn = Dsn {
+            notify: mail.dsn_notify.clone(),
+            ret: mail.dsn_ret.clone(),
+            envid: mail.envid.clone(),
+        };
+        let body = &mail.body;
+
+        match mailer.send_mail(username, &recipients, &dsn, body).await {
+            Ok(delivery) => {
+                self.release(mailer).await;
+                delivery_response(&delivery)
+            },
+            Err(error)   => {
+                // The state of the session is unknown, so it is not reused
+                mailer.close().await;
+                failure_response(&error)
+            },
+        }
+    }
+
+    async fn handle(self: Arc<Self>, mut stream: UnixStream) {
+        let mut bytes = Vec::new();
+        let read = stream.read_to_end(&mut bytes).await;
+        // Clients that predate the framed responses only understand the signals
+        let framed = Mail::expects_framed_response(&bytes);
+        let detailed = Mail::expects_detailed_signals(&bytes);
+        let response = match read {
+            Ok(_)  => self.deliver(&mut bytes).await,
+            Err(e) => Response::new(Status::TransientFailure,
+                &format!("Error happened while reading the incoming email {}", e)),
+        };
+
+        match response.status {
+            Status::Sent => info!("{}", response),
+            _            => error!("{}", response),
+        }
+        if !framed && !detailed && response.status == Status::PartiallySent {
+            warn!("The client only understands OK and ERROR, and is not told of the rejected \
+                   recipients");
+        }
+
+        if framed {
+            let _ = stream.write_all(&response.serialize()).await;
+        } else {
+            let _ = stream.write_all(response.legacy_signal(detailed).as_bytes()).await;
+        }
+    }
+
+    /// Serves the clients of the account on the runtime, with at most
+    /// max-concurrent-connections emails in flight
+    pub async fn start(self: Arc<Self>, prefix: String) {
+        let account = self.account();
+        let label = account.label.clone();
+
+        if account.password.is_none() && account.oauth2.is_none() {
+            error!("Password is not defined for {}", &label);
+            return;
+        }
+
+        if account.oauth2.is_some() {
+            let refreshed = block_in_place(|| self.client.refresh_access_token(&account.vault));
+            if let Err(error) = refreshed {
+                error!("Cannot refresh the access token of {}: {}", label, error);
+            }
+        }
+
+        let listener = match UnixListener::bind(get_socket_path(&prefix, &label)) {
+            Ok(listener) => listener,
+            Err(error)   => {
+                error!("Cannot open the socket of {}: {}", label, error);
+                return;
+            },
+        };
+
+        let permits = Arc::new(Semaphore::new(account.max_concurrent_connections));
+        loop {
+            let mut stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(_)          => {
+                    /* connection failed */
+                    break;
+                },
+            };
+
+            match permits.clone().try_acquire_owned() {
+                Ok(permit) => {
+                    let client = self.clone();
+                    tokio::spawn(async move {
+                        client.handle(stream).await;
+                        drop(permit);
+                    });
+                },
+                Err(_)     => {
+                    warn!("Every connection of {} is busy, turning a client away", label);
+                    let label = label.clone();
+                    tokio::spawn(async move {
+                        let mut bytes = Vec::new();
+                        let _ = tokio::time::timeout(TURN_AWAY_TIMEOUT,
+                                                     stream.read_to_end(&mut bytes)).await;
+                        let _ = stream.write_all(&busy_response(&bytes, &label)).await;
+                    });
+                },
+            }
+        }
+    }
+
+    pub fn new(account: Account) -> Self {
+        AsyncClient { client: DefaultClient::new(account), idle: Mutex::new(None) }
+    }
+}
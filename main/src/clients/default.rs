@@ -1,120 +1,415 @@
-use protocol::{Raven, Authentication};
-use common::{ERROR_SIGNAL,OK_SIGNAL,get_socket_path};
+use protocol::{Raven, Stream, StartTls, Authentication, BufTcpStream, BufTlsStream,
+               Capabilities, Delivery, Dsn, Reply, negotiate};
+use common::{Error, get_socket_path, evaluate};
 use common::mail::Mail;
+use common::response::{Response, RecipientStatus, Status};
+use common::oauth2;
 use common::vault::Vault;
-use common::account::Account;
-use native_tls::TlsStream;
+use common::account::{Account, AuthMethod};
 use std::io::{Read, Write};
 use std::os::unix::net::{UnixListener, UnixStream};
+use crate::clients::pool::serve;
 use std::ops::Deref;
-use std::net::TcpStream;
+use std::sync::{Mutex, Condvar};
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub struct DefaultClient {
     pub account: Account,
+    tokens: Mutex<Tokens>,
+    idle: Mutex<Idle>,
+    idle_changed: Condvar,
 }
 
-impl DefaultClient {
-    fn get_mailer<R: Raven>(&self, vault: &Vault, passwd: &[u8]) -> Option<R> {
-        let account = &self.account;
+/// An authenticated connection, along with the capabilities that the
+/// server advertised on it
+enum Mailer {
+    Plain(BufTcpStream, Capabilities),
+    Secured(BufTlsStream, Capabilities),
+}
 
-        let label    = &account.label.to_string();
+impl Mailer {
+    fn send_mail(&mut self, from: &str, recipients: &[&str], dsn: &Dsn, body: &[u8])
+            -> Result<Delivery, Error> {
+        match self {
+            Mailer::Plain(mailer, capabilities)   =>
+                mailer.send_mail(capabilities, from, recipients, dsn, body),
+            Mailer::Secured(mailer, capabilities) =>
+                mailer.send_mail(capabilities, from, recipients, dsn, body),
+        }
+    }
+
+    fn reset(&mut self) -> Result<Reply, Error> {
+        match self {
+            Mailer::Plain(mailer, _)   => mailer.reset(),
+            Mailer::Secured(mailer, _) => mailer.reset(),
+        }
+    }
 
-        if account.host.is_none() {
-            error!("Please configure the host for {}", label);
-            return None;
-        } else if account.username.is_none() {
-            error!("Please configure the username for {}", label);
-            return None;
-        } else if account.port.is_none() {
-            error!("Please configure the port for {}", label);
-            return None;
+    fn noop(&mut self) -> Result<Reply, Error> {
+        match self {
+            Mailer::Plain(mailer, _)   => mailer.noop(),
+            Mailer::Secured(mailer, _) => mailer.noop(),
         }
+    }
 
-        let host     = account.host.as_ref().unwrap();
+    fn quit(&mut self) -> Result<Reply, Error> {
+        match self {
+            Mailer::Plain(mailer, _)   => mailer.quit(),
+            Mailer::Secured(mailer, _) => mailer.quit(),
+        }
+    }
 
-        let username = account.username.as_ref().unwrap();
+    fn close(&mut self) {
+        match self {
+            Mailer::Plain(mailer, _)   => mailer.close(),
+            Mailer::Secured(mailer, _) => mailer.close(),
+        }
+    }
+}
 
-        let port     = account.port.as_ref().unwrap();
+/// The connection that is kept open between the emails, along with when
+/// it was last used
+#[derive(Default)]
+struct Idle {
+    connection: Option<(Mailer, Instant)>,
+    stopped: bool,
+}
+
+/// The OAuth 2.0 tokens of the account, encrypted by the vault
+#[derive(Default)]
+struct Tokens {
+    access_token: Option<Vec<u8>>,
+    refresh_token: Option<Vec<u8>>,
+    expires_at: Option<Instant>,
+}
+
+/// Access tokens are refreshed this long before they actually expire
+const EXPIRY_MARGIN: Duration = Duration::from_secs(300);
+const TOKEN_ENDPOINT_TIMEOUT: Duration = Duration::from_secs(30);
+
+impl DefaultClient {
+    fn open_connection<R: Raven>(&self) -> Result<(R, Capabilities), Error> {
+        let account = &self.account;
+
+        let label    = &account.label.to_string();
+
+        let host     = account.host.as_ref().ok_or_else(||
+            Error::Config(format!("Please configure the host for {}", label)))?;
+
+        let port     = account.port.ok_or_else(||
+            Error::Config(format!("Please configure the port for {}", label)))?;
 
         let cert_root = account.cert_root.as_ref().map(|c| c.to_owned());
 
         let timeout = account.timeout;
 
-        let mailer = R::create_connection(&host, *port, timeout, cert_root);
+        let mut mailer = R::create_connection(host, port, timeout, cert_root)?;
 
-        let mut mailer = match mailer {
-            Ok(mailer) => mailer,
-            Err(error) => {
-                error!("{}", error);
-                return None;
-            }
-        };
+        let capabilities = mailer.hand_shake(host)?;
+        Ok((mailer, capabilities))
+    }
 
-        match mailer.hand_shake(host) {
-            Ok(auths) => {
-                if auths.contains(&Authentication::Login) {
-                    if let Err(error) = mailer.authenticate_with_login(&username.as_ref(),
-                        &vault.decrypt(passwd).into_bytes()) {
-                        error!("{}", error);
-                        return None;
-                    };
+    fn authenticate<R: Raven>(&self, mailer: &mut R, capabilities: &Capabilities,
+                              vault: &Vault, passwd: &[u8]) -> Result<(), Error> {
+        let account = &self.account;
+        let username = account.username.as_ref().ok_or_else(||
+            Error::Config(format!("Please configure the username for {}", account.label)))?;
+        let auths = &capabilities.auths;
+        match account.auth {
+            AuthMethod::Login if auths == &[Authentication::None] => return Ok(()),
+            AuthMethod::Login => {
+                let preferred = self.password_mechanisms();
+                match negotiate(auths, &preferred) {
+                    Some(auth) => {
+                        debug!("Authenticating {} with {:?}", account.label, auth);
+                        mailer.authenticate_with_password(auth, username,
+                            vault.decrypt(passwd)?.as_bytes())
+                    },
+                    None       => Err(Error::Auth(format!(
+                        "The server of {} supports none of the mechanisms {:?}",
+                        account.label, preferred))),
                 }
-                Some(mailer)
             },
-            Err(error) => {
-                error!("{}", error);
-                None
-            }
+            AuthMethod::XOAuth2 if auths.contains(&Authentication::XAuth2) =>
+                mailer.authenticate_with_xoauth2(username, &vault.decrypt(passwd)?),
+            AuthMethod::OAuthBearer if auths.contains(&Authentication::OAuthBearer) => {
+                let host = account.host.as_ref().unwrap();
+                let port = account.port.unwrap();
+                mailer.authenticate_with_oauthbearer(username, host, port,
+                    &vault.decrypt(passwd)?)
+            },
+            auth => Err(Error::Auth(format!("The server of {} does not support {:?}",
+                                            account.label, auth))),
+        }.map(|_| ())
+    }
+
+    /// The password mechanisms that can be used, in the order of preference
+    pub(crate) fn password_mechanisms(&self) -> Vec<Authentication> {
+        match &self.account.auth_mechanisms {
+            Some(mechanisms) => mechanisms.clone(),
+            None             => Authentication::PASSWORD_MECHANISMS.to_vec(),
         }
     }
 
-    fn send_email<R: Raven>(&self, stream: &mut UnixStream, vault: &Vault) {
-            //where I = Incoming + Write + Read {
+    fn get_mailer(&self, vault: &Vault, passwd: &[u8]) -> Result<Mailer, Error> {
         let account = &self.account;
-        let password = account.password.as_ref().unwrap();
-        let label = &account.label;
-        let mailer = self.get_mailer::<R>(vault, &password);
-        if mailer.is_none() {
-            error!("Cannot open a connection for account {}", label);
-            let _ = stream.write_all(ERROR_SIGNAL.as_bytes());
-            return;
+
+        if account.tls.unwrap_or(false) {
+            let (mut mailer, capabilities) = self.open_connection::<BufTlsStream>()?;
+            self.authenticate(&mut mailer, &capabilities, vault, passwd)?;
+            return Ok(Mailer::Secured(mailer, capabilities));
+        }
+
+        let (mut mailer, capabilities) = self.open_connection::<BufTcpStream>()?;
+        let host = account.host.as_ref().unwrap();
+
+        if capabilities.starttls {
+            let cert_root = account.cert_root.as_ref().map(|c| c.to_owned());
+            let mut mailer = mailer.start_tls(host, cert_root)?;
+
+            debug!("Shaking hands with the server again, but this time over TLS");
+            let capabilities = mailer.ehlo(host)?;
+
+            self.authenticate(&mut mailer, &capabilities, vault, passwd)?;
+            Ok(Mailer::Secured(mailer, capabilities))
+        } else if account.require_starttls {
+            Err(Error::Tls(format!(
+                "{} does not support STARTTLS, refusing to send over a plain connection", host)))
+        } else {
+            self.authenticate(&mut mailer, &capabilities, vault, passwd)?;
+            Ok(Mailer::Plain(mailer, capabilities))
+        }
+    }
+
+    pub(crate) fn refresh_access_token(&self, vault: &Vault) -> Result<(), Error> {
+        let account = &self.account;
+        let settings = match &account.oauth2 {
+            Some(settings) => settings,
+            None           => return Ok(()),
+        };
+
+        info!("Refreshing the access token of {}", account.label);
+        let mut tokens = self.tokens.lock().unwrap();
+
+        let refresh_token = match &tokens.refresh_token {
+            Some(refresh_token) => vault.decrypt(refresh_token)?,
+            None                => evaluate(&settings.refresh_token_eval)?,
+        };
+
+        let client_secret = match &settings.client_secret_eval {
+            Some(eval) => Some(evaluate(eval)?),
+            None       => None,
+        };
+
+        let token = oauth2::refresh(settings, &refresh_token,
+                                    client_secret.as_ref().map(|s| s.as_ref()),
+                                    TOKEN_ENDPOINT_TIMEOUT)?;
+
+        tokens.access_token = Some(vault.encrypt(&token.access_token)?);
+        tokens.expires_at = token.expires_in
+            .map(|seconds| Instant::now() + Duration::from_secs(seconds));
+        if let Some(refresh_token) = token.refresh_token {
+            tokens.refresh_token = Some(vault.encrypt(&refresh_token)?);
+        }
+        Ok(())
+    }
+
+    /// The encrypted password, or the access token, refreshing it first if
+    /// it has expired. An expired token is still tried when it cannot be
+    /// refreshed.
+    pub(crate) fn password(&self, vault: &Vault) -> Result<Vec<u8>, Error> {
+        let account = &self.account;
+        if account.oauth2.is_none() {
+            return account.password.clone().ok_or_else(||
+                Error::Config(format!("Password is not defined for {}", account.label)));
+        }
+
+        let expired = {
+            let tokens = self.tokens.lock().unwrap();
+            tokens.access_token.is_none() || tokens.expires_at
+                .map(|expires_at| expires_at <= Instant::now() + EXPIRY_MARGIN)
+                .unwrap_or(false)
+        };
+
+        let refreshed = if expired { self.refresh_access_token(vault) } else { Ok(()) };
+        if let Err(error) = &refreshed {
+            error!("Cannot refresh the access token of {}: {}", account.label, error);
+        }
+
+        let tokens = self.tokens.lock().unwrap();
+        match tokens.access_token.clone().or_else(|| account.password.clone()) {
+            Some(password) => Ok(password),
+            None           => Err(refreshed.err().unwrap_or_else(||
+                Error::Auth(format!("There is no access token for {}", account.label)))),
+        }
+    }
+
+    fn connect(&self, vault: &Vault) -> Result<Mailer, Error> {
+        let account = &self.account;
+        let password = self.password(vault)?;
+        match self.get_mailer(vault, &password) {
+            Err(Error::Auth(error)) if account.oauth2.is_some() => {
+                // The access token might have been revoked or expired early
+                info!("Refreshing the access token of {} again: {}", account.label, error);
+                self.refresh_access_token(vault)?;
+                let password = self.password(vault)?;
+                self.get_mailer(vault, &password)
+            },
+            result                                              => result,
         }
+    }
+
+    fn send_email(&self, mailer: &mut Mailer, mail: Mail) -> Result<Response, Error> {
+        let username = self.account.username.as_ref().unwrap();
+
+        let recipients: Vec<&str> = mail.recipients.iter()
+            .filter(|&s| s != "--").map(|s| s.deref()).collect();
+        let dsn = Dsn {
+            notify: mail.dsn_notify,
+            ret: mail.dsn_ret,
+            envid: mail.envid,
+        };
+        let body = mail.body;
 
-        let mut mailer = mailer.unwrap();
+        mailer.send_mail(username, &recipients, &dsn, &body)
+            .map(|delivery| delivery_response(&delivery))
+    }
 
-        if account.username.is_none() {
-            error!("Please configure the username for {}", label);
-            let _ = stream.write_all(ERROR_SIGNAL.as_bytes());
+    /// Takes the idle connection if the server still answers on it,
+    /// otherwise opens a new one
+    fn checkout(&self, vault: &Vault) -> Result<Mailer, Error> {
+        let label = &self.account.label;
+        let idle = self.idle.lock().unwrap().connection.take();
+        if let Some((mut mailer, _)) = idle {
+            match mailer.noop() {
+                Ok(_)      => {
+                    debug!("Reusing the connection of {}", label);
+                    return Ok(mailer);
+                },
+                Err(error) => {
+                    info!("Reconnecting {}: {}", label, error);
+                    mailer.close();
+                },
+            }
+        }
+        self.connect(vault)
+    }
+
+    /// Keeps the connection open for the next email, unless it is not
+    /// wanted or the server cannot start a new transaction on it
+    fn release(&self, mut mailer: Mailer) {
+        let label = &self.account.label;
+        if self.account.idle_timeout == Duration::from_secs(0) {
+            if let Err(error) = mailer.quit() {
+                debug!("{}: {}", label, error);
+            }
             return;
         }
 
-        let username = &account.username.as_ref().unwrap();
-        let mut mail = Vec::new();
+        if let Err(error) = mailer.reset() {
+            info!("Closing the connection of {}: {}", label, error);
+            mailer.close();
+            return;
+        }
 
-        if let Err(e) = stream.read_to_end(&mut mail) {
-            error!("Error happened while reading the incoming email {}", e);
-            let _ = stream.write_all(ERROR_SIGNAL.as_bytes());
+        // Only one connection is kept, the others were opened for the
+        // clients that came while it was in use
+        let replaced = self.idle.lock().unwrap().connection.replace((mailer, Instant::now()));
+        self.idle_changed.notify_one();
+        if let Some((mut mailer, _)) = replaced {
+            if let Err(error) = mailer.quit() {
+                debug!("{}: {}", label, error);
+            }
         }
+    }
 
-        let mail = Mail::deserialize(&mut mail);
-        match mail {
-            Ok(mail)  => {
-                let recipients: Vec<&str> = mail.recipients.iter()
-                    .filter(|&s| s != "--").map(|s| s.deref()).collect();
-                let body = mail.body;
-                if let Err(error) = mailer.send_mail(&username, &recipients, &body) {
-                    error!("{}", error);
-                    let _ = stream.write_all(ERROR_SIGNAL.as_bytes());
-                } else {
-                    let _ = stream.write_all(OK_SIGNAL.as_bytes());
+    /// Ends the session on the idle connection once it has not been used
+    /// for the idle timeout, or once the client stops
+    fn close_idle_connection(&self) {
+        let idle_timeout = self.account.idle_timeout;
+        let mut idle = self.idle.lock().unwrap();
+        loop {
+            let expired = match &idle.connection {
+                Some((_, last_used)) => idle.stopped || last_used.elapsed() >= idle_timeout,
+                None                 => false,
+            };
+            if expired {
+                if let Some((mut mailer, _)) = idle.connection.take() {
+                    // The server may be slow to answer, so the emails are not
+                    // kept waiting for it meanwhile
+                    drop(idle);
+                    debug!("Closing the idle connection of {}", self.account.label);
+                    if let Err(error) = mailer.quit() {
+                        debug!("{}: {}", self.account.label, error);
+                    }
+                    idle = self.idle.lock().unwrap();
+                    continue;
                 }
-            },
-            Err(e) => {
-                error!("Error happened while reading the incoming email {}", e);
-                let _ = stream.write_all(ERROR_SIGNAL.as_bytes());
             }
+            if idle.stopped {
+                return;
+            }
+
+            let wait = match &idle.connection {
+                Some((_, last_used)) => idle_timeout - last_used.elapsed().min(idle_timeout),
+                None                 => idle_timeout,
+            };
+            idle = self.idle_changed.wait_timeout(idle, wait).unwrap().0;
+        }
+    }
+
+    fn deliver(&self, bytes: &mut Vec<u8>, vault: &Vault) -> Response {
+        let mail = match Mail::deserialize(bytes) {
+            Ok(mail) => mail,
+            Err(e)   => return Response::new(Status::PermanentFailure,
+                &format!("Error happened while reading the incoming email {}", e)),
+        };
+
+        let mut mailer = match self.checkout(vault) {
+            Ok(mailer) => mailer,
+            Err(error) => return failure_response(&error),
+        };
+
+        match self.send_email(&mut mailer, mail) {
+            Ok(response) => {
+                self.release(mailer);
+                response
+            },
+            Err(error)   => {
+                // The state of the session is unknown, so it is not reused
+                mailer.close();
+                failure_response(&error)
+            },
+        }
+    }
+
+    fn handle(&self, stream: &mut UnixStream, vault: &Vault) {
+        let mut bytes = Vec::new();
+        let read = stream.read_to_end(&mut bytes);
+        // Clients that predate the framed responses only understand the signals
+        let framed = Mail::expects_framed_response(&bytes);
+        let detailed = Mail::expects_detailed_signals(&bytes);
+        let response = match read {
+            Ok(_)  => self.deliver(&mut bytes, vault),
+            Err(e) => Response::new(Status::TransientFailure,
+                &format!("Error happened while reading the incoming email {}", e)),
+        };
+
+        match response.status {
+            Status::Sent => info!("{}", response),
+            _            => error!("{}", response),
+        }
+        if !framed && !detailed && response.status == Status::PartiallySent {
+            warn!("The client only understands OK and ERROR, and is not told of the rejected \
+                   recipients");
+        }
+
+        if framed {
+            let _ = stream.write_all(&response.serialize());
+        } else {
+            let _ = stream.write_all(response.legacy_signal(detailed).as_bytes());
         }
-        mailer.close();
     }
 
     pub fn start(&self, prefix: &str, vault: &Vault) {
@@ -122,36 +417,94 @@ impl DefaultClient {
 
         let label = &account.label;
 
-        let tls = &account.tls.unwrap_or(false);
-        if account.password.is_none() {
+        if account.password.is_none() && account.oauth2.is_none() {
             error!("Password is not defined for {}", &label);
             return;
         }
 
-        if let Ok(listener) = UnixListener::bind(get_socket_path(prefix, &label)) {
-            for stream in listener.incoming() {
-                match stream {
-                    Ok(mut stream) => {
-                        if *tls {
-                            self.send_email::<TlsStream<TcpStream>>(&mut stream, vault);
-                        } else {
-                            self.send_email::<TcpStream>(&mut stream, vault);
-                        }
-                    }
-                    _                 => {
-                        /* connection failed */
-                        break;
-                    }
-                }
+        if account.oauth2.is_some() {
+            if let Err(error) = self.refresh_access_token(vault) {
+                error!("Cannot refresh the access token of {}: {}", label, error);
             }
+        }
+
+        if let Ok(listener) = UnixListener::bind(get_socket_path(prefix, label)) {
+            thread::scope(|scope| {
+                if account.idle_timeout > Duration::from_secs(0) {
+                    scope.spawn(|| self.close_idle_connection());
+                }
+
+                serve(&listener, account.max_concurrent_connections, label, |mut stream| {
+                    self.handle(&mut stream, vault);
+                });
+
+                self.idle.lock().unwrap().stopped = true;
+                self.idle_changed.notify_one();
+            });
         } else {
             panic!("failed to open a socket")
         }
     }
 
     pub fn new(account: Account) -> Self {
-        DefaultClient { account }
+        DefaultClient {
+            account,
+            tokens: Mutex::new(Tokens::default()),
+            idle: Mutex::new(Idle::default()),
+            idle_changed: Condvar::new(),
+        }
     }
 }
 
+/// The response to the client, after the SMTP transaction
+pub(crate) fn delivery_response(delivery: &Delivery) -> Response {
+    let recipients: Vec<RecipientStatus> = delivery.recipients.iter()
+        .map(|recipient| RecipientStatus {
+            recipient: recipient.recipient.clone(),
+            code: recipient.reply.code,
+            enhanced_status: recipient.reply.enhanced_status.map(|s| s.to_string()),
+            message: recipient.reply.message(),
+        })
+        .collect();
 
+    match &delivery.reply {
+        Some(reply) => {
+            let rejected = recipients.iter().any(|recipient| !recipient.is_accepted());
+            Response {
+                status: if rejected { Status::PartiallySent } else { Status::Sent },
+                code: Some(reply.code),
+                enhanced_status: reply.enhanced_status.map(|s| s.to_string()),
+                message: reply.message(),
+                recipients,
+                queue_id: reply.queue_id(),
+            }
+        },
+        None        => {
+            let transient = recipients.iter().any(|recipient| recipient.is_transient());
+            let status = if transient { Status::TransientFailure } else { Status::PermanentFailure };
+            Response {
+                recipients,
+                ..Response::new(status, "Every recipient was rejected")
+            }
+        },
+    }
+}
+
+/// The response to the client, when the email cannot be sent at all. Only
+/// the failures that may go away on their own are retried.
+pub(crate) fn failure_response(error: &Error) -> Response {
+    let status = if error.is_transient() {
+        Status::TransientFailure
+    } else {
+        Status::PermanentFailure
+    };
+    let response = Response::new(status, &error.to_string());
+    match error {
+        Error::SmtpReply { code, enhanced_status, .. } => Response {
+            code: Some(*code),
+            enhanced_status: enhanced_status.map(|s| s.to_string()),
+            ..response
+        },
+        _                                              => response,
+    }
+}
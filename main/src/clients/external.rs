@@ -2,10 +2,10 @@ use common::{OK_SIGNAL,ERROR_SIGNAL,get_socket_path};
 use common::mail::Mail;
 use common::vault::Vault;
 use std::os::unix::net::{UnixStream, UnixListener};
+use crate::clients::pool::serve;
 use std::process::{Command, Stdio};
 use std::str;
 use std::io::{Read, Write};
-use std::error::Error;
 
 pub struct ExternalClient {
     pub client: String,
@@ -53,7 +53,7 @@ impl ExternalClient {
                     },
                     Some(Err(why)) => {
                         let _ = stream.write_all(ERROR_SIGNAL.as_bytes());
-                        error!("Couldn't write to smtp stdin: {}", why.description());
+                        error!("Couldn't write to smtp stdin: {}", why);
                     },
                     _             => {
                         let _ = stream.write_all(ERROR_SIGNAL.as_bytes());
@@ -68,20 +68,18 @@ impl ExternalClient {
         }
     }
 
-    pub fn start(&self, label: &str, prefix: &str, vault: &Vault, passwd: &[u8]) {
-        if let Ok(listener) = UnixListener::bind(get_socket_path(prefix, &label)) {
-            for stream in listener.incoming() {
-                match stream {
-                    Ok(stream) => {
-                      let decrypted = vault.decrypt(passwd);
-                      self.send_mail(stream, &decrypted.into_bytes());
-                    }
-                    _              => {
-                        /* connection failed */
-                        break;
-                    }
+    pub fn start(&self, label: &str, prefix: &str, max_concurrent_connections: usize,
+                 vault: &Vault, passwd: &[u8]) {
+        if let Ok(listener) = UnixListener::bind(get_socket_path(prefix, label)) {
+            serve(&listener, max_concurrent_connections, label, |mut stream| {
+                match vault.decrypt(passwd) {
+                    Ok(decrypted) => self.send_mail(stream, decrypted.as_bytes()),
+                    Err(error)    => {
+                        error!("{}", error);
+                        let _ = stream.write_all(ERROR_SIGNAL.as_bytes());
+                    },
                 }
-            }
+            });
         } else {
             error!("failed to open a socket")
         }
@@ -0,0 +1,227 @@
+use common::mail::Mail;
+use common::response::{Response, Status};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::sync_channel;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a turned away client has to finish sending its email
+pub(crate) const TURN_AWAY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The sockets that can time out their reads
+pub(crate) trait ReadTimeout: Read {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl ReadTimeout for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl ReadTimeout for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// Reads the socket until the deadline, however slowly the client sends,
+/// as the read timeout alone starts over with every read
+pub(crate) struct Deadline<S> {
+    stream: S,
+    deadline: Instant,
+}
+
+impl<S: ReadTimeout> Deadline<S> {
+    pub(crate) fn new(stream: S, timeout: Duration) -> Self {
+        Deadline {
+            stream,
+            deadline: Instant::now() + timeout,
+        }
+    }
+}
+
+impl<S: ReadTimeout> Read for Deadline<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(ErrorKind::TimedOut, "The deadline has passed"));
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+/// Serves the connections of the listener on at most `size` threads. The
+/// connections that arrive while every thread is busy are answered right
+/// away with a busy response, instead of waiting for a slow server.
+pub fn serve<H>(listener: &UnixListener, size: usize, label: &str, handle: H)
+        where H: Fn(UnixStream) + Sync {
+    // A failed connection stops the listener
+    serve_incoming(listener.incoming().map_while(Result::ok), size, label, handle,
+                   |stream| turn_away(stream, label));
+}
+
+/// Serves the connections on at most `size` threads, like serve, and hands
+/// the ones that arrive while every thread is busy to `turn_away`, on at
+/// most `size` threads of its own so that accepting never waits for it. The
+/// connections beyond them are closed.
+pub fn serve_incoming<S, I, H, T>(incoming: I, size: usize, label: &str, handle: H, turn_away: T)
+        where S: Send, I: Iterator<Item = S>, H: Fn(S) + Sync, T: Fn(S) + Sync {
+    let (sender, receiver) = sync_channel::<S>(size);
+    let receiver = Mutex::new(receiver);
+    // Only this thread counts up, so checking and counting cannot race
+    let busy = AtomicUsize::new(0);
+    let turning_away = AtomicUsize::new(0);
+
+    thread::scope(|scope| {
+        for _ in 0..size {
+            scope.spawn(|| loop {
+                let stream = receiver.lock().unwrap().recv();
+                match stream {
+                    Ok(stream) => {
+                        handle(stream);
+                        busy.fetch_sub(1, Ordering::SeqCst);
+                    },
+                    Err(_)     => return,
+                }
+            });
+        }
+
+        for stream in incoming {
+            if busy.load(Ordering::SeqCst) < size {
+                busy.fetch_add(1, Ordering::SeqCst);
+                let _ = sender.send(stream);
+            } else if turning_away.load(Ordering::SeqCst) < size {
+                warn!("Every connection of {} is busy, turning a client away", label);
+                turning_away.fetch_add(1, Ordering::SeqCst);
+                let (turn_away, turning_away) = (&turn_away, &turning_away);
+                scope.spawn(move || {
+                    turn_away(stream);
+                    turning_away.fetch_sub(1, Ordering::SeqCst);
+                });
+            } else {
+                warn!("Every connection of {} is busy, closing a client", label);
+            }
+        }
+
+        drop(sender);
+    });
+}
+
+/// Tells the client that the daemon is busy. The email is read first, so
+/// that the client is not cut off while it is still sending it.
+fn turn_away(mut stream: UnixStream, label: &str) {
+    let mut bytes = Vec::new();
+    if let Ok(reader) = stream.try_clone() {
+        let _ = Deadline::new(reader, TURN_AWAY_TIMEOUT).read_to_end(&mut bytes);
+    }
+    let _ = stream.write_all(&busy_response(&bytes, label));
+}
+
+/// The busy response, in the form that the client of the email understands
+pub(crate) fn busy_response(email: &[u8], label: &str) -> Vec<u8> {
+    let response = Response::new(Status::Busy,
+        &format!("The daemon of {} is busy, please try again later", label));
+    if Mail::expects_framed_response(email) {
+        response.serialize()
+    } else {
+        response.legacy_signal(Mail::expects_detailed_signals(email)).into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Shutdown;
+    use std::sync::{Arc, Barrier};
+
+    fn request(stream: &mut UnixStream) -> Vec<u8> {
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_saturated_pool_turns_clients_away() {
+        let path = std::env::temp_dir()
+            .join(format!("rusmtp-pool-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let release = Arc::new(Barrier::new(2));
+        let worker_release = release.clone();
+        thread::spawn(move || {
+            serve(&listener, 1, "first", |mut stream| {
+                worker_release.wait();
+                let _ = stream.write_all(b"OK");
+            });
+        });
+
+        let mut first = UnixStream::connect(&path).unwrap();
+        let mut second = UnixStream::connect(&path).unwrap();
+        assert_eq!(b"ERROR".to_vec(), request(&mut second));
+
+        release.wait();
+        assert_eq!(b"OK".to_vec(), request(&mut first));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_silent_client_does_not_hold_up_the_others() {
+        let path = std::env::temp_dir()
+            .join(format!("rusmtp-pool-silent-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let release = Arc::new(Barrier::new(3));
+        let worker_release = release.clone();
+        thread::spawn(move || {
+            serve(&listener, 2, "first", |mut stream| {
+                worker_release.wait();
+                let _ = stream.write_all(b"OK");
+            });
+        });
+
+        let started = Instant::now();
+        let mut first = UnixStream::connect(&path).unwrap();
+        let mut second = UnixStream::connect(&path).unwrap();
+        let _silent = UnixStream::connect(&path).unwrap();
+        let mut fourth = UnixStream::connect(&path).unwrap();
+        assert_eq!(b"ERROR".to_vec(), request(&mut fourth));
+        assert!(started.elapsed() < TURN_AWAY_TIMEOUT);
+
+        release.wait();
+        assert_eq!(b"OK".to_vec(), request(&mut first));
+        assert_eq!(b"OK".to_vec(), request(&mut second));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_deadline() {
+        let (reader, mut writer) = UnixStream::pair().unwrap();
+        let sender = thread::spawn(move || {
+            // Every byte arrives before the read timeout, but not before
+            // the deadline
+            for _ in 0..10 {
+                if writer.write_all(b"x").is_err() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+        });
+        let mut bytes = Vec::new();
+        let started = Instant::now();
+        let read = Deadline::new(reader, Duration::from_millis(300)).read_to_end(&mut bytes);
+        // Either the deadline, or the read timeout that runs up to it
+        assert!(read.is_err());
+        assert!(started.elapsed() < Duration::from_millis(600));
+        assert!(!bytes.is_empty() && bytes.len() < 10);
+        sender.join().unwrap();
+    }
+}
@@ -0,0 +1,209 @@
+// Hands the emails of the SMTP and HTTP listeners to the daemon of their
+// account, and spools what may still be sent later, like
+// rusmtpc --with-retry does.
+
+use client::{send_to_daemon, to_retry, SendError};
+use common::{Error, get_lock_path};
+use common::mail::Mail;
+use common::response::{Response, Status};
+use common::spool::{Metadata, Spool};
+use fs2::FileExt;
+use std::fs::File;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// An account that a listener sends emails with
+pub struct Route {
+    pub label: String,
+    pub username: Option<String>,
+    pub default: bool,
+}
+
+/// The accounts that a listener sends emails with
+pub struct Routes {
+    routes: Vec<Route>,
+}
+
+impl Routes {
+    pub fn new(routes: Vec<Route>) -> Self {
+        Routes {
+            routes,
+        }
+    }
+
+    /// The account that a client names, either by its label or by its
+    /// username
+    pub fn of_user(&self, user: &str) -> Option<&str> {
+        self.routes.iter()
+            .find(|route| route.label == user || route.username.as_deref() == Some(user))
+            .map(|route| route.label.as_str())
+    }
+
+    /// The account of the sender, when the client does not name one. The
+    /// listener of an account always sends with it, and the others look for
+    /// the account of the address, falling back to the default account.
+    pub fn of_sender(&self, sender: &str) -> Option<&str> {
+        if let [route] = self.routes.as_slice() {
+            return Some(&route.label);
+        }
+        self.routes.iter()
+            .find(|route| route.username.as_ref()
+                  .is_some_and(|username| username.eq_ignore_ascii_case(sender)))
+            .or_else(|| self.routes.iter().find(|route| route.default))
+            .map(|route| route.label.as_str())
+    }
+}
+
+/// What became of an email that was handed over
+#[derive(Debug)]
+pub enum Delivery {
+    /// The daemon sent the email to every recipient
+    Sent(Response),
+    /// The email was not sent to every recipient, and the ones that may
+    /// still accept it are spooled, under the id
+    Queued { id: String, error: SendError },
+    /// The email was not sent to every recipient, and retrying does not help
+    Failed(SendError),
+    /// The email was not sent, and it cannot be spooled either
+    Unspooled(Error),
+}
+
+/// Where the emails are handed over, i.e. the paths of the rusmtprc
+#[derive(Clone)]
+pub struct Handoff {
+    pub socket_root: String,
+    pub flock_root: String,
+    pub spool_root: String,
+    pub timeout: Duration,
+}
+
+impl Handoff {
+    /// Sends the email through the daemon of its account, or spools it when
+    /// it may still be sent later
+    pub fn deliver(&self, mail: &Mail) -> Delivery {
+        let account = mail.account.clone().unwrap_or_default();
+        let flock_path = get_lock_path(&self.flock_root, &account);
+        if !Path::new(&flock_path).exists() {
+            let _ = File::create(&flock_path);
+        }
+        let lock_file = match File::open(&flock_path) {
+            Ok(lock_file) => lock_file,
+            Err(error)    => {
+                let error = Error::Io(format!("Cannot open flock {}: {}", flock_path, error));
+                error!("{}", error);
+                return Delivery::Unspooled(error);
+            },
+        };
+        // Shared with rusmtpc, and not with the resender
+        let ten_millis = Duration::from_millis(10);
+        while FileExt::lock_shared(&lock_file).is_err() {
+            thread::sleep(ten_millis);
+        }
+
+        let delivery = match send_to_daemon(mail, &self.socket_root, self.timeout, &account) {
+            Ok(response) => Delivery::Sent(response),
+            Err(error)   => self.after_failure(mail, &account, error),
+        };
+        let _ = FileExt::unlock(&lock_file);
+        delivery
+    }
+
+    /// Spools what may still be sent of an email that was not sent to every
+    /// recipient
+    fn after_failure(&self, mail: &Mail, account: &str, error: SendError) -> Delivery {
+        match to_retry(mail, &error) {
+            Some(retry) => {
+                let metadata = Metadata::failed(&error.to_string(), error.code());
+                match Spool::new(&self.spool_root).enqueue(&retry, account, &metadata) {
+                    Ok(id)     => {
+                        warn!("An email of {} is queued for retry as {}: {}", account, id, error);
+                        Delivery::Queued { id, error }
+                    },
+                    Err(spool) => {
+                        let error = Error::Io(format!("{}; {}", error, spool));
+                        error!("{}", error);
+                        Delivery::Unspooled(error)
+                    },
+                }
+            },
+            None        => {
+                match &error {
+                    SendError::Failed(response) if response.status == Status::PartiallySent =>
+                        error!("An email of {} is not sent to every recipient: {}",
+                               account, error),
+                    _                                                                        =>
+                        error!("An email of {} is not sent: {}", account, error),
+                }
+                Delivery::Failed(error)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::spool::Queue;
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::process;
+
+    fn route(label: &str, username: &str, default: bool) -> Route {
+        Route {
+            label: label.to_string(),
+            username: Some(username.to_string()),
+            default,
+        }
+    }
+
+    #[test]
+    fn test_routes() {
+        let single = Routes::new(vec![route("first", "me@example.com", false)]);
+        assert_eq!(Some("first"), single.of_sender("anyone@example.com"));
+        assert_eq!(None, single.of_user("anyone@example.com"));
+
+        let shared = Routes::new(vec![route("first", "me@example.com", false),
+                                      route("second", "you@example.com", true)]);
+        assert_eq!(Some("first"), shared.of_sender("ME@example.com"));
+        assert_eq!(Some("second"), shared.of_sender("anyone@example.com"));
+        assert_eq!(Some("first"), shared.of_user("first"));
+        assert_eq!(Some("second"), shared.of_user("you@example.com"));
+
+        let no_default = Routes::new(vec![route("first", "me@example.com", false),
+                                          route("second", "you@example.com", false)]);
+        assert_eq!(None, no_default.of_sender("anyone@example.com"));
+    }
+
+    #[test]
+    fn test_unsent_emails_are_spooled() {
+        let root = temp_dir().join(format!("rusmtp-handoff-{}", process::id()));
+        let _ = remove_dir_all(&root);
+        create_dir_all(&root).unwrap();
+        let root = root.display().to_string();
+        let handoff = Handoff {
+            socket_root: root.clone(),
+            flock_root: root.clone(),
+            spool_root: root.clone(),
+            timeout: Duration::from_secs(1),
+        };
+
+        let mail = Mail {
+            account: Some("first".to_string()),
+            recipients: vec!["a@b.c".to_string()],
+            dsn_notify: None,
+            dsn_ret: None,
+            envid: None,
+            body: b"hi".to_vec(),
+        };
+        // The daemon is not running
+        let id = match handoff.deliver(&mail) {
+            Delivery::Queued { id, error: SendError::Unavailable(_) } => id,
+            delivery                                                => panic!("{:?}", delivery),
+        };
+        let spool = Spool::new(&root);
+        assert_eq!((vec![id.clone()], vec![]), spool.ids(Queue::New).unwrap());
+        assert_eq!(1, spool.metadata(Queue::New, &id).unwrap().unwrap().attempts);
+        let _ = remove_dir_all(&root);
+    }
+}
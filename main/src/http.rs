@@ -0,0 +1,528 @@
+// Accepts emails as JSON over HTTP on a loopback address, for the services
+// that would rather POST them than speak SMTP or reach the sockets, and hands
+// them to the daemon of their account like the SMTP listener does.
+
+use client::{MailBuilder, SendError};
+use common::mail::Mail;
+use common::message::{address, Message};
+use common::response::Status;
+use crate::clients::pool::{Deadline, TURN_AWAY_TIMEOUT, serve_incoming};
+use crate::handoff::{Delivery, Handoff, Routes};
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How long a client may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+/// The largest request line or header
+const MAX_LINE_LENGTH: u64 = 8 * 1024;
+/// The largest body of a request
+const MAX_BODY_LENGTH: usize = 32 * 1024 * 1024;
+/// How many requests are served at once, the clients beyond them are told
+/// to try again later
+const MAX_REQUESTS: usize = 16;
+
+/// An email, either composed from its parts or raw
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Submission {
+    /// The label or the username of the account, the account of the sender
+    /// otherwise
+    account: Option<String>,
+    from: Option<String>,
+    #[serde(default)]
+    to: Vec<String>,
+    #[serde(default)]
+    cc: Vec<String>,
+    #[serde(default)]
+    bcc: Vec<String>,
+    subject: Option<String>,
+    text: Option<String>,
+    html: Option<String>,
+    /// The whole RFC 5322 message, with the headers
+    raw: Option<String>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    host: Option<String>,
+    content_type: Option<String>,
+    /// Whether a browser sent the request, on behalf of a web page
+    origin: bool,
+    body: Vec<u8>,
+}
+
+pub struct HttpListener {
+    routes: Routes,
+    handoff: Handoff,
+}
+
+impl HttpListener {
+    pub fn new(routes: Routes, handoff: Handoff) -> Self {
+        HttpListener {
+            routes,
+            handoff,
+        }
+    }
+
+    /// Accepts the connections of the address, serving at most MAX_REQUESTS
+    /// of them at once
+    pub fn start(self, addr: SocketAddr) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let tcp_listener = match TcpListener::bind(addr) {
+                Ok(tcp_listener) => tcp_listener,
+                Err(error)       => {
+                    error!("Cannot accept emails over HTTP on {}: {}", addr, error);
+                    return;
+                },
+            };
+            info!("Accepting emails over HTTP on {}", addr);
+
+            let incoming = tcp_listener.incoming().filter_map(|stream| match stream {
+                Ok(stream) => Some(stream),
+                Err(error) => {
+                    warn!("Cannot accept a connection on {}: {}", addr, error);
+                    None
+                },
+            });
+            let label = format!("the HTTP listener on {}", addr);
+            serve_incoming(incoming, MAX_REQUESTS, &label, |stream| {
+                if let Err(error) = self.serve_stream(stream) {
+                    debug!("HTTP request on {} failed: {}", addr, error);
+                }
+            }, turn_away);
+        })
+    }
+
+    /// Serves a single request, the connection is closed after it
+    fn serve_stream(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let local_addr = stream.local_addr()?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let (status, body) = match read_request(&mut reader) {
+            Ok(request)          =>
+                self.handle(&request, local_addr, |mail| self.handoff.deliver(&mail)),
+            Err((status, error)) => (status, json!({ "error": error })),
+        };
+        write_response(&mut stream, status, &body)
+    }
+
+    /// The status and the body of the response to the request. Loopback
+    /// does not keep the web pages that the user opens out, so the requests
+    /// of the browsers are refused: the ones that name another host, as DNS
+    /// rebinding does, the ones with an Origin, and the ones that are not
+    /// JSON, which the browsers would send without asking first.
+    fn handle<D>(&self, request: &Request, local_addr: SocketAddr, deliver: D) -> (u16, Value)
+            where D: FnOnce(Mail) -> Delivery {
+        if !is_local_host(request.host.as_deref(), local_addr) {
+            return (400, json!({ "error": format!("The Host should be {}", local_addr) }));
+        }
+        if request.origin {
+            return (403, json!({ "error": "The requests of web pages are not accepted" }));
+        }
+        match (request.method.as_str(), request.path.as_str()) {
+            ("POST", "/send") if !is_json(request.content_type.as_deref()) =>
+                (415, json!({ "error": "Please send the email as application/json" })),
+            ("POST", "/send")                                              =>
+                match self.mail(&request.body) {
+                    Ok(mail)   => delivery_json(deliver(mail)),
+                    Err(error) => (400, json!({ "error": error })),
+                },
+            (_, "/send")                                                   =>
+                (405, json!({ "error": "Only POST is allowed" })),
+            _                                                              =>
+                (404, json!({ "error": "Please POST the emails to /send" })),
+        }
+    }
+
+    /// The email of the submission, composed unless it is raw
+    fn mail(&self, body: &[u8]) -> Result<Mail, String> {
+        let Submission { account, from, to, cc, bcc, subject, text, html, raw } =
+            serde_json::from_slice(body).map_err(|e| format!("Invalid submission: {}", e))?;
+
+        let account = match (&account, &from) {
+            (Some(account), _) => self.routes.of_user(account)
+                .ok_or_else(|| format!("Unknown account {}", account))?,
+            (None, Some(from)) => self.routes.of_sender(address(from))
+                .ok_or_else(|| format!("No account sends emails from {}", from))?,
+            (None, None)       => self.routes.of_sender("")
+                .ok_or("Please pass an account or set a default account")?,
+        };
+
+        let recipients: Vec<String> = to.iter().chain(&cc).chain(&bcc)
+            .map(|recipient| address(recipient).to_string())
+            .collect();
+        let body = match raw {
+            Some(_) if subject.is_some() || text.is_some() || html.is_some() =>
+                return Err("raw cannot be combined with subject, text or html".to_string()),
+            Some(raw)                                                        => raw.into_bytes(),
+            None                                                             => Message {
+                from: from.ok_or("from is missing")?,
+                to,
+                cc,
+                subject,
+                text,
+                html,
+            }.compose().map_err(|e| e.to_string())?,
+        };
+
+        MailBuilder::default()
+            .account(account)
+            .recipients(recipients)
+            .body(body)
+            .build()
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// The status and the body of the response to an email that was handed over
+fn delivery_json(delivery: Delivery) -> (u16, Value) {
+    let response_json = |error: &SendError| match error {
+        SendError::Failed(response) => json!(response),
+        _                           => Value::Null,
+    };
+    match delivery {
+        Delivery::Sent(response)                      => (200, json!(response)),
+        Delivery::Queued { id, error }                => (202, json!({
+            "status": "queued",
+            "spool_id": id,
+            "error": error.to_string(),
+            "response": response_json(&error),
+        })),
+        Delivery::Failed(SendError::Failed(response)) => {
+            let status = if response.status == Status::PartiallySent { 200 } else { 422 };
+            (status, json!(response))
+        },
+        Delivery::Failed(error)                       =>
+            (502, json!({ "status": "failed", "error": error.to_string() })),
+        Delivery::Unspooled(error)                    =>
+            (503, json!({ "status": "failed", "error": error.to_string() })),
+    }
+}
+
+/// Whether the Host of the request is the address that it was sent to, or
+/// localhost with its port
+fn is_local_host(host: Option<&str>, local_addr: SocketAddr) -> bool {
+    match host {
+        Some(host) => host.parse::<SocketAddr>().ok() == Some(local_addr) ||
+            host.eq_ignore_ascii_case(&format!("localhost:{}", local_addr.port())),
+        None       => false,
+    }
+}
+
+fn is_json(content_type: Option<&str>) -> bool {
+    content_type
+        .and_then(|content_type| content_type.split(';').next())
+        .is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case("application/json"))
+}
+
+/// Reads the request, or the status and the error of a request that is
+/// not understood
+fn read_request<R: BufRead>(reader: &mut R) -> Result<Request, (u16, String)> {
+    let request_line = read_line(reader)?;
+    let mut parts = request_line.split(' ');
+    let (method, path) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version)) if version.starts_with("HTTP/1.") =>
+            (method.to_string(), path.to_string()),
+        _                                                                           =>
+            return Err((400, format!("Invalid request line: {}", request_line))),
+    };
+
+    let mut content_length = None;
+    let mut host = None;
+    let mut content_type = None;
+    let mut origin = false;
+    loop {
+        let header = read_line(reader)?;
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = match header.find(':') {
+            Some(index) => (header[..index].trim().to_lowercase(), header[index + 1..].trim()),
+            None        => return Err((400, format!("Invalid header: {}", header))),
+        };
+        match name.as_str() {
+            "content-length"    => content_length = Some(value.parse::<usize>()
+                .map_err(|_| (400, format!("Invalid Content-Length: {}", value)))?),
+            "transfer-encoding" =>
+                return Err((411, "Please send the Content-Length of the body".to_string())),
+            "host"              => host = Some(value.to_string()),
+            "content-type"      => content_type = Some(value.to_string()),
+            "origin"            => origin = true,
+            _                   => (),
+        }
+    }
+
+    let body = match content_length {
+        Some(length) if length > MAX_BODY_LENGTH =>
+            return Err((413, format!("The body is larger than {} bytes", MAX_BODY_LENGTH))),
+        Some(length)                             => {
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).map_err(|e| (400, e.to_string()))?;
+            body
+        },
+        None if method == "POST"                 =>
+            return Err((411, "Please send the Content-Length of the body".to_string())),
+        None                                     => Vec::new(),
+    };
+
+    Ok(Request {
+        method,
+        path,
+        host,
+        content_type,
+        origin,
+        body,
+    })
+}
+
+/// Reads a line of the request, without the line ending
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String, (u16, String)> {
+    let mut line = Vec::new();
+    reader.take(MAX_LINE_LENGTH).read_until(b'\n', &mut line)
+        .map_err(|e| (400, e.to_string()))?;
+    if !line.ends_with(b"\n") {
+        return Err((431, "The request line or a header is too long, or cut off".to_string()));
+    }
+    Ok(String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Tells the client that the listener is busy. The request is read first,
+/// so that the client is not cut off while it is still sending it.
+fn turn_away(mut stream: TcpStream) {
+    if let Ok(reader) = stream.try_clone() {
+        let _ = read_request(&mut BufReader::new(Deadline::new(reader, TURN_AWAY_TIMEOUT)));
+    }
+    let _ = write_response(&mut stream, 503,
+                           &json!({ "error": "The listener is busy, please try again later" }));
+}
+
+fn write_response<W: Write>(writer: &mut W, status: u16, body: &Value) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Entity",
+        431 => "Request Header Fields Too Large",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _   => "",
+    };
+    let body = body.to_string();
+    writer.write_all(format!("HTTP/1.1 {} {}\r\n\
+                              Content-Type: application/json\r\n\
+                              Content-Length: {}\r\n\
+                              Connection: close\r\n\r\n{}", status, reason, body.len(), body)
+                     .as_bytes())?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handoff::Route;
+    use common::Error;
+    use common::response::{RecipientStatus, Response};
+    use std::io::Cursor;
+
+    fn listener() -> HttpListener {
+        let route = |label: &str, username: &str, default: bool| Route {
+            label: label.to_string(),
+            username: Some(username.to_string()),
+            default,
+        };
+        HttpListener::new(Routes::new(vec![route("first", "me@example.com", false),
+                                           route("second", "you@example.com", true)]),
+                          Handoff {
+                              socket_root: String::new(),
+                              flock_root: String::new(),
+                              spool_root: String::new(),
+                              timeout: Duration::from_secs(1),
+                          })
+    }
+
+    const LOCAL_ADDR: &str = "127.0.0.1:8025";
+
+    fn post_with(headers: &str, body: &str) -> Request {
+        read_request(&mut Cursor::new(format!(
+            "POST /send HTTP/1.1\r\n{}content-length: {}\r\n\r\n{}",
+            headers, body.len(), body))).unwrap()
+    }
+
+    fn post(body: &str) -> Request {
+        post_with("Host: 127.0.0.1:8025\r\nContent-Type: application/json\r\n", body)
+    }
+
+    /// Handles the request, and returns the response along with the email
+    /// that was handed over
+    fn handle(request: &Request) -> ((u16, Value), Option<Mail>) {
+        let mut mail = None;
+        let response = listener().handle(request, LOCAL_ADDR.parse().unwrap(), |submitted| {
+            mail = Some(submitted);
+            Delivery::Sent(Response {
+                queue_id: Some("4AB3C2".to_string()),
+                ..Response::new(Status::Sent, "Email sent")
+            })
+        });
+        (response, mail)
+    }
+
+    #[test]
+    fn test_compose_and_send() {
+        let ((status, body), mail) = handle(&post(r#"{
+            "from": "Jenkins <me@example.com>",
+            "to": ["a@b.c"],
+            "cc": ["D <d@e.f>"],
+            "bcc": ["g@h.i"],
+            "subject": "Build #42 fixed",
+            "text": "All good"
+        }"#));
+        assert_eq!(200, status);
+        assert_eq!(json!("sent"), body["status"]);
+        assert_eq!(json!("4AB3C2"), body["queue_id"]);
+
+        let mail = mail.unwrap();
+        assert_eq!(Some("first".to_string()), mail.account);
+        assert_eq!(vec!["a@b.c".to_string(), "d@e.f".to_string(), "g@h.i".to_string()],
+                   mail.recipients);
+        let message = String::from_utf8(mail.body).unwrap();
+        assert!(message.contains("\r\nTo: a@b.c\r\nCc: D <d@e.f>\r\nSubject: Build #42 fixed\r\n"));
+        assert!(!message.contains("g@h.i"));
+    }
+
+    #[test]
+    fn test_send_raw() {
+        let (_, mail) = handle(&post(r#"{
+            "account": "you@example.com",
+            "to": ["a@b.c"],
+            "raw": "Subject: hi\r\n\r\nhi\r\n"
+        }"#));
+        let mail = mail.unwrap();
+        assert_eq!(Some("second".to_string()), mail.account);
+        assert_eq!(b"Subject: hi\r\n\r\nhi\r\n".to_vec(), mail.body);
+
+        // The default account
+        let (_, mail) = handle(&post(r#"{"to": ["a@b.c"], "raw": "hi"}"#));
+        assert_eq!(Some("second".to_string()), mail.unwrap().account);
+    }
+
+    #[test]
+    fn test_invalid_submissions() {
+        for submission in &[r#"{"to": ["a@b.c"], "text": "hi"}"#,
+                            r#"{"from": "me@example.com", "text": "hi"}"#,
+                            r#"{"account": "third", "to": ["a@b.c"], "raw": "hi"}"#,
+                            r#"{"to": ["a@b.c"], "raw": "hi", "subject": "hi"}"#,
+                            r#"{"to": ["a@b.c"], "raw": "hi", "reply-to": "a@b.c"}"#,
+                            r#"{"to": "a@b.c", "raw": "hi"}"#,
+                            r#"{"from": "me@example.com", "to": ["a@b.c"],
+                                "subject": "hi\nBcc: d@e.f"}"#] {
+            let ((status, body), mail) = handle(&post(submission));
+            assert_eq!(400, status, "{}", submission);
+            assert!(body["error"].is_string());
+            assert!(mail.is_none());
+        }
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        let request = |request: &str| read_request(&mut Cursor::new(request.to_string()))
+            .map(|_| ()).map_err(|(status, _)| status);
+        assert_eq!(Err(411), request("POST /send HTTP/1.1\r\n\r\n"));
+        assert_eq!(Err(411), request("POST /send HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"));
+        assert_eq!(Err(413), request("POST /send HTTP/1.1\r\nContent-Length: 999999999\r\n\r\n"));
+        assert_eq!(Err(400), request("POST /send HTTP/1.1\r\nContent-Length: 10\r\n\r\nhi"));
+        assert_eq!(Err(400), request("POST /send\r\n\r\n"));
+        assert_eq!(Err(431), request(&"a".repeat(10_000)));
+
+        let get = read_request(&mut Cursor::new("GET /send HTTP/1.1\r\nHost: localhost:8025\r\n\r\n"))
+            .unwrap();
+        assert_eq!(405, handle(&get).0 .0);
+        let other = read_request(&mut Cursor::new("GET / HTTP/1.1\r\nHost: 127.0.0.1:8025\r\n\r\n"))
+            .unwrap();
+        assert_eq!(404, handle(&other).0 .0);
+    }
+
+    #[test]
+    fn test_browser_requests_are_refused() {
+        let submission = r#"{"to": ["a@b.c"], "raw": "hi"}"#;
+        let json = "Content-Type: application/json\r\n";
+        for (headers, expected) in &[
+            // A simple request, which the browsers send without a preflight
+            ("Host: 127.0.0.1:8025\r\nContent-Type: text/plain\r\n", 415),
+            ("Host: 127.0.0.1:8025\r\n", 415),
+            ("Host: 127.0.0.1:8025\r\nContent-Type: application/json-seq\r\n", 415),
+            ("Host: 127.0.0.1:8025\r\nOrigin: https://example.com\r\n", 403),
+            ("Host: 127.0.0.1:8025\r\nOrigin: null\r\n", 403),
+            // DNS rebinding
+            ("Host: attacker.example.com:8025\r\n", 400),
+            ("Host: 127.0.0.1:8026\r\n", 400),
+            ("Host: localhost\r\n", 400),
+            ("", 400),
+        ] {
+            let headers = if *expected == 415 {
+                headers.to_string()
+            } else {
+                format!("{}{}", headers, json)
+            };
+            let ((status, body), mail) = handle(&post_with(&headers, submission));
+            assert_eq!(*expected, status, "{}", headers);
+            assert!(body["error"].is_string());
+            assert!(mail.is_none());
+        }
+
+        let ((status, _), mail) = handle(&post_with(
+            "Host: LOCALHOST:8025\r\nContent-Type: Application/JSON; charset=utf-8\r\n",
+            submission));
+        assert_eq!(200, status);
+        assert!(mail.is_some());
+    }
+
+    #[test]
+    fn test_delivery_json() {
+        let rejected = Response {
+            recipients: vec![RecipientStatus {
+                recipient: "a@b.c".to_string(),
+                code: 550,
+                enhanced_status: Some("5.1.1".to_string()),
+                message: "No such user".to_string(),
+            }],
+            ..Response::new(Status::PermanentFailure, "Every recipient was rejected")
+        };
+        let (status, body) = delivery_json(Delivery::Failed(SendError::Failed(rejected)));
+        assert_eq!(422, status);
+        assert_eq!(json!({
+            "status": "permanent-failure",
+            "code": null,
+            "enhanced_status": null,
+            "message": "Every recipient was rejected",
+            "recipients": [{
+                "recipient": "a@b.c",
+                "code": 550,
+                "enhanced_status": "5.1.1",
+                "message": "No such user",
+            }],
+            "queue_id": null,
+        }), body);
+
+        let (status, body) = delivery_json(Delivery::Queued {
+            id: "first-1-2".to_string(),
+            error: SendError::Unavailable(Error::Io("Connection refused".to_string())),
+        });
+        assert_eq!(202, status);
+        assert_eq!(json!("first-1-2"), body["spool_id"]);
+        assert_eq!(json!("The daemon is not running: Connection refused"), body["error"]);
+
+        let unspooled = Delivery::Unspooled(Error::Io("Cannot archive the email".to_string()));
+        assert_eq!(503, delivery_json(unspooled).0);
+    }
+}
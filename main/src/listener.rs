@@ -0,0 +1,700 @@
+// Accepts emails over SMTP on a loopback address, for the programs that can
+// only speak SMTP, and hands them to the daemon of their account the same
+// way rusmtpc does.
+
+use base64::decode;
+use client::SendError;
+use common::mail::Mail;
+use common::response::{RecipientStatus, Status};
+use common::spool::Metadata;
+use crate::bouncer::Bouncer;
+use crate::clients::pool::serve_incoming;
+use crate::handoff::{Delivery, Handoff, Routes};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How long a client may stay silent, RFC 5321 section 4.5.3.2.7
+const SESSION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// How many sessions are served at once, the clients beyond them are told
+/// to try again later
+const MAX_SESSIONS: usize = 16;
+
+/// The addresses are serialized after their length, in one byte
+const MAX_PATH_LENGTH: usize = 255;
+/// The longest command or text line, with its CRLF, RFC 5321 section
+/// 4.5.3.1.4 and 4.5.3.1.6
+const MAX_LINE_LENGTH: usize = 1000;
+/// The largest message, like the body of the HTTP listener
+const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+
+pub struct Listener {
+    routes: Routes,
+    handoff: Handoff,
+    bouncer: Option<Bouncer>,
+}
+
+impl Listener {
+    pub fn new(routes: Routes, handoff: Handoff, bouncer: Option<Bouncer>) -> Self {
+        Listener {
+            routes,
+            handoff,
+            bouncer,
+        }
+    }
+
+    /// Accepts the connections of the address, serving at most MAX_SESSIONS
+    /// of them at once
+    pub fn start(self, addr: SocketAddr) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let tcp_listener = match TcpListener::bind(addr) {
+                Ok(tcp_listener) => tcp_listener,
+                Err(error)       => {
+                    error!("Cannot accept emails over SMTP on {}: {}", addr, error);
+                    return;
+                },
+            };
+            info!("Accepting emails over SMTP on {}", addr);
+
+            let incoming = tcp_listener.incoming().filter_map(|stream| match stream {
+                Ok(stream) => Some(stream),
+                Err(error) => {
+                    warn!("Cannot accept a connection on {}: {}", addr, error);
+                    None
+                },
+            });
+            let label = format!("the SMTP listener on {}", addr);
+            serve_incoming(incoming, MAX_SESSIONS, &label, |stream| {
+                if let Err(error) = self.serve_stream(stream) {
+                    debug!("SMTP session on {} ended: {}", addr, error);
+                }
+            }, |mut stream| {
+                // RFC 5321 section 3.1, the server may refuse the session
+                // with 421 instead of the greeting
+                let _ = reply(&mut stream, 421, "4.3.2 localhost is busy, try again later");
+            });
+        })
+    }
+
+    fn serve_stream(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(SESSION_TIMEOUT))?;
+        let reader = BufReader::new(stream.try_clone()?);
+        self.serve(reader, stream, |mail| {
+            let delivery = self.handoff.deliver(&mail);
+            self.bounce_rejected(&mail, &delivery);
+            smtp_reply(delivery)
+        })
+    }
+
+    /// Bounces the recipients that the server rejected for good, when the
+    /// email is accepted anyway, as the reply to DATA cannot name them
+    fn bounce_rejected(&self, mail: &Mail, delivery: &Delivery) {
+        let error = match delivery {
+            Delivery::Queued { error, .. }                                         => error,
+            Delivery::Failed(error @ SendError::Failed(response))
+                    if response.status == Status::PartiallySent                     => error,
+            _                                                                      => return,
+        };
+        let rejected: Vec<RecipientStatus> = match error {
+            SendError::Failed(response) => response.rejected().into_iter()
+                .filter(|rejected| !rejected.is_transient())
+                .cloned()
+                .collect(),
+            _                           => return,
+        };
+        if rejected.is_empty() {
+            return;
+        }
+        let account = mail.account.as_deref().unwrap_or_default();
+        match &self.bouncer {
+            Some(bouncer) => {
+                let metadata = Metadata::failed(&error.to_string(), error.code());
+                if let Err(error) = bouncer.bounce(account, mail, &metadata, &rejected) {
+                    error!("Cannot bounce an email of {}: {}", account, error);
+                }
+            },
+            None          =>
+                warn!("An email of {} is not bounced, as the rusmtprc sets no bounce", account),
+        }
+    }
+
+    /// Serves one SMTP session, handing every email over to `deliver`,
+    /// which returns the reply of the email
+    fn serve<R, W, D>(&self, mut reader: R, mut writer: W, mut deliver: D) -> io::Result<()>
+            where R: BufRead, W: Write, D: FnMut(Mail) -> (u16, String) {
+        reply(&mut writer, 220, "localhost rusmtpd ESMTP ready")?;
+
+        let mut greeted = false;
+        let mut authenticated: Option<String> = None;
+        let mut account: Option<String> = None;
+        let mut recipients: Vec<String> = Vec::new();
+        loop {
+            let line = match read_line(&mut reader) {
+                Ok(Some(line))                                          => line,
+                Ok(None)                                                => return Ok(()),
+                Err(ref error) if error.kind() == ErrorKind::InvalidData => {
+                    reply(&mut writer, 500, "5.5.6 Line too long")?;
+                    continue;
+                },
+                Err(error)                                              => return Err(error),
+            };
+            let (verb, argument) = match line.find(' ') {
+                Some(index) => (line[..index].to_uppercase(), line[index + 1..].trim()),
+                None        => (line.to_uppercase(), ""),
+            };
+            if is_http(&verb, argument) {
+                // A web page that POSTs to the listener would have the
+                // browser send the SMTP commands of its body otherwise
+                warn!("Closing an SMTP session that sent an HTTP request");
+                return Ok(());
+            }
+
+            match verb.as_str() {
+                "EHLO"                       => {
+                    greeted = true;
+                    account = None;
+                    recipients.clear();
+                    writer.write_all(format!("250-localhost greets {}\r\n\
+                                              250-8BITMIME\r\n\
+                                              250-SIZE {}\r\n\
+                                              250 AUTH PLAIN LOGIN\r\n",
+                                             argument, MAX_MESSAGE_SIZE).as_bytes())?;
+                    writer.flush()?;
+                },
+                "HELO"                       => {
+                    greeted = true;
+                    account = None;
+                    recipients.clear();
+                    reply(&mut writer, 250, "localhost")?;
+                },
+                "MAIL" | "RCPT" | "DATA" |
+                "AUTH" if !greeted           => reply(&mut writer, 503, "Send EHLO first")?,
+                "MAIL" if account.is_some()  => reply(&mut writer, 503, "Nested MAIL command")?,
+                "MAIL"                       => match path(argument, "FROM:") {
+                    None         => reply(&mut writer, 501, "Syntax: MAIL FROM:<address>")?,
+                    Some(sender) => {
+                        account = authenticated.clone()
+                            .or_else(|| self.routes.of_sender(&sender).map(String::from));
+                        match account {
+                            Some(_) => reply(&mut writer, 250, "OK")?,
+                            None    => reply(&mut writer, 550,
+                                &format!("No account sends emails from <{}>", sender))?,
+                        }
+                    },
+                },
+                "RCPT" if account.is_none()  => reply(&mut writer, 503, "Send MAIL first")?,
+                "RCPT"                       => match path(argument, "TO:") {
+                    Some(ref recipient) if recipient.is_empty() =>
+                        reply(&mut writer, 501, "Syntax: RCPT TO:<address>")?,
+                    Some(ref recipient) if recipient.len() > MAX_PATH_LENGTH =>
+                        reply(&mut writer, 553, "The address is too long")?,
+                    Some(recipient)                             => {
+                        recipients.push(recipient);
+                        reply(&mut writer, 250, "OK")?;
+                    },
+                    None                                        =>
+                        reply(&mut writer, 501, "Syntax: RCPT TO:<address>")?,
+                },
+                "DATA" if recipients.is_empty() => reply(&mut writer, 503, "Send RCPT first")?,
+                "DATA"                          => {
+                    reply(&mut writer, 354, "End data with <CR><LF>.<CR><LF>")?;
+                    let mail = read_data(&mut reader)?.map(|body| Mail {
+                        account: account.take(),
+                        recipients: recipients.split_off(0),
+                        dsn_notify: None,
+                        dsn_ret: None,
+                        envid: None,
+                        body,
+                    });
+                    match mail {
+                        Ok(mail)          => {
+                            let (code, text) = deliver(mail);
+                            reply(&mut writer, code, &text)?;
+                        },
+                        Err((code, text)) => {
+                            // The transaction is over either way
+                            account = None;
+                            recipients.clear();
+                            reply(&mut writer, code, text)?;
+                        },
+                    }
+                },
+                "AUTH" if authenticated.is_some() => reply(&mut writer, 503, "Already authenticated")?,
+                "AUTH" if account.is_some()       =>
+                    reply(&mut writer, 503, "AUTH is not allowed during a transaction")?,
+                "AUTH"                            =>
+                    match read_username(argument, &mut reader, &mut writer)? {
+                        Err((code, text)) => reply(&mut writer, code, text)?,
+                        Ok(user)          => match self.routes.of_user(&user) {
+                            Some(label) => {
+                                authenticated = Some(label.to_string());
+                                reply(&mut writer, 235, "Authentication succeeded")?;
+                            },
+                            None        => reply(&mut writer, 535,
+                                &format!("No account has the username {}", user))?,
+                        },
+                    },
+                "RSET"                       => {
+                    account = None;
+                    recipients.clear();
+                    reply(&mut writer, 250, "OK")?;
+                },
+                "NOOP"                       => reply(&mut writer, 250, "OK")?,
+                "VRFY"                       =>
+                    reply(&mut writer, 252, "Cannot verify the user, but will try to deliver")?,
+                "QUIT"                       => return reply(&mut writer, 221, "Bye"),
+                ""                           => reply(&mut writer, 500, "Syntax error")?,
+                _                            => reply(&mut writer, 502, "Command not implemented")?,
+            }
+        }
+    }
+}
+
+/// The reply to an email that was handed over
+fn smtp_reply(delivery: Delivery) -> (u16, String) {
+    let text = |error: &dyn ToString| error.to_string().replace(['\r', '\n'], " ");
+    match delivery {
+        Delivery::Sent(response)                                           =>
+            match response.queue_id {
+                Some(queue_id) => (250, format!("Sent, queued as {}", queue_id)),
+                None           => (250, "Sent".to_string()),
+            },
+        Delivery::Queued { id, .. }                                        =>
+            (250, format!("Queued for retry as {}", id)),
+        Delivery::Failed(SendError::Failed(ref response))
+                if response.status == Status::PartiallySent                 => {
+            let rejected: Vec<String> = response.rejected().iter()
+                .map(|rejected| text(rejected))
+                .collect();
+            (250, format!("Sent to some of the recipients, rejected: {}", rejected.join("; ")))
+        },
+        Delivery::Failed(SendError::Failed(response))                      => {
+            let code = response.code
+                .or_else(|| response.rejected().first().map(|rejected| rejected.code));
+            (code.filter(|code| code / 100 == 5).unwrap_or(554), text(&response))
+        },
+        Delivery::Failed(error)                                            => (554, text(&error)),
+        Delivery::Unspooled(error)                                         => (451, text(&error)),
+    }
+}
+
+/// Whether the line is an HTTP request line or the Host header, like the
+/// ones that a browser sends, as Postfix checks
+fn is_http(verb: &str, argument: &str) -> bool {
+    match verb {
+        "GET" | "POST" | "HEAD" | "PUT" | "OPTIONS" | "CONNECT" => argument.contains(" HTTP/"),
+        verb                                                     => verb.starts_with("HOST:"),
+    }
+}
+
+fn reply<W: Write>(writer: &mut W, code: u16, text: &str) -> io::Result<()> {
+    writer.write_all(format!("{} {}\r\n", code, text).as_bytes())?;
+    writer.flush()
+}
+
+/// Reads a command line, without the line ending, or None once the client
+/// closes the connection. A line longer than MAX_LINE_LENGTH is skipped, and
+/// fails with InvalidData.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    if !read_limited_line(reader, &mut line)? {
+        return Err(io::Error::new(ErrorKind::InvalidData,
+                                  format!("The line is longer than {} octets", MAX_LINE_LENGTH)));
+    }
+    if line.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n'])
+            .to_string()))
+}
+
+/// Reads a line with its line ending into `line`, which stays empty once the
+/// client closes the connection. Returns false, after skipping the rest of
+/// it, when the line is longer than MAX_LINE_LENGTH.
+fn read_limited_line<R: BufRead>(reader: &mut R, line: &mut Vec<u8>) -> io::Result<bool> {
+    Read::take(&mut *reader, MAX_LINE_LENGTH as u64).read_until(b'\n', line)?;
+    if line.len() < MAX_LINE_LENGTH || line.ends_with(b"\n") {
+        return Ok(true);
+    }
+    loop {
+        let (skipped, found) = {
+            let buffer = reader.fill_buf()?;
+            match buffer.iter().position(|&byte| byte == b'\n') {
+                Some(index) => (index + 1, true),
+                None        => (buffer.len(), buffer.is_empty()),
+            }
+        };
+        reader.consume(skipped);
+        if found {
+            return Ok(false);
+        }
+    }
+}
+
+/// The address of `FROM:<address>` or `TO:<address>`, ignoring the
+/// parameters after it
+fn path(argument: &str, prefix: &str) -> Option<String> {
+    if argument.len() < prefix.len() || !argument[..prefix.len()].eq_ignore_ascii_case(prefix) {
+        return None;
+    }
+    let argument = argument[prefix.len()..].trim_start();
+    let end = argument.find('>')?;
+    if argument.starts_with('<') {
+        Some(argument[1..end].to_string())
+    } else {
+        None
+    }
+}
+
+/// Reads the message of DATA up to the lone period, undoing the
+/// dot-stuffing. A message with a line longer than MAX_LINE_LENGTH, or
+/// larger than MAX_MESSAGE_SIZE, is read to its end and rejected with the
+/// reply.
+fn read_data<R: BufRead>(reader: &mut R) -> io::Result<Result<Vec<u8>, (u16, &'static str)>> {
+    let mut body = Vec::new();
+    let mut rejection = None;
+    loop {
+        let mut line = Vec::new();
+        if !read_limited_line(reader, &mut line)? {
+            rejection = rejection.or(Some((500, "5.5.6 Line too long")));
+            continue;
+        }
+        if line.is_empty() {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof,
+                                      "The client closed the connection in the middle of DATA"));
+        }
+        if line == b".\r\n" || line == b".\n" {
+            return Ok(rejection.map_or(Ok(body), Err));
+        }
+        let unstuffed = if line.starts_with(b".") { &line[1..] } else { &line[..] };
+        if body.len() + unstuffed.len() > MAX_MESSAGE_SIZE {
+            rejection = rejection.or(Some((552, "5.3.4 The message is too big")));
+        }
+        if rejection.is_none() {
+            body.extend_from_slice(unstuffed);
+        }
+    }
+}
+
+/// Reads the username of AUTH PLAIN or AUTH LOGIN. The password is not
+/// checked, the username only picks the account.
+fn read_username<R, W>(argument: &str, reader: &mut R, writer: &mut W)
+        -> io::Result<Result<String, (u16, &'static str)>> where R: BufRead, W: Write {
+    let mut parts = argument.splitn(2, ' ');
+    let mechanism = parts.next().unwrap_or("").to_uppercase();
+    let initial = parts.next().map(|initial| initial.trim().to_string());
+
+    let mut response = |challenge: &str, initial: Option<String>| -> io::Result<Option<Vec<u8>>> {
+        let line = match initial {
+            Some(initial) => initial,
+            None          => {
+                reply(writer, 334, challenge)?;
+                match read_line(reader) {
+                    Err(ref error) if error.kind() == ErrorKind::InvalidData => return Ok(None),
+                    line                                                    =>
+                        line?.unwrap_or_default(),
+                }
+            },
+        };
+        if line == "=" {
+            Ok(Some(Vec::new()))
+        } else {
+            Ok(decode(&line).ok())
+        }
+    };
+
+    let malformed = Err((501, "Malformed credentials"));
+    match mechanism.as_str() {
+        "PLAIN" => match response("", initial)? {
+            // authorization identity \0 authentication identity \0 password
+            Some(credentials) => match credentials.split(|&byte| byte == 0).nth(1) {
+                Some(user) if !user.is_empty() =>
+                    Ok(Ok(String::from_utf8_lossy(user).to_string())),
+                _                              => Ok(malformed),
+            },
+            None              => Ok(malformed),
+        },
+        "LOGIN" => match response("VXNlcm5hbWU6", initial)? {
+            Some(user) if !user.is_empty() => {
+                // The password is read, and ignored
+                response("UGFzc3dvcmQ6", None)?;
+                Ok(Ok(String::from_utf8_lossy(&user).to_string()))
+            },
+            _                              => Ok(malformed),
+        },
+        _       => Ok(Err((504, "Unrecognized authentication type"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handoff::Route;
+    use common::Error;
+    use common::bounce::BounceTarget;
+    use common::response::Response;
+    use std::collections::HashMap;
+    use std::env::temp_dir;
+    use std::fs::{read_to_string, remove_file};
+    use std::io::Cursor;
+    use std::process;
+
+    fn route(label: &str, username: &str, default: bool) -> Route {
+        Route {
+            label: label.to_string(),
+            username: Some(username.to_string()),
+            default,
+        }
+    }
+
+    fn listener(routes: Vec<Route>) -> Listener {
+        Listener::new(Routes::new(routes), Handoff {
+            socket_root: String::new(),
+            flock_root: String::new(),
+            spool_root: String::new(),
+            timeout: Duration::from_secs(1),
+        }, None)
+    }
+
+    /// Runs the session, and returns the replies along with the emails that
+    /// were handed over
+    fn session(listener: &Listener, commands: &str) -> (Vec<String>, Vec<Mail>) {
+        let mut writer = Vec::new();
+        let mut mails = Vec::new();
+        listener.serve(Cursor::new(commands.as_bytes()), &mut writer, |mail| {
+            mails.push(mail);
+            (250, "Sent".to_string())
+        }).unwrap();
+        let replies = String::from_utf8(writer).unwrap().lines()
+            .map(|line| line.to_string())
+            .collect();
+        (replies, mails)
+    }
+
+    #[test]
+    fn test_session() {
+        let listener = listener(vec![route("first", "me@example.com", false),
+                                     route("second", "you@example.com", false)]);
+        let (replies, mails) = session(&listener,
+            "EHLO jenkins\r\n\
+             MAIL FROM:<You@Example.com> SIZE=100\r\n\
+             RCPT TO:<a@b.c>\r\n\
+             RCPT TO:<d@e.f> NOTIFY=NEVER\r\n\
+             DATA\r\n\
+             Subject: hi\r\n\
+             \r\n\
+             ..dotted\r\n\
+             .\r\n\
+             QUIT\r\n");
+
+        assert_eq!(vec!["220 localhost rusmtpd ESMTP ready", "250-localhost greets jenkins",
+                        "250-8BITMIME", "250-SIZE 33554432", "250 AUTH PLAIN LOGIN", "250 OK",
+                        "250 OK", "250 OK",
+                        "354 End data with <CR><LF>.<CR><LF>", "250 Sent", "221 Bye"],
+                   replies);
+        assert_eq!(vec![Mail {
+            account: Some("second".to_string()),
+            recipients: vec!["a@b.c".to_string(), "d@e.f".to_string()],
+            dsn_notify: None,
+            dsn_ret: None,
+            envid: None,
+            body: b"Subject: hi\r\n\r\n.dotted\r\n".to_vec(),
+        }], mails);
+    }
+
+    #[test]
+    fn test_session_refuses_commands_out_of_order() {
+        let listener = listener(vec![route("first", "me@example.com", false)]);
+        let (replies, mails) = session(&listener,
+            "MAIL FROM:<me@example.com>\r\n\
+             HELO jenkins\r\n\
+             RCPT TO:<a@b.c>\r\n\
+             MAIL FROM:<me@example.com>\r\n\
+             DATA\r\n\
+             RCPT TO:a@b.c\r\n\
+             EXPN list\r\n");
+        assert_eq!(vec!["220 localhost rusmtpd ESMTP ready", "503 Send EHLO first",
+                        "250 localhost", "503 Send MAIL first", "250 OK", "503 Send RCPT first",
+                        "501 Syntax: RCPT TO:<address>", "502 Command not implemented"],
+                   replies);
+        assert!(mails.is_empty());
+    }
+
+    #[test]
+    fn test_session_without_account() {
+        let listener = listener(vec![route("first", "me@example.com", false),
+                                     route("second", "you@example.com", false)]);
+        let (replies, _) = session(&listener,
+            "EHLO jenkins\r\nMAIL FROM:<anyone@example.com>\r\n");
+        assert_eq!("550 No account sends emails from <anyone@example.com>", replies[5]);
+    }
+
+    #[test]
+    fn test_account_of_authenticated_user() {
+        let listener = listener(vec![route("first", "me@example.com", true),
+                                     route("second", "you@example.com", false)]);
+        // \0you@example.com\0secret, then second and secret
+        let (replies, mails) = session(&listener,
+            "EHLO jenkins\r\n\
+             AUTH PLAIN AHlvdUBleGFtcGxlLmNvbQBzZWNyZXQ=\r\n\
+             MAIL FROM:<me@example.com>\r\n\
+             RCPT TO:<a@b.c>\r\n\
+             DATA\r\n\
+             hi\r\n\
+             .\r\n\
+             AUTH LOGIN\r\n");
+        assert_eq!("235 Authentication succeeded", replies[5]);
+        assert_eq!("503 Already authenticated", replies[10]);
+        assert_eq!(Some("second".to_string()), mails[0].account);
+
+        let (replies, _) = session(&listener,
+            "EHLO jenkins\r\nAUTH LOGIN\r\nc2Vjb25k\r\nc2VjcmV0\r\nAUTH CRAM-MD5\r\n");
+        assert_eq!(vec!["334 VXNlcm5hbWU6", "334 UGFzc3dvcmQ6", "235 Authentication succeeded",
+                        "503 Already authenticated"], replies[5..].to_vec());
+
+        let (replies, _) = session(&listener,
+            "EHLO jenkins\r\nAUTH PLAIN AGFueW9uZQBzZWNyZXQ=\r\nAUTH CRAM-MD5\r\n\
+             AUTH PLAIN !!\r\n");
+        assert_eq!(vec!["535 No account has the username anyone",
+                        "504 Unrecognized authentication type", "501 Malformed credentials"],
+                   replies[5..].to_vec());
+    }
+
+    #[test]
+    fn test_session_limits_lines_and_messages() {
+        let listener = listener(vec![route("first", "me@example.com", true)]);
+        let transaction = "MAIL FROM:<me@example.com>\r\nRCPT TO:<a@b.c>\r\nDATA\r\n";
+        let long_line = "x".repeat(MAX_LINE_LENGTH);
+        let lines = (MAX_MESSAGE_SIZE / long_line.len()) + 1;
+        let (replies, mails) = session(&listener, &format!(
+            "HELO jenkins\r\n\
+             NOOP {long}\r\n\
+             {transaction}{long}\r\nshort\r\n.\r\n\
+             {transaction}{many}.\r\n\
+             {transaction}fits\r\n.\r\n",
+            long = long_line, transaction = transaction,
+            many = format!("{}\r\n", &long_line[2..]).repeat(lines)));
+
+        assert_eq!(vec!["220 localhost rusmtpd ESMTP ready", "250 localhost",
+                        "500 5.5.6 Line too long",
+                        "250 OK", "250 OK", "354 End data with <CR><LF>.<CR><LF>",
+                        "500 5.5.6 Line too long",
+                        "250 OK", "250 OK", "354 End data with <CR><LF>.<CR><LF>",
+                        "552 5.3.4 The message is too big",
+                        "250 OK", "250 OK", "354 End data with <CR><LF>.<CR><LF>", "250 Sent"],
+                   replies);
+        assert_eq!(1, mails.len());
+        assert_eq!(b"fits\r\n".to_vec(), mails[0].body);
+    }
+
+    #[test]
+    fn test_session_of_a_browser_is_closed() {
+        let listener = listener(vec![route("first", "me@example.com", true)]);
+        let commands = "EHLO jenkins\r\n\
+                        MAIL FROM:<me@example.com>\r\n\
+                        RCPT TO:<a@b.c>\r\n\
+                        DATA\r\n\
+                        hi\r\n\
+                        .\r\n";
+        let request = format!("POST / HTTP/1.1\r\n\
+                               Host: 127.0.0.1:2525\r\n\
+                               Content-Type: text/plain\r\n\
+                               Content-Length: {}\r\n\
+                               \r\n{}", commands.len(), commands);
+        let (replies, mails) = session(&listener, &request);
+        assert_eq!(vec!["220 localhost rusmtpd ESMTP ready"], replies);
+        assert!(mails.is_empty());
+
+        // Even when the request line is cut off
+        let (replies, mails) = session(&listener, &format!("Host:localhost\r\n{}", commands));
+        assert_eq!(vec!["220 localhost rusmtpd ESMTP ready"], replies);
+        assert!(mails.is_empty());
+
+        assert!(is_http("GET", "/index.html HTTP/1.0"));
+        assert!(is_http("CONNECT", "localhost:2525 HTTP/1.1"));
+        assert!(!is_http("POST", "office"));
+        assert!(!is_http("HELO", "host:"));
+    }
+
+    #[test]
+    fn test_smtp_reply() {
+        let sent = Response {
+            queue_id: Some("4AB3C2".to_string()),
+            ..Response::new(Status::Sent, "Email sent")
+        };
+        assert_eq!((250, "Sent, queued as 4AB3C2".to_string()), smtp_reply(Delivery::Sent(sent)));
+
+        let queued = Delivery::Queued {
+            id: "first-1-2".to_string(),
+            error: SendError::Unavailable(Error::Io("Connection refused".to_string())),
+        };
+        assert_eq!((250, "Queued for retry as first-1-2".to_string()), smtp_reply(queued));
+
+        let rejected = Response {
+            recipients: vec![RecipientStatus {
+                recipient: "a@b.c".to_string(),
+                code: 550,
+                enhanced_status: None,
+                message: "No such user".to_string(),
+            }],
+            ..Response::new(Status::PermanentFailure, "Every recipient was rejected")
+        };
+        assert_eq!((550, "Every recipient was rejected; a@b.c: 550 No such user".to_string()),
+                   smtp_reply(Delivery::Failed(SendError::Failed(rejected))));
+
+        let unspooled = Delivery::Unspooled(Error::Io("Cannot archive the email".to_string()));
+        assert_eq!(451, smtp_reply(unspooled).0);
+
+        assert_eq!((250, "Sent to some of the recipients, rejected: a@b.c: 550 No such user; \
+                          d@e.f: 450 Mailbox busy".to_string()),
+                   smtp_reply(Delivery::Failed(SendError::Failed(partially_sent()))));
+    }
+
+    #[test]
+    fn test_rejected_recipients_are_bounced() {
+        let path = temp_dir().join(format!("rusmtp-listener-{}.mbox", process::id()));
+        let _ = remove_file(&path);
+        let mut listener = listener(vec![route("first", "me@example.com", true)]);
+        listener.bouncer = Some(Bouncer {
+            target: BounceTarget::Mbox(path.display().to_string()),
+            socket_root: String::new(),
+            timeout: Duration::from_secs(1),
+            usernames: HashMap::new(),
+        });
+        let mail = Mail {
+            account: Some("first".to_string()),
+            recipients: vec!["a@b.c".to_string(), "d@e.f".to_string(), "g@h.i".to_string()],
+            dsn_notify: None,
+            dsn_ret: None,
+            envid: None,
+            body: b"Subject: hi\r\n\r\nhi\r\n".to_vec(),
+        };
+
+        listener.bounce_rejected(&mail, &Delivery::Sent(Response::new(Status::Sent, "")));
+        assert!(!path.exists());
+
+        // The recipient that is queued for retry is not bounced yet
+        listener.bounce_rejected(&mail, &Delivery::Failed(SendError::Failed(partially_sent())));
+        let bounce = read_to_string(&path).unwrap();
+        assert!(bounce.contains("Final-Recipient: rfc822; a@b.c"));
+        assert!(!bounce.contains("Final-Recipient: rfc822; d@e.f"));
+        assert!(!bounce.contains("Final-Recipient: rfc822; g@h.i"));
+        let _ = remove_file(&path);
+    }
+
+    fn partially_sent() -> Response {
+        let rejected = |recipient: &str, code, message: &str| RecipientStatus {
+            recipient: recipient.to_string(),
+            code,
+            enhanced_status: None,
+            message: message.to_string(),
+        };
+        Response {
+            recipients: vec![rejected("a@b.c", 550, "No such user"),
+                             rejected("d@e.f", 450, "Mailbox busy"),
+                             rejected("g@h.i", 250, "OK")],
+            ..Response::new(Status::PartiallySent, "Some of the recipients were rejected")
+        }
+    }
+}
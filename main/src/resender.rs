@@ -0,0 +1,186 @@
+// Retries the spooled emails when they are due, and moves the ones that it
+// gives up on to the failed queue of the spool, bouncing them if the
+// rusmtprc asks for it.
+
+use client::{send_to_daemon, to_retry, SendError};
+use common::Error;
+use common::mail::Mail;
+use common::response::RecipientStatus;
+use common::spool::{account_of, lock, now, Metadata, Queue, RetryPolicy, Spool};
+use crate::bouncer::Bouncer;
+use fs2::FileExt;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How often the spool is checked for the emails that are newly queued
+const SPOOL_POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// How long the files of the crashed writers are left in tmp, like the
+/// Maildir readers do
+const STALE_TMP_AGE: Duration = Duration::from_secs(36 * 60 * 60);
+
+pub struct Resender {
+    pub spool: Spool,
+    pub flock_root: String,
+    pub socket_root: String,
+    pub timeout: Duration,
+    pub policy: RetryPolicy,
+    pub bouncer: Option<Bouncer>,
+}
+
+impl Resender {
+    pub fn start(self) -> JoinHandle<()> {
+        thread::spawn(move || {
+            loop {
+                let next_attempt_at = match self.retry_due() {
+                    Ok(next_attempt_at) => next_attempt_at,
+                    Err(error)          => {
+                        error!("Cannot retry the spooled emails: {}", error);
+                        None
+                    },
+                };
+                // Wake up for the next email that is due, but look for the
+                // newly queued ones meanwhile
+                let wait = next_attempt_at
+                    .map(|at| Duration::from_secs(at.saturating_sub(now())))
+                    .unwrap_or(SPOOL_POLL_INTERVAL)
+                    .clamp(Duration::from_secs(1), SPOOL_POLL_INTERVAL);
+                thread::sleep(wait);
+            }
+        })
+    }
+
+    /// Retries the spooled emails that are due, and returns when the next
+    /// one is due, if any
+    fn retry_due(&self) -> Result<Option<u64>, Error> {
+        self.spool.clean_tmp(STALE_TMP_AGE)?;
+        // The clients before the queues wrote the emails in place, while
+        // they held the flock
+        for (name, account) in self.spool.legacy_ids()? {
+            let lock_file = match lock(&self.flock_root, &account) {
+                Ok(lock_file) => lock_file,
+                Err(error)    => {
+                    error!("Cannot migrate the spooled email {}: {}", name, error);
+                    continue;
+                },
+            };
+            let migrated = self.spool.migrate(&name);
+            let _ = FileExt::unlock(&lock_file);
+            match migrated {
+                Ok((id, Queue::Failed)) =>
+                    error!("The spooled email {} is truncated, moved it to the failed queue as {}",
+                           name, id),
+                Ok((id, _))             => info!("Migrated the spooled email {} to {}", name, id),
+                Err(error)              =>
+                    error!("Cannot migrate the spooled email {}: {}", name, error),
+            }
+        }
+
+        let mut next_attempt_at: Option<u64> = None;
+        for &queue in &[Queue::New, Queue::Cur] {
+            let (ids, unknown) = self.spool.ids(queue)?;
+            for name in unknown {
+                warn!("{} is not a spooled email, moving it to the failed queue", name);
+                if let Err(error) = self.spool.fail(queue, &name,
+                                                    &Metadata::failed("Not a spooled email", None)) {
+                    error!("Cannot move {} to the failed queue: {}", name, error);
+                }
+            }
+            for id in ids {
+                let account = match account_of(&id) {
+                    Some(account) => account,
+                    None          => continue,
+                };
+                // The other emails are still retried when one of them fails
+                let lock_file = match lock(&self.flock_root, account) {
+                    Ok(lock_file) => lock_file,
+                    Err(error)    => {
+                        error!("Cannot retry the spooled email {}: {}", id, error);
+                        continue;
+                    },
+                };
+                let retried = self.retry_spooled(queue, &id, account);
+                let _ = FileExt::unlock(&lock_file);
+                match retried {
+                    Ok(Some(at)) =>
+                        next_attempt_at = Some(next_attempt_at.map_or(at, |next| next.min(at))),
+                    Ok(None)     => (),
+                    Err(error)   => error!("Cannot retry the spooled email {}: {}", id, error),
+                }
+            }
+        }
+        Ok(next_attempt_at)
+    }
+
+    /// Retries the spooled email if it is due, and returns when it is retried
+    /// next, unless it leaves the spool
+    fn retry_spooled(&self, queue: Queue, id: &str, account: &str)
+            -> Result<Option<u64>, Error> {
+        let now = now();
+        let mut metadata = self.spool.metadata_or_new(queue, id);
+        if metadata.next_attempt_at > now {
+            return Ok(Some(metadata.next_attempt_at));
+        }
+
+        let mail = match self.spool.read(queue, id) {
+            Ok(mail)   => mail,
+            Err(error) => {
+                error!("Cannot read {}, moving it to the failed queue: {}", id, error);
+                metadata.last_error = Some(format!("Cannot read the spooled email: {}", error));
+                self.spool.fail(queue, id, &metadata)?;
+                return Ok(None);
+            },
+        };
+        let error = match send_to_daemon(&mail, &self.socket_root, self.timeout, account) {
+            Ok(_)      => {
+                self.spool.remove(queue, id)?;
+                return Ok(None);
+            },
+            Err(error) => error,
+        };
+
+        metadata.record_failure(&error.to_string(), error.code());
+        match to_retry(&mail, &error) {
+            None                                                  => {
+                error!("Giving up on {}: {}", id, error);
+                self.give_up(queue, id, account, &mail, &metadata, &error)?;
+                Ok(None)
+            },
+            Some(_) if self.policy.is_exhausted(&metadata, now)   => {
+                error!("Giving up on {} after {} attempts: {}", id, metadata.attempts, error);
+                self.give_up(queue, id, account, &mail, &metadata, &error)?;
+                Ok(None)
+            },
+            Some(retry)                                           => {
+                // Keep only the recipients that may still accept it
+                if retry.recipients != mail.recipients {
+                    self.spool.rewrite(queue, id, &retry)?;
+                }
+                metadata.next_attempt_at = now + self.policy.backoff(metadata.attempts).as_secs();
+                match queue {
+                    Queue::Cur => self.spool.save_metadata(queue, id, &metadata)?,
+                    _          => self.spool.move_to(id, queue, Queue::Cur, &metadata)?,
+                }
+                debug!("Retrying {} in {} seconds: {}", id, metadata.next_attempt_at - now, error);
+                Ok(Some(metadata.next_attempt_at))
+            },
+        }
+    }
+
+    /// Moves the email to the failed queue, where it is not retried anymore,
+    /// and bounces it
+    fn give_up(&self, queue: Queue, id: &str, account: &str, mail: &Mail, metadata: &Metadata,
+               error: &SendError) -> Result<(), Error> {
+        self.spool.fail(queue, id, metadata)?;
+        if let Some(bouncer) = &self.bouncer {
+            // The recipients that the server accepted are not bounced
+            let rejected: Vec<RecipientStatus> = match error {
+                SendError::Failed(response) => response.rejected().into_iter().cloned().collect(),
+                _                           => Vec::new(),
+            };
+            if let Err(error) = bouncer.bounce(account, mail, metadata, &rejected) {
+                error!("Cannot bounce {}: {}", id, error);
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,93 +1,150 @@
-pub mod clients;
-
 #[macro_use]
 extern crate log;
 
 use std::alloc::System;
 use std::fs::File;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::Duration;
 use std::path::Path;
 use std::{thread, time};
-use std::io::{self, Read, Write};
-use rand::random;
+use std::io::{self, Read};
+use std::process::exit;
 use fs2::FileExt;
 use dirs::home_dir;
-use crate::clients::send_to_daemon;
-use common::*;
+use client::{send_to_daemon, to_retry, SendError};
+use protocol::dsn::{parse_notify, parse_ret, parse_envid};
+use common::get_lock_path;
 use common::args::*;
 use common::mail::*;
 use common::config::*;
+use common::response::Status;
+use common::spool::{Metadata, Spool};
+use common::sysexits::*;
 
 #[global_allocator]
 static GLOBAL: System = System;
 
+/// Reports the failure in one line, and exits with the code
+fn fail<A>(code: i32, msg: &str) -> A {
+    error!("{}", msg);
+    eprintln!("rusmtpc: {}", msg);
+    exit(code)
+}
+
 fn main () {
-    log4rs::init_file(format!("{}/.rusmtp/rusmtpc-log4rs.yaml",
-          home_dir().expect("Cannot find the home directory").display()),
-          Default::default()).unwrap();
+    let args: Args = process_args("rusmtpc", &rusmtpc_usage("rusmtpc"));
 
-    let args = process_args("rusmtpc", &rusmtpc_usage("rusmtpc"));
-    let conf = read_config(&args.flag_rusmtprc);
+    let home_dir = home_dir().unwrap_or_else(||
+        fail(EX_CONFIG, "Cannot find the home directory"));
+    if let Err(e) = log4rs::init_file(
+            format!("{}/.rusmtp/rusmtpc-log4rs.yaml", home_dir.display()),
+            Default::default()) {
+        fail::<()>(EX_CONFIG, &format!("Cannot configure the logs: {}", e));
+    }
+
+    let conf = read_config(&args.flag_rusmtprc)
+        .unwrap_or_else(|e| fail(EX_CONFIG, &e.to_string()));
 
+    let dsn_notify = args.flag_dsn_notify.map(|notify| parse_notify(&notify)
+        .unwrap_or_else(|e| fail(EX_USAGE, &e.to_string())));
+    let dsn_ret = args.flag_dsn_ret.map(|ret| parse_ret(&ret)
+        .unwrap_or_else(|e| fail(EX_USAGE, &e.to_string())));
+    let envid = args.flag_envid.map(|envid| parse_envid(&envid)
+        .unwrap_or_else(|e| fail(EX_USAGE, &e.to_string())));
+
+    let account = args.flag_account.clone().unwrap_or_else(|| {
+        conf.accounts.iter()
+            .find(|acc| acc.default)
+            .map(|acc| acc.label.clone())
+            .unwrap_or_else(||
+                fail(EX_USAGE, "Please pass a valid account name or set a default account"))
+    });
 
     let mut body: Vec<u8> = Vec::new();
-    io::stdin().read_to_end(&mut body).unwrap_or_else(|_|
-        log_and_panic("Reading mail from the stdin"));
+    io::stdin().read_to_end(&mut body).unwrap_or_else(|e|
+        fail(EX_IOERR, &format!("Cannot read the email from the stdin: {}", e)));
 
     let mail = Mail {
         recipients: args.arg_recipients,
+        dsn_notify,
+        dsn_ret,
+        envid,
         body,
         account: args.flag_account,
     };
 
-    let account = &mail.account.as_ref().unwrap_or_else(|| {
-      let &value = conf.accounts.iter()
-        .filter(|acc| acc.default)
-        .map(|x| &x.label)
-        .collect::<Vec<_>>()
-        .first()
-        .unwrap_or_else(||
-            log_and_panic("Please pass a valid account name or set a default account"));
-      value
-    });
-
-    let flock_path = get_lock_path(&conf.flock_root, account);
+    let flock_path = get_lock_path(&conf.flock_root, &account);
 
     if ! Path::new(&flock_path).exists() {
         let _ = File::create(&flock_path);
     }
 
     let retry = args.flag_with_retry.unwrap_or(false);
-    let spool_root = &conf.spool_root;
+    let spool = Spool::new(&conf.spool_root);
 
-    let lock_file = File::open(&flock_path).unwrap_or_else(|_| {
-        enqueue(&mail, spool_root, retry);
-        log_and_panic(&format!("Cannot open flock {}", flock_path))
+    let lock_file = File::open(&flock_path).unwrap_or_else(|e| {
+        let msg = format!("Cannot open flock {}: {}", flock_path, e);
+        if retry {
+            spool.enqueue(&mail, &account, &Metadata::new())
+                .unwrap_or_else(|e| fail(EX_CANTCREAT, &e.to_string()));
+            fail(EX_TEMPFAIL, &format!("{} (queued for retry)", msg))
+        } else {
+            fail(EX_UNAVAILABLE, &msg)
+        }
     });
+    // The lock is shared with the other clients, as the daemon serves them
+    // concurrently, but not with the resender, which retries the spooled
+    // emails of the account while no client is sending with it
     let ten_millis = time::Duration::from_millis(10);
-    while lock_file.lock_exclusive().is_err() {
+    while FileExt::lock_shared(&lock_file).is_err() {
         thread::sleep(ten_millis);
     }
 
-    let res = send_to_daemon(&mail, &conf.socket_root, conf.timeout, account);
-    if res.is_err() {
-        enqueue(&mail, spool_root, retry);
-        let _: String = log_and_panic(&res.unwrap_err());
+    match send_to_daemon(&mail, &conf.socket_root, Duration::from_secs(conf.timeout),
+                         &account) {
+        Ok(response) => {
+            if let Some(queue_id) = response.queue_id {
+                info!("The email is queued as {}", queue_id);
+            }
+            let _ = FileExt::unlock(&lock_file);
+        },
+        Err(error)   => {
+            let queued = match to_retry(&mail, &error) {
+                Some(mail) if retry => {
+                    let metadata = Metadata::failed(&error.to_string(), error.code());
+                    if let Err(e) = spool.enqueue(&mail, &account, &metadata) {
+                        let _ = FileExt::unlock(&lock_file);
+                        fail::<()>(EX_CANTCREAT, &format!("{}; {}", error, e));
+                    }
+                    true
+                },
+                _                   => false,
+            };
+            let _ = FileExt::unlock(&lock_file);
+            if queued {
+                fail::<()>(EX_TEMPFAIL, &format!("{} (queued for retry)", error));
+            } else {
+                fail::<()>(exit_code(&error), &error.to_string());
+            }
+        },
     }
-    let _ = lock_file.unlock();
 }
 
-fn enqueue(mail: &Mail, spool_root: &str, should_retry: bool) {
-    if should_retry {
-        let account = &mail.account.as_ref().unwrap();
-        let rand: u64 = random::<u64>();;
-        let since_the_epoch = SystemTime::now().duration_since(UNIX_EPOCH)
-            .expect("Time went backwards");
-        let mut email_file = File::create(
-            format!("{}/{}-{}-{}", spool_root,
-                    &account, rand, since_the_epoch.as_secs()))
-            .expect("Cannot archive the email, failing...");
-        email_file.write_all(mail.serialize().as_slice())
-            .expect("Cannot archive the email, failing...");
+/// The exit code of an email that is not sent to every recipient
+fn exit_code(error: &SendError) -> i32 {
+    let response = match error {
+        SendError::NoAccount        => return EX_USAGE,
+        SendError::Unavailable(_)   => return EX_UNAVAILABLE,
+        SendError::Daemon(_)        => return EX_TEMPFAIL,
+        SendError::Failed(response) => response,
+    };
+
+    let unknown_user = response.code == Some(550) ||
+        response.rejected().iter().any(|rejected| rejected.code == 550);
+    let transient = response.rejected().iter().any(|rejected| rejected.is_transient());
+    match response.status {
+        Status::TransientFailure | Status::Busy => EX_TEMPFAIL,
+        _ if unknown_user                       => EX_NOUSER,
+        Status::PartiallySent if transient      => EX_TEMPFAIL,
+        _                                       => EX_UNAVAILABLE,
     }
 }
@@ -1,24 +1,34 @@
+pub mod bouncer;
 pub mod clients;
+pub mod handoff;
+pub mod http;
+pub mod listener;
+pub mod resender;
 
 #[macro_use]
 extern crate log;
 
 use std::alloc::System;
-use std::process::{Command, Stdio};
-use std::fs::{remove_file, File};
-use std::io::{Read, Error};
-use std::path::Path;
+use std::process::exit;
 use dirs::home_dir;
-use fs2::FileExt;
-use std::{thread, time, fs, thread::JoinHandle};
+use std::{thread, fs, thread::JoinHandle};
 use common::*;
 use common::args::*;
-use common::mail::*;
 use common::config::*;
-use common::account::*;
-use crate::clients::*;
+use common::spool::Spool;
+use common::sysexits::EX_CONFIG;
+use common::account::Account;
+use crate::bouncer::Bouncer;
 use crate::clients::external::*;
 use crate::clients::default::*;
+use crate::clients::asynchronous::AsyncClient;
+use crate::handoff::{Handoff, Route, Routes};
+use crate::http::HttpListener;
+use crate::listener::Listener;
+use crate::resender::Resender;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Builder;
 
 #[global_allocator]
 static GLOBAL: System = System;
@@ -28,53 +38,31 @@ fn print_welcome_message() {
     println!("Ready to send emails");
 }
 
-fn retry_logic(spool_path: &str, flock_root: &str, socket_root: &str,
-               timeout: u64) -> Result<(), Error> {
-    let spool_dir = Path::new(&spool_path);
-    if spool_dir.is_dir() {
-        let spool_dir = fs::read_dir(spool_dir)?;
-        for entry in spool_dir {
-            let path = entry?.path();
-            if path.is_file() {
-                if let Some(path_string) =
-                    path.clone().file_name().and_then(|f| f.to_str()) {
-                    let v: Vec<&str> = path_string.split('-').collect();
-                    if v.len() == 3 {
-                        let account = v[0];
-                        let flock_path = get_lock_path(&flock_root, account);
-                        let lock_file = File::open(&flock_path)?;
-                        // It is safe to assume that everything is written
-                        // for this account contains the complete email message,
-                        // because this thread already acquires the flock,
-                        // which is only available if there is no active writes
-                        // to the emails of this account.
-                        lock_file.lock_exclusive()?;
-                        let file = &mut File::open(path.clone())?;
-                        let mut contents = Vec::new();
-                        file.read_to_end(&mut contents)?;
-                        if let Ok(mail) = Mail::deserialize(&mut contents) {
-                            if send_to_daemon(&mail, &socket_root, timeout, account).is_ok() {
-                                remove_file(path)?;
-                            }
-                        }
-                        let _ = lock_file.unlock();
-                    }
-                }
+/// Evaluates the password of the account, and removes the socket that a
+/// previous daemon may have left behind
+fn unlock_account(mut account: Account, socket_root: &str) -> Option<Account> {
+    let passwd = match &account.passwordeval {
+        Some(eval) => match evaluate(eval) {
+            Ok(passwd) => Some(passwd),
+            Err(error) => {
+                error!("Cannot evaluate the password of {}: {}", account.label, error);
+                return None;
             }
-        }
-    }
-    Ok(())
-}
-
-fn start_resender(spool_path: String, flock_root: String,
-                  socket_root: String, timeout: u64) -> JoinHandle<()> {
-    thread::spawn(move || {
-        loop {
-            let _ = retry_logic(&spool_path, &flock_root, &socket_root, timeout);
-            let one_minute = time::Duration::new(60, 0);
-            thread::sleep(one_minute);
-        }
-    })
+        },
+        None       => None,
+    };
+
+    // close the socket, if it exists
+    let _ = fs::remove_file(get_socket_path(socket_root, &account.label));
+
+    account.password = match passwd.map(|passwd| account.vault.encrypt(&passwd)).transpose() {
+        Ok(password) => password,
+        Err(error)   => {
+            error!("Cannot keep the password of {}: {}", account.label, error);
+            return None;
+        },
+    };
+    Some(account)
 }
 
 fn start_daemon(conf: Configuration) -> Vec<JoinHandle<()>> {
@@ -83,52 +71,33 @@ fn start_daemon(conf: Configuration) -> Vec<JoinHandle<()>> {
         let client = conf.smtpclient.clone();
         let socket_root = conf.socket_root.clone();
         children.push(thread::spawn(move || {
-            let eval = account.passwordeval.clone();
-
-            if let Ok(result) = Command::new("sh").arg("-c")
-                    .arg(eval).stdout(Stdio::piped()).spawn() {
-                let child_stdout = result.stdout;
-                if child_stdout.is_none() {
-                    error!("Cannot get the handle of the child process");
-                } else {
-                    let mut child_stdout = child_stdout.unwrap();
-                    let mut passwd = String::new();
-                    let _ = child_stdout.read_to_string(&mut passwd);
-
-                    let mut passwd = passwd.trim().to_string();
-
-                    // close the socket, if it exists
-                    let _ = fs::remove_file(get_socket_path(&socket_root, &account.label));
-
-                    let account = Account {
-                        label: account.label,
-                        username: account.username,
-                        passwordeval: account.passwordeval,
-                        host: account.host,
-                        port: account.port,
-                        tls: account.tls,
-                        default: account.default,
-                        password: Some(account.vault.encrypt(&mut passwd)),
-                        vault: account.vault,
-                        cert_root: account.cert_root,
-                        timeout: account.timeout,
+            let account = match unlock_account(account, &socket_root) {
+                Some(account) => account,
+                None          => return,
+            };
+
+            match client {
+                Some(client) => {
+                    let password = match &account.password {
+                        Some(password) => password,
+                        None           => {
+                            error!("{} needs passwordeval to use an external client",
+                                   account.label);
+                            return;
+                        }
                     };
-
-                    match client {
-                        Some(client) => {
-                            let external_client = ExternalClient::new(&client);
-                            external_client.start(&account.label,
-                                                  &socket_root,
-                                                  &account.vault,
-                                                  &passwd.into_bytes());
-                        },
-                        None         => {
-                            let default_client = DefaultClient::new(account);
-                            default_client.start(&socket_root, &default_client.account.vault);
-
-                        },
-                    }
-                }
+                    let external_client = ExternalClient::new(&client);
+                    external_client.start(&account.label,
+                                          &socket_root,
+                                          account.max_concurrent_connections,
+                                          &account.vault,
+                                          password);
+                },
+                None         => {
+                    let default_client = DefaultClient::new(account);
+                    default_client.start(&socket_root, &default_client.account.vault);
+
+                },
             }
         }));
     }
@@ -136,23 +105,115 @@ fn start_daemon(conf: Configuration) -> Vec<JoinHandle<()>> {
     children
 }
 
+/// Serves every account on a single runtime, instead of a thread each
+fn start_async_daemon(conf: Configuration) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let runtime = match Builder::new_multi_thread().enable_all().build() {
+            Ok(runtime) => runtime,
+            Err(error)  => {
+                error!("Cannot start the asynchronous runtime: {}", error);
+                return;
+            },
+        };
+
+        let socket_root = conf.socket_root;
+        let clients: Vec<Arc<AsyncClient>> = conf.accounts.into_iter()
+            .filter_map(|account| unlock_account(account, &socket_root))
+            .map(|account| Arc::new(AsyncClient::new(account)))
+            .collect();
+
+        runtime.block_on(async move {
+            let tasks: Vec<_> = clients.into_iter()
+                .map(|client| tokio::spawn(client.start(socket_root.clone())))
+                .collect();
+            for task in tasks {
+                let _ = task.await;
+            }
+        });
+    })
+}
+
+/// Starts the SMTP and HTTP listeners of the daemon, which serve every
+/// account, and the SMTP listeners of the accounts
+fn start_listeners(conf: &Configuration, bouncer: Option<Bouncer>) -> Vec<JoinHandle<()>> {
+    let handoff = Handoff {
+        socket_root: conf.socket_root.clone(),
+        flock_root: conf.flock_root.clone(),
+        spool_root: conf.spool_root.clone(),
+        timeout: Duration::from_secs(conf.timeout),
+    };
+    let route = |account: &Account| Route {
+        label: account.label.clone(),
+        username: account.username.clone(),
+        default: account.default,
+    };
+
+    let mut listeners = vec![];
+    if let Some(addr) = conf.listen {
+        let routes = conf.accounts.iter().map(route).collect();
+        listeners.push(Listener::new(Routes::new(routes), handoff.clone(), bouncer.clone())
+                       .start(addr));
+    }
+    if let Some(addr) = conf.http_listen {
+        let routes = conf.accounts.iter().map(route).collect();
+        listeners.push(HttpListener::new(Routes::new(routes), handoff.clone()).start(addr));
+    }
+    for account in &conf.accounts {
+        if let Some(addr) = account.listen {
+            listeners.push(Listener::new(Routes::new(vec![route(account)]), handoff.clone(),
+                                         bouncer.clone())
+                           .start(addr));
+        }
+    }
+    listeners
+}
+
 fn main() {
     log4rs::init_file(format!("{}/.rusmtp/rusmtpd-log4rs.yaml",
           home_dir().expect("Cannot find the home directory").display()),
           Default::default()).unwrap();
 
-    let args = process_args("rusmtpd", &rusmtpd_usage("rusmtpd"));
-    let conf = read_config(&args.flag_rusmtprc);
+    let args: Args = process_args("rusmtpd", &rusmtpd_usage("rusmtpd"));
+    let conf = read_config(&args.flag_rusmtprc).unwrap_or_else(|e| {
+        error!("{}", e);
+        eprintln!("rusmtpd: {}", e);
+        exit(EX_CONFIG)
+    });
 
     info!("rusmtpd started");
 
     print_welcome_message();
-    let resender = start_resender(conf.spool_root.clone(),
-                                  conf.flock_root.clone(),
-                                  conf.socket_root.clone(), conf.timeout);
-    let senders = start_daemon(conf);
+    let bouncer = conf.bounce.clone().map(|target| Bouncer {
+        target,
+        socket_root: conf.socket_root.clone(),
+        timeout: Duration::from_secs(conf.timeout),
+        usernames: conf.accounts.iter()
+            .filter_map(|account| account.username.clone()
+                        .map(|username| (account.label.clone(), username)))
+            .collect(),
+    });
+    let resender = Resender {
+        spool: Spool::new(&conf.spool_root),
+        flock_root: conf.flock_root.clone(),
+        socket_root: conf.socket_root.clone(),
+        timeout: Duration::from_secs(conf.timeout),
+        policy: conf.retry_policy,
+        bouncer: bouncer.clone(),
+    }.start();
+    let listeners = start_listeners(&conf, bouncer);
+    let senders = if conf.async_runtime && conf.smtpclient.is_none() {
+        vec![start_async_daemon(conf)]
+    } else {
+        if conf.async_runtime {
+            warn!("The external smtp client only runs on threads, ignoring runtime=async");
+        }
+        start_daemon(conf)
+    };
 
     let _ = resender.join();
+    for listener in listeners {
+        let _ = listener.join();
+    }
     for sender in senders {
         // Wait for the thread to finish. Returns a result.
         let _ = sender.join();
@@ -0,0 +1,368 @@
+// Lists and manages the spooled emails. Every change takes the flock of the
+// account of the email, like the resender does, so that the two never act
+// on the same email at the same time.
+
+use std::alloc::System;
+use std::process::exit;
+use common::Error;
+use common::args::*;
+use common::config::*;
+use common::message::date;
+use common::spool::{account_of, lock, now, Queue, Spool};
+use common::sysexits::*;
+use fs2::FileExt;
+
+#[global_allocator]
+static GLOBAL: System = System;
+
+/// Reports the failure in one line, and exits with the code
+fn fail<A>(code: i32, msg: &str) -> A {
+    eprintln!("rusmtpq: {}", msg);
+    exit(code)
+}
+
+/// The spool, and the flocks that guard it
+struct Queues {
+    spool: Spool,
+    flock_root: String,
+}
+
+impl Queues {
+    /// Changes the email while holding the flock of its account, after
+    /// looking up where it is now
+    fn change<T>(&self, id: &str, change: impl FnOnce(Queue) -> Result<T, Error>)
+            -> Result<T, Error> {
+        let account = account_of(id)
+            .unwrap_or_else(|| fail(EX_USAGE, &format!("{} is not the id of an email", id)));
+        let lock_file = lock(&self.flock_root, account)?;
+        let changed = match self.spool.locate(id) {
+            Some(queue) => change(queue),
+            None        => fail(EX_NOINPUT, &format!("There is no email {} in the spool", id)),
+        };
+        let _ = FileExt::unlock(&lock_file);
+        changed
+    }
+
+    fn list(&self) -> Result<(), Error> {
+        let now = now();
+        let mut rows = vec![["ID", "QUEUE", "ACCOUNT", "RECIPIENTS", "SIZE", "AGE", "ATTEMPTS",
+                             "LAST ERROR"].iter().map(|title| title.to_string()).collect()];
+        for &queue in &Queue::ALL {
+            for id in self.spool.ids(queue)?.0 {
+                let (recipients, size) = match self.spool.read(queue, &id) {
+                    Ok(mail)                                   =>
+                        (mail.recipients.join(","), size(mail.body.len() as u64)),
+                    // Sent or moved by the resender since it was listed
+                    Err(_) if self.spool.locate(&id).is_none() => continue,
+                    // Truncated, the metadata tells why
+                    Err(_)                                     => ("-".to_string(), "-".to_string()),
+                };
+                let metadata = self.spool.metadata_or_new(queue, &id);
+                rows.push(vec![
+                    id.clone(),
+                    queue.directory().to_string(),
+                    account_of(&id).unwrap_or_default().to_string(),
+                    recipients,
+                    size,
+                    age(now.saturating_sub(metadata.queued_at)),
+                    metadata.attempts.to_string(),
+                    metadata.last_error.unwrap_or_default(),
+                ]);
+            }
+        }
+        if rows.len() == 1 {
+            println!("The spool is empty");
+        } else {
+            print!("{}", table(&rows));
+        }
+        Ok(())
+    }
+
+    /// Reads the email without the flock, like list, so that it does not
+    /// wait for the daemon to finish sending
+    fn show(&self, id: &str) -> Result<(), Error> {
+        if account_of(id).is_none() {
+            fail::<()>(EX_USAGE, &format!("{} is not the id of an email", id));
+        }
+        let (mail, queue) = loop {
+            let queue = self.spool.locate(id).unwrap_or_else(||
+                fail(EX_NOINPUT, &format!("There is no email {} in the spool", id)));
+            match self.spool.read(queue, id) {
+                Ok(mail)                                       => break (mail, queue),
+                // Moved to another queue by the resender meanwhile
+                Err(_) if self.spool.locate(id) != Some(queue) => continue,
+                Err(error)                                     => return Err(error),
+            }
+        };
+        let metadata = self.spool.metadata_or_new(queue, id);
+        println!("Id:           {}", id);
+        println!("Queue:        {}", queue.directory());
+        println!("Account:      {}", account_of(id).unwrap_or_default());
+        println!("Recipients:   {}", mail.recipients.join(", "));
+        for (name, value) in &[("DSN notify:  ", &mail.dsn_notify), ("DSN ret:     ", &mail.dsn_ret),
+                               ("Envelope id: ", &mail.envid)] {
+            if let Some(value) = value {
+                println!("{} {}", name, value);
+            }
+        }
+        println!("Size:         {} bytes", mail.body.len());
+        println!("Queued at:    {}", date(metadata.queued_at));
+        println!("Attempts:     {}", metadata.attempts);
+        match queue {
+            Queue::New | Queue::Cur =>
+                println!("Next attempt: {}", date(metadata.next_attempt_at.max(now()))),
+            _                       => (),
+        }
+        if let Some(last_error) = &metadata.last_error {
+            println!("Last error:   {}", last_error);
+        }
+        println!();
+        print!("{}", headers(&mail.body));
+        Ok(())
+    }
+
+    fn delete(&self, id: &str) -> Result<(), Error> {
+        self.change(id, |queue| self.spool.remove(queue, id))?;
+        println!("Deleted {}", id);
+        Ok(())
+    }
+
+    /// Makes the email due now, and queues it again if it is held or failed
+    fn retry(&self, id: &str) -> Result<(), Error> {
+        self.change(id, |queue| {
+            let mut metadata = self.spool.metadata_or_new(queue, id);
+            metadata.next_attempt_at = now();
+            match queue {
+                Queue::New | Queue::Cur => self.spool.save_metadata(queue, id, &metadata),
+                _                       =>
+                    self.spool.move_to(id, queue, requeue(metadata.attempts), &metadata),
+            }
+        })?;
+        println!("Retrying {}", id);
+        Ok(())
+    }
+
+    /// Retries the queued emails, of the account if there is one
+    fn retry_queued(&self, account: Option<&str>) -> Result<(), Error> {
+        let mut retried = 0;
+        for &queue in &[Queue::New, Queue::Cur] {
+            for id in self.spool.ids(queue)?.0 {
+                if account.is_some() && account_of(&id) != account {
+                    continue;
+                }
+                let account = account_of(&id).unwrap_or_default();
+                let lock_file = lock(&self.flock_root, account)?;
+                // Unless the resender got to it first
+                let changed = if self.spool.locate(&id) == Some(queue) {
+                    let mut metadata = self.spool.metadata_or_new(queue, &id);
+                    metadata.next_attempt_at = now();
+                    self.spool.save_metadata(queue, &id, &metadata).map(|_| 1)
+                } else {
+                    Ok(0)
+                };
+                let _ = FileExt::unlock(&lock_file);
+                retried += changed?;
+            }
+        }
+        println!("Retrying {} emails", retried);
+        Ok(())
+    }
+
+    fn hold(&self, id: &str) -> Result<(), Error> {
+        self.change(id, |queue| match queue {
+            Queue::New | Queue::Cur => {
+                let metadata = self.spool.metadata_or_new(queue, id);
+                self.spool.move_to(id, queue, Queue::Held, &metadata)
+            },
+            Queue::Held             => Ok(()),
+            Queue::Failed           =>
+                fail(EX_USAGE, &format!("{} was given up on, retry it instead", id)),
+        })?;
+        println!("Holding {}", id);
+        Ok(())
+    }
+
+    fn release(&self, id: &str) -> Result<(), Error> {
+        self.change(id, |queue| match queue {
+            Queue::Held => {
+                let metadata = self.spool.metadata_or_new(queue, id);
+                self.spool.move_to(id, queue, requeue(metadata.attempts), &metadata)
+            },
+            _           => fail(EX_USAGE, &format!("{} is not held", id)),
+        })?;
+        println!("Released {}", id);
+        Ok(())
+    }
+}
+
+/// The queue of an email that is retried again, depending on whether the
+/// daemon tried it already
+fn requeue(attempts: u32) -> Queue {
+    if attempts == 0 { Queue::New } else { Queue::Cur }
+}
+
+/// The size in a short human readable form
+fn size(bytes: u64) -> String {
+    match bytes {
+        0..=1_023         => format!("{}B", bytes),
+        1_024..=1_048_575 => format!("{:.1}K", bytes as f64 / 1_024.0),
+        _                 => format!("{:.1}M", bytes as f64 / 1_048_576.0),
+    }
+}
+
+/// The age in its largest unit
+fn age(seconds: u64) -> String {
+    match seconds {
+        0..=59          => format!("{}s", seconds),
+        60..=3_599      => format!("{}m", seconds / 60),
+        3_600..=86_399  => format!("{}h", seconds / 3_600),
+        _               => format!("{}d", seconds / 86_400),
+    }
+}
+
+/// The rows with their columns aligned, the last one is left as it is
+fn table(rows: &[Vec<String>]) -> String {
+    let columns = rows[0].len();
+    let widths: Vec<usize> = (0..columns)
+        .map(|column| rows.iter().map(|row| row[column].chars().count()).max().unwrap_or(0))
+        .collect();
+    let mut table = String::new();
+    for row in rows {
+        let mut line = String::new();
+        for (column, cell) in row.iter().enumerate() {
+            if column + 1 == columns {
+                line.push_str(cell);
+            } else {
+                line.push_str(&format!("{:width$}  ", cell, width = widths[column]));
+            }
+        }
+        table.push_str(line.trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+/// The header section of the email
+fn headers(body: &[u8]) -> String {
+    let body = String::from_utf8_lossy(body);
+    let mut headers = String::new();
+    for line in body.lines() {
+        if line.is_empty() {
+            break;
+        }
+        headers.push_str(line);
+        headers.push('\n');
+    }
+    headers
+}
+
+fn main() {
+    let args: QueueArgs = process_args("rusmtpq", &rusmtpq_usage("rusmtpq"));
+    let conf = read_config(&args.flag_rusmtprc)
+        .unwrap_or_else(|e| fail(EX_CONFIG, &e.to_string()));
+    let queues = Queues {
+        spool: Spool::new(&conf.spool_root),
+        flock_root: conf.flock_root,
+    };
+
+    let id = args.arg_id.as_deref().unwrap_or_default();
+    let result = if args.cmd_list {
+        queues.list()
+    } else if args.cmd_show {
+        queues.show(id)
+    } else if args.cmd_delete {
+        queues.delete(id)
+    } else if args.cmd_retry && args.flag_all {
+        queues.retry_queued(None)
+    } else if args.cmd_retry {
+        queues.retry(id)
+    } else if args.cmd_hold {
+        queues.hold(id)
+    } else if args.cmd_release {
+        queues.release(id)
+    } else if args.cmd_flush {
+        queues.retry_queued(args.flag_account.as_deref())
+    } else {
+        unreachable!("The usage has no other command")
+    };
+    if let Err(error) = result {
+        fail::<()>(EX_IOERR, &error.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::mail::Mail;
+    use common::spool::Metadata;
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::process;
+
+    #[test]
+    fn test_size_and_age() {
+        assert_eq!("512B", size(512));
+        assert_eq!("1.5K", size(1_536));
+        assert_eq!("2.0M", size(2 * 1_048_576));
+        assert_eq!("59s", age(59));
+        assert_eq!("2m", age(150));
+        assert_eq!("5h", age(5 * 3_600 + 59));
+        assert_eq!("3d", age(3 * 86_400));
+    }
+
+    #[test]
+    fn test_table() {
+        let rows = vec![vec!["ID".to_string(), "QUEUE".to_string(), "LAST ERROR".to_string()],
+                        vec!["long-id".to_string(), "new".to_string(), String::new()]];
+        assert_eq!("ID       QUEUE  LAST ERROR\nlong-id  new\n", table(&rows));
+    }
+
+    #[test]
+    fn test_headers() {
+        assert_eq!("Subject: hi\nFrom: me@example.com\n",
+                   headers(b"Subject: hi\r\nFrom: me@example.com\r\n\r\nbody\r\n"));
+    }
+
+    #[test]
+    fn test_hold_release_and_retry() {
+        let root = temp_dir().join(format!("rusmtp-rusmtpq-{}", process::id()));
+        let _ = remove_dir_all(&root);
+        create_dir_all(&root).unwrap();
+        let root = root.display().to_string();
+        let queues = Queues {
+            spool: Spool::new(&root),
+            flock_root: root.clone(),
+        };
+        let mail = Mail {
+            account: Some("first".to_string()),
+            recipients: vec!["a@b.c".to_string()],
+            dsn_notify: None,
+            dsn_ret: None,
+            envid: None,
+            body: b"hi".to_vec(),
+        };
+        let mut metadata = Metadata::failed("Connection refused", None);
+        metadata.next_attempt_at += 600;
+        let id = queues.spool.enqueue(&mail, "first", &metadata).unwrap();
+        let other = queues.spool.enqueue(&mail, "second", &metadata).unwrap();
+
+        queues.hold(&id).unwrap();
+        assert_eq!(Some(Queue::Held), queues.spool.locate(&id));
+        queues.release(&id).unwrap();
+        assert_eq!(Some(Queue::Cur), queues.spool.locate(&id));
+
+        queues.retry_queued(Some("first")).unwrap();
+        assert!(queues.spool.metadata(Queue::Cur, &id).unwrap().unwrap().next_attempt_at <= now());
+        assert_eq!(Some(metadata.clone()), queues.spool.metadata(Queue::New, &other).unwrap());
+
+        queues.spool.fail(Queue::New, &other, &metadata).unwrap();
+        queues.retry(&other).unwrap();
+        assert_eq!(Some(Queue::Cur), queues.spool.locate(&other));
+        assert!(queues.spool.metadata(Queue::Cur, &other).unwrap().unwrap().next_attempt_at
+                <= now());
+
+        queues.delete(&other).unwrap();
+        assert_eq!(None, queues.spool.locate(&other));
+        let _ = remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,59 @@
+// The mailbox addresses of the envelope, and their ASCII forms for the
+// servers that do not support SMTPUTF8 (RFC 6531).
+
+use crate::error::Error;
+
+/// Checks that the address can be put in MAIL FROM or RCPT TO as it is
+pub(crate) fn check(address: &str) -> Result<(), Error> {
+    let valid = match address.rsplit_once('@') {
+        Some((local, domain)) => !local.is_empty() && !domain.is_empty(),
+        None                  => false,
+    };
+    if !valid || address.chars().any(|c| c.is_control() || c.is_whitespace()
+                                         || c == '<' || c == '>') {
+        return Err(Error::Protocol(format!("Invalid email address: {:?}", address)));
+    }
+    Ok(())
+}
+
+/// Converts the domain of the address to punycode. Non-ASCII mailbox names
+/// have no ASCII form, and so cannot be delivered without SMTPUTF8.
+pub(crate) fn to_ascii(address: &str) -> Result<String, Error> {
+    if address.is_ascii() {
+        return Ok(address.to_string());
+    }
+
+    let (local, domain) = address.rsplit_once('@')
+        .ok_or_else(|| Error::Protocol(format!("Invalid email address: {:?}", address)))?;
+    if !local.is_ascii() {
+        return Err(Error::Protocol(format!("Cannot send email to {}, the server does not \
+                                            support SMTPUTF8 for non-ASCII mailbox names",
+                                           address)));
+    }
+
+    let domain = idna::domain_to_ascii(domain)
+        .map_err(|e| Error::Protocol(format!("Invalid domain name in {}: {:?}", address, e)))?;
+    Ok(format!("{}@{}", local, domain))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        assert!(check("me@example.com").is_ok());
+        assert!(check("j\u{f6}rg@b\u{fc}cher.de").is_ok());
+        assert!(check("example.com").is_err());
+        assert!(check("@example.com").is_err());
+        assert!(check("me@example.com> NOTIFY=NEVER").is_err());
+        assert!(check("me@example.com\r\nRSET").is_err());
+    }
+
+    #[test]
+    fn test_to_ascii() {
+        assert_eq!(Ok("me@example.com".to_string()), to_ascii("me@example.com"));
+        assert_eq!(Ok("me@xn--bcher-kva.de".to_string()), to_ascii("me@b\u{fc}cher.de"));
+        assert!(to_ascii("j\u{f6}rg@example.com").is_err());
+    }
+}
@@ -0,0 +1,705 @@
+// The asynchronous counterpart of Raven, over the tokio streams, so that a
+// single runtime can keep the connections of many accounts without a thread
+// for each of them. The commands are exactly the ones that Raven sends.
+
+use crate::verbs::*;
+use crate::{reply, sasl, Authentication, Capabilities, Delivery, Dsn, Error, Reply, CHUNK_SIZE,
+            MAX_REPLY_LINE_LENGTH, decode_challenge, oauthbearer_response, parse_ehlo,
+            scram_unverified, scram_verified, tls_connector, tls_error, xoauth2_response};
+use crate::envelope::{Envelope, Transfer, recipient_replies};
+use crate::transparency::{DataEncoder, crlf_line_endings};
+use base64::encode;
+use std::future::Future;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite,
+                AsyncWriteExt, BufReader, ReadBuf};
+use tokio::net::{TcpStream, lookup_host};
+use tokio_native_tls::{TlsConnector, TlsStream};
+
+/// A tokio stream, along with how long the server is waited for on it. Like
+/// BufStream, the reads go through a buffer that lives as long as the
+/// connection.
+pub struct AsyncStream<S> {
+    inner: BufReader<S>,
+    timeout: Duration,
+}
+
+impl<S: AsyncRead> AsyncStream<S> {
+    pub fn new(inner: S, timeout: Duration) -> Self {
+        AsyncStream { inner: BufReader::new(inner), timeout }
+    }
+
+    /// Whether the server sent anything that was not read yet
+    fn has_unread_data(&self) -> bool {
+        !self.inner.buffer().is_empty()
+    }
+
+    fn into_inner(self) -> S {
+        self.inner.into_inner()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for AsyncStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>)
+            -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncBufRead for AsyncStream<S> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().inner).poll_fill_buf(cx)
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.get_mut().inner).consume(amt)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for AsyncStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8])
+            -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+pub type AsyncTcpStream = AsyncStream<TcpStream>;
+pub type AsyncTlsStream = AsyncStream<TlsStream<TcpStream>>;
+
+/// The streams are only used with concrete types, whose futures are Send
+/// whenever the streams are
+#[allow(async_fn_in_trait)]
+pub trait AsyncRaven: AsyncBufRead + AsyncWrite + Unpin + Send + Sized {
+
+    fn create_connection(host: &str, port: u16, timeout: Duration, cert_root: Option<String>)
+        -> impl Future<Output = Result<Self, Error>> + Send;
+
+    /// How long a reply of the server is waited for
+    fn timeout(&self) -> Duration;
+
+    async fn close(&mut self) {
+        let _ = self.shutdown().await;
+    }
+
+    async fn send_hello(&mut self, host: &str) -> Result<Reply, Error> {
+        debug!("Shaking hands with the ESMTP server");
+        self.send_or_err(
+            format!("{} rusmtp.amanj.me\r\n", EHLO).as_bytes(),
+            &|reply| reply.is_positive_completion(),
+            &format!("SMTP Server {} does not support ESMTP", host)).await
+    }
+
+    async fn hand_shake(&mut self, host: &str) -> Result<Capabilities, Error> {
+        let reply = self.recieve().await?;
+        debug!("{}", &reply);
+
+        debug!("Checking the presence of ESMTP protocol");
+        if reply.is_positive_completion() {
+            self.ehlo(host).await
+        } else {
+            Err(Error::reply("Bad reply from server", &reply))
+        }
+    }
+
+    async fn ehlo(&mut self, host: &str) -> Result<Capabilities, Error> {
+        let reply = self.send_hello(host).await?;
+        let capabilities = parse_ehlo(&reply);
+        debug!("{:?}", capabilities);
+        Ok(capabilities)
+    }
+
+    async fn authenticate_with_password(&mut self, auth: Authentication, username: &str,
+                                        passwd: &[u8]) -> Result<Reply, Error> {
+       match auth {
+           Authentication::Login       =>
+               self.authenticate_with_login(username.as_bytes(), passwd).await,
+           Authentication::Plain       => self.authenticate_with_plain(username, passwd).await,
+           Authentication::CramMd5     => self.authenticate_with_cram_md5(username, passwd).await,
+           Authentication::ScramSha256 =>
+               self.authenticate_with_scram_sha256(username, passwd).await,
+           auth                        =>
+               Err(Error::Config(format!("{:?} does not authenticate with a password", auth))),
+       }
+    }
+
+    async fn authenticate_with_login(&mut self, username: &[u8], passwd: &[u8])
+            -> Result<Reply, Error> {
+       let _ = self.send_or_err(format!("{} {}\r\n", AUTH, LOGIN).as_bytes(),
+           &|reply| reply.is_positive_intermediate(),
+           "The server refused to start LOGIN authentication").await?;
+       let _ = self.send_or_err(format!("{}\r\n", encode(username)).as_bytes(),
+           &|reply| reply.is_positive_intermediate(),
+           "Invalid username").await.map_err(Error::into_auth)?;
+       self.send_or_err(format!("{}\r\n", encode(passwd)).as_bytes(),
+           &|reply| reply.is_positive_completion(),
+           "Invalid username or password").await.map_err(Error::into_auth)
+    }
+
+    async fn authenticate_with_plain(&mut self, username: &str, passwd: &[u8])
+            -> Result<Reply, Error> {
+       debug!("Authenticating with PLAIN");
+       let mut credentials = vec![0u8];
+       credentials.extend_from_slice(username.as_bytes());
+       credentials.push(0);
+       credentials.extend_from_slice(passwd);
+       self.send_or_err(format!("{} {} {}\r\n", AUTH, PLAIN, encode(&credentials)).as_bytes(),
+           &|reply| reply.is_positive_completion(),
+           "Invalid username or password").await.map_err(Error::into_auth)
+    }
+
+    async fn authenticate_with_cram_md5(&mut self, username: &str, passwd: &[u8])
+            -> Result<Reply, Error> {
+       debug!("Authenticating with CRAM-MD5");
+       let challenge = self.send_or_err(format!("{} {}\r\n", AUTH, CRAM_MD5).as_bytes(),
+           &|reply| reply.is_positive_intermediate(),
+           "The server refused to start CRAM-MD5 authentication").await?;
+       let challenge = decode_challenge(&challenge)?;
+       let response = sasl::cram_md5_response(username, passwd, &challenge);
+       self.send_or_err(format!("{}\r\n", encode(&response)).as_bytes(),
+           &|reply| reply.is_positive_completion(),
+           "Invalid username or password").await.map_err(Error::into_auth)
+    }
+
+    async fn authenticate_with_scram_sha256(&mut self, username: &str, passwd: &[u8])
+            -> Result<Reply, Error> {
+       debug!("Authenticating with SCRAM-SHA-256");
+       let client_first_bare = sasl::scram_client_first_bare(username, &sasl::scram_nonce()?);
+       let server_first = self.send_or_err(
+           format!("{} {} {}\r\n", AUTH, SCRAM_SHA_256,
+                   encode(&format!("n,,{}", client_first_bare))).as_bytes(),
+           &|reply| reply.is_positive_intermediate(),
+           "The server refused to start SCRAM-SHA-256 authentication").await?;
+       let server_first = String::from_utf8(decode_challenge(&server_first)?)
+           .map_err(|_| Error::Protocol("The server sent an invalid SCRAM challenge".to_string()))?;
+
+       let proof = match sasl::scram_client_final(&client_first_bare, &server_first, passwd) {
+           Ok(proof)  => proof,
+           Err(error) => {
+               // Cancel the exchange, RFC 4954 section 4
+               let _ = self.recieve_after(b"*\r\n").await;
+               return Err(error);
+           },
+       };
+
+       let server_final = self.send_or_err(
+           format!("{}\r\n", encode(&proof.client_final)).as_bytes(),
+           &|reply| reply.is_positive_intermediate() || reply.is_positive_completion(),
+           "Invalid username or password").await.map_err(Error::into_auth)?;
+
+       let verified = scram_verified(&server_final, &proof.server_signature);
+       if server_final.is_positive_intermediate() {
+           if !verified {
+               let _ = self.recieve_after(b"*\r\n").await;
+               return Err(scram_unverified());
+           }
+           self.send_or_err(b"\r\n",
+               &|reply| reply.is_positive_completion(),
+               "Invalid username or password").await.map_err(Error::into_auth)
+       } else if verified {
+           Ok(server_final)
+       } else {
+           Err(scram_unverified())
+       }
+    }
+
+    async fn authenticate_with_xoauth2(&mut self, username: &str, token: &str)
+            -> Result<Reply, Error> {
+       debug!("Authenticating with XOAUTH2");
+       let response = format!("{} {} {}\r\n", AUTH, XOAUTH2,
+                              encode(&xoauth2_response(username, token)));
+       self.send_or_err_with_sasl_error(response.as_bytes(), b"\r\n",
+           "Invalid username or access token").await
+    }
+
+    async fn authenticate_with_oauthbearer(&mut self, username: &str, host: &str, port: u16,
+                                           token: &str) -> Result<Reply, Error> {
+       debug!("Authenticating with OAUTHBEARER");
+       let response = format!("{} {} {}\r\n", AUTH, OAUTHBEARER,
+                              encode(&oauthbearer_response(username, host, port, token)));
+       self.send_or_err_with_sasl_error(response.as_bytes(), b"AQ==\r\n",
+           "Invalid username or access token").await
+    }
+
+    async fn send_or_err_with_sasl_error(&mut self, msg: &[u8], acknowledgement: &[u8],
+                                         on_failure_msg: &str) -> Result<Reply, Error> {
+       let reply = self.recieve_after(msg).await?;
+       if reply.is_positive_completion() {
+           Ok(reply)
+       } else if reply.is_positive_intermediate() {
+           let reply = self.recieve_after(acknowledgement).await?;
+           Err(Error::reply(on_failure_msg, &reply).into_auth())
+       } else {
+           Err(Error::reply(on_failure_msg, &reply).into_auth())
+       }
+    }
+
+    /// Sends the email the same way as Raven::send_mail
+    async fn send_mail(&mut self, capabilities: &Capabilities, from: &str, recipients: &[&str],
+                       dsn: &Dsn, body: &[u8]) -> Result<Delivery, Error> {
+       let envelope = Envelope::new(capabilities, from, recipients, dsn, body)?;
+
+       let replies = if capabilities.pipelining {
+           self.send_envelope_pipelined(&envelope).await?
+       } else {
+           self.send_envelope(&envelope).await?
+       };
+       let recipients = recipient_replies(recipients, replies);
+
+       if !recipients.iter().any(|recipient| recipient.is_accepted()) {
+           let _ = self.recieve_after(format!("{}\r\n", RSET).as_bytes()).await;
+           return Ok(Delivery { recipients, reply: None });
+       }
+
+       let reply = match envelope.transfer {
+           Transfer::Binary => self.send_chunks(body).await?,
+           Transfer::Chunks => self.send_chunks(&crlf_line_endings(body)).await?,
+           Transfer::Data   => self.send_data(body).await?,
+       };
+       Ok(Delivery { recipients, reply: Some(reply) })
+    }
+
+    async fn reset(&mut self) -> Result<Reply, Error> {
+       self.send_or_err(format!("{}\r\n", RSET).as_bytes(),
+              &|reply| reply.is_positive_completion(),
+              "Cannot reset the mail transaction").await
+    }
+
+    async fn noop(&mut self) -> Result<Reply, Error> {
+       self.send_or_err(format!("{}\r\n", NOOP).as_bytes(),
+              &|reply| reply.is_positive_completion(),
+              "The connection is not usable anymore").await
+    }
+
+    async fn quit(&mut self) -> Result<Reply, Error> {
+       let reply = self.send_or_err(format!("{}\r\n", QUIT).as_bytes(),
+              &|reply| reply.is_positive_completion(),
+              "The server did not close the session cleanly").await;
+       self.close().await;
+       reply
+    }
+
+    async fn send_data(&mut self, body: &[u8]) -> Result<Reply, Error> {
+       let _ = self.send_or_err(format!("{}\r\n", DATA).as_bytes(),
+              &|reply| reply.is_positive_intermediate(),
+              "Cannot start sending email").await?;
+
+       // The message is encoded in memory, as the encoder only writes to
+       // blocking writers
+       let mut encoder = DataEncoder::new(Vec::with_capacity(body.len()));
+       let encoded = encoder.write_all(body)
+           .and_then(|_| encoder.finish())
+           .map_err(|e| Error::Protocol(format!("Failed to send email: {}", e)))?;
+       self.write_all(&encoded).await
+           .map_err(|e| Error::Io(format!("Failed to send email: {}", e)))?;
+
+       let reply = self.recieve().await?;
+       debug!("{}", &reply);
+       if reply.is_positive_completion() {
+           Ok(reply)
+       } else {
+           Err(Error::reply("Failed to send email", &reply))
+       }
+    }
+
+    async fn send_chunks(&mut self, body: &[u8]) -> Result<Reply, Error> {
+       let chunks: Vec<&[u8]> = if body.is_empty() {
+           vec![body]
+       } else {
+           body.chunks(CHUNK_SIZE).collect()
+       };
+
+       let count = chunks.len();
+       for (index, chunk) in chunks.into_iter().enumerate() {
+           let last = index + 1 == count;
+           if last {
+               self.send(format!("{} {} {}\r\n", BDAT, chunk.len(), LAST).as_bytes()).await;
+           } else {
+               self.send(format!("{} {}\r\n", BDAT, chunk.len()).as_bytes()).await;
+           }
+           self.send(chunk).await;
+
+           let reply = self.recieve().await?;
+           debug!("{}", &reply);
+           if !reply.is_positive_completion() {
+               return Err(Error::reply("Failed to send email", &reply));
+           }
+           if last {
+               return Ok(reply);
+           }
+       }
+       unreachable!("There is always a last chunk")
+    }
+
+    async fn send_envelope(&mut self, envelope: &Envelope) -> Result<Vec<Reply>, Error> {
+       let _ = self.send_or_err(envelope.mail_from.as_bytes(),
+           &|reply| reply.is_positive_completion(),
+           &format!("Cannot send email from {}", envelope.from)).await?;
+
+       let mut replies = Vec::new();
+       for (recipient, rcpt_to) in envelope.recipients.iter().zip(&envelope.rcpt_to) {
+          let reply = self.recieve_after(rcpt_to.as_bytes()).await?;
+          if !reply.is_positive_completion() {
+              warn!("Cannot send email to {}: {}", recipient, reply);
+          }
+          replies.push(reply);
+       }
+       Ok(replies)
+    }
+
+    async fn send_envelope_pipelined(&mut self, envelope: &Envelope)
+            -> Result<Vec<Reply>, Error> {
+       debug!("Pipelining the envelope of {} recipients", envelope.recipients.len());
+       let batch = envelope.rcpt_to.concat();
+       self.send(format!("{}{}", envelope.mail_from, batch).as_bytes()).await;
+
+       let mail_reply = self.recieve().await?;
+       debug!("{}", &mail_reply);
+
+       let mut replies = Vec::new();
+       for recipient in envelope.recipients.iter() {
+           let reply = self.recieve().await?;
+           debug!("{}", &reply);
+           if !reply.is_positive_completion() {
+               warn!("Cannot send email to {}: {}", recipient, reply);
+           }
+           replies.push(reply);
+       }
+
+       if mail_reply.is_positive_completion() {
+           Ok(replies)
+       } else {
+           let _ = self.recieve_after(format!("{}\r\n", RSET).as_bytes()).await;
+           Err(Error::reply(&format!("Cannot send email from {}", envelope.from), &mail_reply))
+       }
+    }
+
+    async fn send_or_err(&mut self, msg: &[u8],
+                         check: &(dyn Fn(&Reply) -> bool + Sync),
+                         on_failure_msg: &str) -> Result<Reply, Error> {
+       let reply = self.recieve_after(msg).await?;
+       if check(&reply) {
+           Ok(reply)
+       } else {
+           Err(Error::reply(on_failure_msg, &reply))
+       }
+    }
+
+    async fn recieve_after(&mut self, msg: &[u8]) -> Result<Reply, Error> {
+       self.send(msg).await;
+       let reply = self.recieve().await?;
+       debug!("{}", &reply);
+       Ok(reply)
+    }
+
+    /// Reads a complete reply, giving up if the server does not send it
+    /// in time
+    async fn recieve(&mut self) -> Result<Reply, Error> {
+        let timeout = self.timeout();
+        tokio::time::timeout(timeout, async {
+            let mut lines = Vec::new();
+            loop {
+                let line = self.read_reply_line().await?;
+                let (_, last, _) = reply::parse_line(&line)?;
+                lines.push(line);
+                if last {
+                    break;
+                }
+            }
+
+            let lines: Vec<&str> = lines.iter().map(|line| line.as_str()).collect();
+            Reply::parse(&lines)
+        }).await
+            .map_err(|_| Error::Io(format!("The server did not reply in {:?}", timeout)))?
+    }
+
+    async fn read_reply_line(&mut self) -> Result<String, Error> {
+        let mut line = Vec::new();
+        let limit = MAX_REPLY_LINE_LENGTH as u64 + 1;
+        if let Err(e) = (&mut *self).take(limit).read_until(b'\n', &mut line).await {
+            return Err(Error::Io(format!("Cannot read the reply of the server: {}", e)));
+        }
+
+        if line.last() == Some(&b'\n') {
+            line.pop();
+        } else if line.len() as u64 == limit {
+            return Err(Error::Protocol("The reply of the server is too long".to_string()));
+        } else {
+            return Err(Error::Io("The server closed the connection".to_string()));
+        }
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+
+        String::from_utf8(line)
+            .map_err(|_| Error::Protocol("Cannot decode SMTP server's resposne".to_string()))
+    }
+
+    async fn send(&mut self, msg: &[u8]) {
+        let _ = self.write_all(msg).await;
+    }
+}
+
+/// The asynchronous counterpart of StartTls
+#[allow(async_fn_in_trait)]
+pub trait AsyncStartTls: AsyncRaven {
+    async fn start_tls(self, host: &str, cert_root: Option<String>)
+        -> Result<AsyncTlsStream, Error>;
+}
+
+impl AsyncStartTls for AsyncTcpStream {
+    async fn start_tls(mut self, host: &str, cert_root: Option<String>)
+            -> Result<AsyncTlsStream, Error> {
+        debug!("Checking if TLS is supported");
+        let _ = self.send_or_err(
+            format!("{}\r\n", STARTTLS).as_bytes(),
+            &|reply| reply.is_positive_completion(),
+            "Cannot start a TLS connection").await?;
+        // Whatever follows the reply was sent in plain text, RFC 3207
+        // section 6
+        if self.has_unread_data() {
+            return Err(Error::Tls(format!(
+                "{} sent more than the reply to STARTTLS, refusing to upgrade", host)));
+        }
+
+        let connector = TlsConnector::from(tls_connector(host, cert_root)?);
+
+        debug!("Upgrading the connection with {} to TLS", host);
+        let timeout = self.timeout;
+        let stream = tls_handshake(&connector, host, self.into_inner(), timeout).await?;
+        Ok(AsyncStream::new(stream, timeout))
+    }
+}
+
+impl AsyncRaven for AsyncTcpStream {
+    async fn create_connection(host: &str, port: u16, timeout: Duration,
+                               _cert_root: Option<String>) -> Result<Self, Error> {
+        debug!("Openning connection with {}", host);
+        let address = lookup_host((host, port)).await.ok()
+            .and_then(|mut addresses| addresses.next())
+            .ok_or_else(|| Error::Dns(format!("Cannot resolve the host {}", host)))?;
+
+        match tokio::time::timeout(timeout, TcpStream::connect(address)).await {
+            Ok(Ok(stream)) => Ok(AsyncStream::new(stream, timeout)),
+            _              =>
+                Err(Error::Io(format!("Cannot establish TCP connection with {}", host))),
+        }
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+impl AsyncRaven for AsyncTlsStream {
+    async fn create_connection(host: &str, port: u16, timeout: Duration,
+                               cert_root: Option<String>) -> Result<Self, Error> {
+        debug!("Securing connection with {} on port {}", host, port);
+        let connector = TlsConnector::from(tls_connector(host, cert_root.clone())?);
+        let stream = AsyncTcpStream::create_connection(host, port, timeout, cert_root).await?;
+
+        debug!("Establishing TLS connection with {}", host);
+        let stream = tls_handshake(&connector, host, stream.into_inner(), timeout).await?;
+        Ok(AsyncStream::new(stream, timeout))
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+/// Establishes TLS with the host over the stream, waiting for it at most
+/// the timeout, like any read
+async fn tls_handshake(connector: &TlsConnector, host: &str, stream: TcpStream,
+                       timeout: Duration) -> Result<TlsStream<TcpStream>, Error> {
+    match tokio::time::timeout(timeout, connector.connect(host, stream)).await {
+        Ok(stream) => stream.map_err(|e| tls_error(host, &e)),
+        Err(_)     =>
+            Err(Error::Io(format!("Establishing TLS connection with {} timed out", host))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use tokio::io::DuplexStream;
+
+    /// Serves each scripted server reply from its own `poll_read` calls
+    struct MockStream {
+        replies: VecDeque<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl MockStream {
+        fn new(replies: &[&[u8]]) -> Self {
+            MockStream {
+                replies: replies.iter().map(|reply| reply.to_vec()).collect(),
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl AsyncRead for MockStream {
+        fn poll_read(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>)
+                -> Poll<io::Result<()>> {
+            let stream = self.get_mut();
+            if let Some(reply) = stream.replies.front_mut() {
+                let n = std::cmp::min(buf.remaining(), reply.len());
+                buf.put_slice(&reply[..n]);
+                reply.drain(..n);
+                if reply.is_empty() {
+                    stream.replies.pop_front();
+                }
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncBufRead for MockStream {
+        fn poll_fill_buf(self: Pin<&mut Self>, _cx: &mut Context<'_>)
+                -> Poll<io::Result<&[u8]>> {
+            let stream = self.get_mut();
+            Poll::Ready(Ok(stream.replies.front().map_or(&[], |reply| reply.as_slice())))
+        }
+
+        fn consume(self: Pin<&mut Self>, amt: usize) {
+            let stream = self.get_mut();
+            if let Some(reply) = stream.replies.front_mut() {
+                reply.drain(..amt);
+                if reply.is_empty() {
+                    stream.replies.pop_front();
+                }
+            }
+        }
+    }
+
+    impl AsyncWrite for MockStream {
+        fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8])
+                -> Poll<io::Result<usize>> {
+            self.get_mut().output.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>)
+                -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncRaven for MockStream {
+        async fn create_connection(_host: &str, _port: u16, _timeout: Duration,
+                                   _cert_root: Option<String>) -> Result<Self, Error> {
+            Err(Error::Io("Mock streams cannot connect".to_string()))
+        }
+
+        fn timeout(&self) -> Duration {
+            Duration::from_secs(1)
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn test_hand_shake_and_authentication() {
+        let mut stream = MockStream::new(&[
+            b"220 smtp.example.com ready\r\n",
+            b"250-smtp.example.com\r\n250 AUTH PLAIN\r\n",
+            b"235 2.7.0 Authentication successful\r\n"]);
+        block_on(async {
+            let capabilities = stream.hand_shake("smtp.example.com").await.unwrap();
+            assert_eq!(vec![Authentication::Plain], capabilities.auths);
+            assert!(stream.authenticate_with_password(
+                Authentication::Plain, "me", b"secret").await.is_ok());
+        });
+        assert_eq!(b"EHLO rusmtp.amanj.me\r\nAUTH PLAIN AG1lAHNlY3JldA==\r\n".to_vec(),
+                   stream.output);
+    }
+
+    #[test]
+    fn test_rejected_password() {
+        let mut stream = MockStream::new(&[b"535 5.7.8 Authentication credentials invalid\r\n"]);
+        match block_on(stream.authenticate_with_password(Authentication::Plain, "me", b"wrong")) {
+            Err(Error::Auth(_)) => (),
+            result              => panic!("{:?}", result),
+        }
+
+        let mut stream = MockStream::new(&[b"334 PDE4OTYuNjk3MTcwOTUyQHBvc3RvZmZpY2U+\r\n",
+                                           b"535 5.7.8 Authentication credentials invalid\r\n"]);
+        match block_on(stream.authenticate_with_password(Authentication::CramMd5, "me", b"wrong")) {
+            Err(Error::Auth(_)) => (),
+            result              => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_send_mail_as_raven_does() {
+        let capabilities = Capabilities { pipelining: true, ..Capabilities::default() };
+        let mut stream = MockStream::new(&[
+            b"250 2.1.0 Ok\r\n", b"550 5.1.1 No such user\r\n", b"250 2.1.5 Ok\r\n",
+            b"354 Go ahead\r\n", b"250 2.0.0 Ok: queued as 4F1A2B\r\n", b"221 2.0.0 Bye\r\n"]);
+        let delivery = block_on(async {
+            let delivery = stream.send_mail(&capabilities, "me@example.com",
+                &["a@example.com", "b@example.com"], &Dsn::default(), b"hi\n.\n").await;
+            assert!(stream.quit().await.is_ok());
+            delivery
+        }).unwrap();
+
+        assert_eq!(vec!["a@example.com"],
+                   delivery.rejected().iter().map(|r| r.recipient.as_str())
+                       .collect::<Vec<&str>>());
+        assert_eq!(Some("4F1A2B".to_string()), delivery.reply.unwrap().queue_id());
+        assert_eq!(b"MAIL FROM:<me@example.com>\r\nRCPT TO:<a@example.com>\r\n\
+                     RCPT TO:<b@example.com>\r\nDATA\r\nhi\r\n..\r\n.\r\nQUIT\r\n".to_vec(),
+                   stream.output);
+    }
+
+    impl AsyncRaven for AsyncStream<DuplexStream> {
+        async fn create_connection(_host: &str, _port: u16, _timeout: Duration,
+                                   _cert_root: Option<String>) -> Result<Self, Error> {
+            Err(Error::Io("Duplex streams cannot connect".to_string()))
+        }
+
+        fn timeout(&self) -> Duration {
+            self.timeout
+        }
+    }
+
+    #[test]
+    fn test_replies_that_arrive_together() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut stream = AsyncStream::new(client, Duration::from_secs(1));
+        block_on(async {
+            server.write_all(b"250 2.0.0 Ok\r\n250-2.0.0 Ok\r\n250 2.0.0 Still ok\r\n")
+                .await.unwrap();
+            assert!(stream.noop().await.is_ok());
+            assert!(stream.has_unread_data());
+            assert_eq!(vec!["2.0.0 Ok", "2.0.0 Still ok"], stream.reset().await.unwrap().lines);
+            assert!(!stream.has_unread_data());
+        });
+    }
+
+    #[test]
+    fn test_closed_connection() {
+        let mut stream = MockStream::new(&[]);
+        assert_eq!(Err(Error::Io("The server closed the connection".to_string())),
+                   block_on(stream.noop()));
+    }
+}
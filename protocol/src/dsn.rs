@@ -0,0 +1,136 @@
+// The envelope parameters of Delivery Status Notifications, RFC 3461.
+
+use crate::error::Error;
+
+/// The notifications that are requested for an email
+#[derive(PartialEq, Debug, Default, Clone)]
+pub struct Dsn {
+    /// When the recipients should be notified about, e.g. SUCCESS,FAILURE
+    /// or NEVER
+    pub notify: Option<String>,
+    /// How much of the email is returned in the notification, HDRS or FULL
+    pub ret: Option<String>,
+    /// The identifier of the envelope that is sent back in the notifications
+    pub envid: Option<String>,
+}
+
+const NOTIFY_VALUES: [&str; 4] = ["SUCCESS", "FAILURE", "DELAY", "NEVER"];
+const MAX_ENVID_LENGTH: usize = 100;
+
+impl Dsn {
+    pub fn is_empty(&self) -> bool {
+        self.notify.is_none() && self.ret.is_none() && self.envid.is_none()
+    }
+
+    pub(crate) fn mail_parameters(&self) -> Vec<String> {
+        let mut parameters = Vec::new();
+        if let Some(ret) = &self.ret {
+            parameters.push(format!("RET={}", ret));
+        }
+        if let Some(envid) = &self.envid {
+            parameters.push(format!("ENVID={}", xtext(envid)));
+        }
+        parameters
+    }
+
+    pub(crate) fn rcpt_parameters(&self, recipient: &str) -> Vec<String> {
+        let mut parameters = Vec::new();
+        if let Some(notify) = &self.notify {
+            parameters.push(format!("NOTIFY={}", notify));
+        }
+        if !self.is_empty() {
+            parameters.push(format!("ORCPT=rfc822;{}", xtext(recipient)));
+        }
+        parameters
+    }
+}
+
+/// Validates and normalizes the value of NOTIFY, a comma separated list
+/// of SUCCESS, FAILURE and DELAY, or NEVER on its own
+pub fn parse_notify(value: &str) -> Result<String, Error> {
+    let mut values: Vec<String> = Vec::new();
+    for value in value.split(',').map(|v| v.trim().to_uppercase()) {
+        if !NOTIFY_VALUES.contains(&value.as_str()) {
+            return Err(Error::Protocol(format!("Unknown DSN notification: {}", value)));
+        }
+        if !values.contains(&value) {
+            values.push(value);
+        }
+    }
+
+    if values.len() > 1 && values.iter().any(|value| value == "NEVER") {
+        return Err(Error::Protocol(
+            "NEVER cannot be combined with other DSN notifications".to_string()));
+    }
+    Ok(values.join(","))
+}
+
+/// Validates and normalizes the value of RET, either HDRS or FULL
+pub fn parse_ret(value: &str) -> Result<String, Error> {
+    let value = value.trim().to_uppercase();
+    if value == "HDRS" || value == "FULL" {
+        Ok(value)
+    } else {
+        Err(Error::Protocol(
+            format!("The DSN return type should be either HDRS or FULL, not {}", value)))
+    }
+}
+
+/// Validates the value of ENVID, which is limited to printable ASCII
+pub fn parse_envid(value: &str) -> Result<String, Error> {
+    if value.is_empty() || value.len() > MAX_ENVID_LENGTH {
+        return Err(Error::Protocol(format!("The envelope id should be 1 to {} characters long",
+                                           MAX_ENVID_LENGTH)));
+    }
+    if !value.bytes().all(|byte| (b' '..=b'~').contains(&byte)) {
+        return Err(Error::Protocol(
+            "The envelope id should only contain printable ASCII".to_string()));
+    }
+    Ok(value.to_string())
+}
+
+/// Encodes the value as xtext, RFC 3461 section 4
+fn xtext(value: &str) -> String {
+    value.bytes().map(|byte| {
+        if (b'!'..=b'~').contains(&byte) && byte != b'+' && byte != b'=' {
+            (byte as char).to_string()
+        } else {
+            format!("+{:02X}", byte)
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_notify() {
+        assert_eq!(Ok("SUCCESS,FAILURE".to_string()), parse_notify("success, FAILURE,success"));
+        assert_eq!(Ok("NEVER".to_string()), parse_notify("never"));
+        assert!(parse_notify("NEVER,DELAY").is_err());
+        assert!(parse_notify("SOMETIMES").is_err());
+    }
+
+    #[test]
+    fn test_parse_ret_and_envid() {
+        assert_eq!(Ok("HDRS".to_string()), parse_ret("hdrs"));
+        assert!(parse_ret("BODY").is_err());
+        assert!(parse_envid("QQ314159").is_ok());
+        assert!(parse_envid("").is_err());
+        assert!(parse_envid("caf\u{e9}").is_err());
+    }
+
+    #[test]
+    fn test_parameters() {
+        let dsn = Dsn {
+            notify: Some("SUCCESS,FAILURE".to_string()),
+            ret: Some("HDRS".to_string()),
+            envid: Some("a b+c=d".to_string()),
+        };
+        assert_eq!(vec!["RET=HDRS", "ENVID=a+20b+2Bc+3Dd"], dsn.mail_parameters());
+        assert_eq!(vec!["NOTIFY=SUCCESS,FAILURE", "ORCPT=rfc822;me+2Bnews@example.com"],
+                   dsn.rcpt_parameters("me+news@example.com"));
+        assert!(Dsn::default().rcpt_parameters("me@example.com").is_empty());
+    }
+}
@@ -0,0 +1,136 @@
+// The envelope of a mail transaction, prepared according to what the
+// server supports, so that the blocking and the asynchronous clients send
+// exactly the same commands.
+
+use crate::verbs::*;
+use crate::{address, Capabilities, Dsn, Error, Reply, RecipientReply};
+use crate::transparency::{MAX_LINE_LENGTH, longest_line};
+
+/// How the message is sent to the server
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Transfer {
+    /// With DATA, the lines are ended with CRLF and the dots are stuffed
+    Data,
+    /// With BDAT, the lines are ended with CRLF
+    Chunks,
+    /// With BDAT, exactly as it is (BINARYMIME)
+    Binary,
+}
+
+/// Everything that is sent before the message
+pub struct Envelope {
+    /// The sender, as it is sent to the server
+    pub from: String,
+    pub mail_from: String,
+    /// The recipients, as they are sent to the server
+    pub recipients: Vec<String>,
+    /// The RCPT TO commands, in the order of the recipients
+    pub rcpt_to: Vec<String>,
+    pub transfer: Transfer,
+}
+
+impl Envelope {
+    /// Checks the addresses and the message against what the server
+    /// accepts, and builds the commands with the parameters that the server
+    /// supports. The delivery status notifications are only requested if
+    /// the server supports DSN. Internationalized addresses are sent as
+    /// they are with SMTPUTF8, otherwise their domains are converted to
+    /// punycode.
+    pub fn new(capabilities: &Capabilities, from: &str, recipients: &[&str],
+               dsn: &Dsn, body: &[u8]) -> Result<Self, Error> {
+        address::check(from)?;
+        for recipient in recipients.iter() {
+            address::check(recipient)?;
+        }
+
+        if !capabilities.accepts_size(body.len()) {
+            return Err(Error::Protocol(
+                format!("The email is {} octets, but the server accepts at most {}",
+                        body.len(), capabilities.size.unwrap_or(0))));
+        }
+
+        let transfer = if capabilities.chunking && capabilities.binarymime {
+            Transfer::Binary
+        } else if capabilities.chunking {
+            Transfer::Chunks
+        } else {
+            Transfer::Data
+        };
+        if transfer != Transfer::Binary && longest_line(body) > MAX_LINE_LENGTH {
+            return Err(Error::Protocol(format!("Cannot send email with lines longer than {} octets",
+                                               MAX_LINE_LENGTH)));
+        }
+
+        let mut parameters = Vec::new();
+        if capabilities.size.is_some() {
+            parameters.push(format!("{}={}", SIZE, body.len()));
+        }
+        if transfer == Transfer::Binary {
+            parameters.push(format!("{}={}", BODY, BINARYMIME));
+        } else if !body.is_ascii() {
+            if capabilities.eightbitmime {
+                parameters.push(format!("{}={}", BODY, EIGHTBITMIME));
+            } else {
+                warn!("The server does not support 8BITMIME, but the email is not 7-bit");
+            }
+        }
+
+        let utf8 = !from.is_ascii() || recipients.iter().any(|recipient| !recipient.is_ascii());
+        let (from, recipients) = if utf8 && !capabilities.smtputf8 {
+            (address::to_ascii(from)?,
+             recipients.iter().map(|recipient| address::to_ascii(recipient))
+                 .collect::<Result<Vec<String>, Error>>()?)
+        } else {
+            (from.to_string(),
+             recipients.iter().map(|recipient| recipient.to_string()).collect())
+        };
+        if utf8 && capabilities.smtputf8 {
+            parameters.push(SMTPUTF8.to_string());
+        }
+
+        let no_dsn = Dsn::default();
+        let dsn = if capabilities.dsn {
+            dsn
+        } else {
+            if !dsn.is_empty() {
+                warn!("The server does not support DSN, no delivery status will be requested");
+            }
+            &no_dsn
+        };
+        parameters.extend(dsn.mail_parameters());
+
+        let mail_from = mail_from_command(&from, &parameters);
+        let rcpt_to = recipients.iter()
+            .map(|recipient| rcpt_to_command(recipient, &dsn.rcpt_parameters(recipient)))
+            .collect();
+
+        Ok(Envelope { from, mail_from, recipients, rcpt_to, transfer })
+    }
+}
+
+/// Matches the replies of the server back to the recipients, as they were
+/// requested rather than as they were sent
+pub(crate) fn recipient_replies(requested: &[&str], replies: Vec<Reply>) -> Vec<RecipientReply> {
+    requested.iter().zip(replies)
+        .map(|(recipient, reply)| RecipientReply { recipient: recipient.to_string(), reply })
+        .collect()
+}
+
+/// The MAIL FROM command, along with its ESMTP parameters
+fn mail_from_command(from: &str, parameters: &[String]) -> String {
+    with_parameters(format!("{} {}:<{}>", MAIL, FROM, from), parameters)
+}
+
+/// The RCPT TO command, along with its ESMTP parameters
+fn rcpt_to_command(recipient: &str, parameters: &[String]) -> String {
+    with_parameters(format!("{} {}:<{}>", RCPT, TO, recipient), parameters)
+}
+
+fn with_parameters(mut command: String, parameters: &[String]) -> String {
+    for parameter in parameters {
+        command.push(' ');
+        command.push_str(parameter);
+    }
+    command.push_str("\r\n");
+    command
+}
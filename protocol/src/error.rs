@@ -0,0 +1,149 @@
+// The errors of sending emails, along with whether retrying them later may
+// help.
+
+use crate::reply::{Reply, EnhancedStatus};
+use std::error;
+use std::fmt;
+use std::io;
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Error {
+    /// Reading from or writing to a connection or a file failed
+    Io(String),
+    /// The host name cannot be resolved
+    Dns(String),
+    /// The TLS connection cannot be established, because of the
+    /// certificates or the handshake, which do not get fixed by retrying.
+    /// The connection failing underneath the handshake is an Io error.
+    Tls(String),
+    /// The server refused the credentials, or the credentials cannot be
+    /// obtained
+    Auth(String),
+    /// The server replied negatively to a command
+    SmtpReply {
+        /// What was attempted, e.g. Cannot send email from me@example.com
+        context: String,
+        code: u16,
+        enhanced_status: Option<EnhancedStatus>,
+        /// The text of the reply, as the server sent it
+        text: String,
+    },
+    /// The server or the client does not follow the protocol, or the email
+    /// cannot be expressed in it
+    Protocol(String),
+    /// The configuration is invalid
+    Config(String),
+    /// The passwords cannot be encrypted or decrypted in memory
+    Vault(String),
+}
+
+impl Error {
+    /// The negative reply of the server to what was attempted
+    pub fn reply(context: &str, reply: &Reply) -> Self {
+        Error::SmtpReply {
+            context: context.to_string(),
+            code: reply.code,
+            enhanced_status: reply.enhanced_status,
+            text: reply.lines.join(" "),
+        }
+    }
+
+    /// Whether the same attempt may succeed later, without changing the
+    /// email or the configuration
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Io(_) | Error::Dns(_)  => true,
+            Error::SmtpReply { code, .. } => code / 100 == 4,
+            _                             => false,
+        }
+    }
+
+    /// The reply code of the server, if the error is a reply of the server
+    pub fn code(&self) -> Option<u16> {
+        match self {
+            Error::SmtpReply { code, .. } => Some(*code),
+            _                             => None,
+        }
+    }
+
+    /// Permanent negative replies to the authentication are rejected
+    /// credentials, transient ones are retried like any other reply
+    pub(crate) fn into_auth(self) -> Self {
+        match self {
+            Error::SmtpReply { .. } if !self.is_transient() => Error::Auth(self.to_string()),
+            error                                           => error,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(message)       |
+            Error::Dns(message)      |
+            Error::Tls(message)      |
+            Error::Auth(message)     |
+            Error::Protocol(message) |
+            Error::Config(message)   |
+            Error::Vault(message)    => write!(f, "{}", message),
+            Error::SmtpReply { context, code, text, .. } =>
+                write!(f, "{}: {} {}", context, code, text),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(line: &str) -> Reply {
+        Reply::parse(&[line]).unwrap()
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(Error::Io("The server closed the connection".to_string()).is_transient());
+        assert!(Error::Dns("Cannot resolve host example.com".to_string()).is_transient());
+        assert!(Error::reply("Failed to send email", &reply("451 4.3.0 Try again")).is_transient());
+        assert!(!Error::reply("Failed to send email", &reply("554 5.7.1 Spam")).is_transient());
+        assert!(!Error::Tls("Establishing TLS connection failed".to_string()).is_transient());
+        assert!(!Error::Protocol("Invalid email address".to_string()).is_transient());
+        assert!(!Error::Config("passwordeval is missing".to_string()).is_transient());
+    }
+
+    #[test]
+    fn test_reply_keeps_the_reply() {
+        let error = Error::reply("Cannot send email from me@example.com",
+                                 &reply("550 5.1.0 Unknown sender"));
+        assert_eq!(Some(550), error.code());
+        assert_eq!("Cannot send email from me@example.com: 550 5.1.0 Unknown sender",
+                   error.to_string());
+        match error {
+            Error::SmtpReply { enhanced_status, .. } =>
+                assert_eq!(Some(EnhancedStatus { class: 5, subject: 1, detail: 0 }),
+                           enhanced_status),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_into_auth() {
+        let rejected = Error::reply("Invalid username or password",
+                                    &reply("535 5.7.8 Authentication failed"));
+        assert_eq!(Error::Auth(
+            "Invalid username or password: 535 5.7.8 Authentication failed".to_string()),
+            rejected.into_auth());
+
+        let unavailable = Error::reply("Invalid username or password",
+                                       &reply("454 4.7.0 Temporary failure"));
+        assert_eq!(unavailable.clone(), unavailable.into_auth());
+    }
+}
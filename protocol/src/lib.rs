@@ -1,286 +1,1292 @@
 pub mod verbs;
+pub mod reply;
+pub mod transparency;
+pub mod dsn;
+pub mod envelope;
+pub mod async_raven;
+pub mod error;
+mod address;
+mod sasl;
 
 #[macro_use]
 extern crate log;
 
-#[macro_use]
-extern crate lazy_static;
-
 use crate::verbs::*;
-use base64::encode;
+pub use crate::reply::{Reply, ReplyClass, EnhancedStatus};
+pub use crate::dsn::Dsn;
+pub use crate::error::Error;
+use crate::transparency::{DataEncoder, crlf_line_endings};
+use crate::envelope::{Envelope, Transfer, recipient_replies};
+use base64::{encode, decode};
 use std::time::Duration;
-use regex::Regex;
 use std::fs::File;
 use std::io::prelude::*;
+use std::io::{self, BufReader, ErrorKind};
 use std::net::Shutdown;
-use native_tls::{TlsConnector, TlsStream, Certificate};
+use native_tls::{TlsConnector, TlsStream, Certificate, HandshakeError};
 use std::net::{TcpStream, ToSocketAddrs, IpAddr};
 
 
-pub trait Stream: Read + Write + Sized {
+pub trait Stream: BufRead + Write + Sized {
     fn close(&mut self);
 }
 
-#[derive(PartialEq, Debug)]
+/// A stream whose reads go through a buffer that lives as long as the
+/// connection, so that a reply is read in as few calls as the server sent
+/// it in, and nothing of the next one is lost
+pub struct BufStream<S: Read> {
+    inner: BufReader<S>,
+}
+
+impl<S: Read> BufStream<S> {
+    pub fn new(inner: S) -> Self {
+        BufStream { inner: BufReader::new(inner) }
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        self.inner.get_mut()
+    }
+
+    /// Whether the server sent anything that was not read yet
+    fn has_unread_data(&self) -> bool {
+        !self.inner.buffer().is_empty()
+    }
+
+    fn into_inner(self) -> S {
+        self.inner.into_inner()
+    }
+}
+
+impl<S: Read> Read for BufStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Read> BufRead for BufStream<S> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
+    }
+}
+
+impl<S: Read + Write> Write for BufStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.get_mut().flush()
+    }
+}
+
+pub type BufTcpStream = BufStream<TcpStream>;
+pub type BufTlsStream = BufStream<TlsStream<TcpStream>>;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum Authentication {
     None,
     Login,
+    Plain,
+    CramMd5,
+    ScramSha256,
     XAuth2,
+    OAuthBearer,
+}
+
+impl Authentication {
+    /// The mechanisms that authenticate with a password, strongest first
+    pub const PASSWORD_MECHANISMS: [Authentication; 4] = [
+        Authentication::ScramSha256,
+        Authentication::CramMd5,
+        Authentication::Plain,
+        Authentication::Login,
+    ];
+
+    /// Maps a SASL mechanism name, as advertised by the server, to its
+    /// authentication method
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_uppercase().as_ref() {
+            LOGIN         => Some(Authentication::Login),
+            PLAIN         => Some(Authentication::Plain),
+            CRAM_MD5      => Some(Authentication::CramMd5),
+            SCRAM_SHA_256 => Some(Authentication::ScramSha256),
+            XOAUTH2       => Some(Authentication::XAuth2),
+            OAUTHBEARER   => Some(Authentication::OAuthBearer),
+            _             => None,
+        }
+    }
+}
+
+/// Picks the first mechanism in `preferred` that the server offers
+pub fn negotiate(offered: &[Authentication], preferred: &[Authentication])
+        -> Option<Authentication> {
+    preferred.iter().find(|auth| offered.contains(auth)).cloned()
+}
+
+/// The reply of the server to the RCPT TO of a recipient
+#[derive(PartialEq, Debug, Clone)]
+pub struct RecipientReply {
+    pub recipient: String,
+    pub reply: Reply,
+}
+
+impl RecipientReply {
+    pub fn is_accepted(&self) -> bool {
+        self.reply.is_positive_completion()
+    }
+}
+
+/// The outcome of a mail transaction
+#[derive(PartialEq, Debug)]
+pub struct Delivery {
+    /// The replies to the recipients, in the order they were sent
+    pub recipients: Vec<RecipientReply>,
+    /// The reply to the message, None if every recipient was rejected and
+    /// so the message was never sent
+    pub reply: Option<Reply>,
+}
+
+impl Delivery {
+    pub fn rejected(&self) -> Vec<&RecipientReply> {
+        self.recipients.iter().filter(|recipient| !recipient.is_accepted()).collect()
+    }
+}
+
+#[derive(PartialEq, Debug, Default)]
+pub struct Capabilities {
+    pub auths: Vec<Authentication>,
+    pub starttls: bool,
+    pub pipelining: bool,
+    pub chunking: bool,
+    pub binarymime: bool,
+    /// The largest message that the server accepts, if it supports the SIZE
+    /// extension. Zero means that there is no fixed limit.
+    pub size: Option<usize>,
+    pub dsn: bool,
+    pub smtputf8: bool,
+    pub eightbitmime: bool,
+}
+
+impl Capabilities {
+    pub fn accepts_size(&self, size: usize) -> bool {
+        match self.size {
+            Some(limit) if limit > 0 => size <= limit,
+            _                        => true,
+        }
+    }
 }
 
-impl Stream for TlsStream<TcpStream> {
+impl Stream for BufTlsStream {
     fn close(&mut self) {
-        let _ = self.shutdown();
+        let _ = self.get_mut().shutdown();
     }
 }
 
-impl Stream for TcpStream {
+impl Stream for BufTcpStream {
     fn close(&mut self) {
-        let _ = self.shutdown(Shutdown::Both);
+        let _ = self.get_mut().shutdown(Shutdown::Both);
     }
 }
 
 pub trait Raven: Stream {
 
     fn create_connection(host: &str, port: u16,
-                         tiemout: Duration, cert_root: Option<String>) -> Result<Self, String>;
+                         tiemout: Duration, cert_root: Option<String>) -> Result<Self, Error>;
 
-    fn send_hello(&mut self, host: &str) -> Result<String, String> {
+    fn send_hello(&mut self, host: &str) -> Result<Reply, Error> {
         debug!("Shaking hands with the ESMTP server");
         self.send_or_err(
-            &format!("{} rusmtp.amanj.me\n", EHLO).as_bytes(),
-            &|res| is_ok(res, &"250"),
+            format!("{} rusmtp.amanj.me\r\n", EHLO).as_bytes(),
+            &|reply| reply.is_positive_completion(),
             &format!("SMTP Server {} does not support ESMTP", host))
     }
 
-    fn hand_shake(&mut self, host: &str) -> Result<Vec<Authentication>, String> {
-        let response = self.recieve()?;
-        debug!("{}", &response);
+    fn hand_shake(&mut self, host: &str) -> Result<Capabilities, Error> {
+        let reply = self.recieve()?;
+        debug!("{}", &reply);
 
         debug!("Checking the presence of ESMTP protocol");
-        let tokens = tokenize(&response);
-        let mut auths: Vec<Authentication> = Vec::new();
+        if reply.is_positive_completion() {
+            self.ehlo(host)
+        } else {
+            Err(Error::reply("Bad reply from server", &reply))
+        }
+    }
 
-        if tokens.get(0) == Some(&"220") {
+    fn ehlo(&mut self, host: &str) -> Result<Capabilities, Error> {
+        let reply = self.send_hello(host)?;
+        debug!("here is the response: {}", reply);
 
-            let response = self.send_hello(host)?;
+        let capabilities = parse_ehlo(&reply);
+        debug!("{:?}", capabilities);
 
-            let tokens = tokenize(&response);
+        Ok(capabilities)
+    }
 
-            if tokens.contains(&STARTTLS) {
-                debug!("Checking if TLS is supported");
-                let _ = self.send_or_err(
-                    &format!("{} rusmtp.amanj.me\n", STARTTLS).as_bytes(),
-                    &|res| is_ok(res, &"250"),
-                    "Cannot start a TLS connection")?;
+    fn authenticate_with_login(&mut self, username: &[u8], passwd: &[u8]) -> Result<Reply, Error> {
+       let _ = self.send_or_err(format!("{} {}\r\n", AUTH, LOGIN).as_bytes(),
+           &|reply| reply.is_positive_intermediate(),
+           "The server refused to start LOGIN authentication")?;
+       let _ = self.send_or_err(format!("{}\r\n", encode(username)).as_bytes(),
+           &|reply| reply.is_positive_intermediate(),
+           "Invalid username").map_err(Error::into_auth)?;
+       self.send_or_err(format!("{}\r\n", encode(passwd)).as_bytes(),
+           &|reply| reply.is_positive_completion(),
+           "Invalid username or password").map_err(Error::into_auth)
+    }
 
-                debug!("Shaking hands with the server again, but this time over TLS");
-                let _ = self.send_hello(host)?;
-            }
+    fn authenticate_with_password(&mut self, auth: Authentication, username: &str,
+                                  passwd: &[u8]) -> Result<Reply, Error> {
+       match auth {
+           Authentication::Login       => self.authenticate_with_login(username.as_bytes(), passwd),
+           Authentication::Plain       => self.authenticate_with_plain(username, passwd),
+           Authentication::CramMd5     => self.authenticate_with_cram_md5(username, passwd),
+           Authentication::ScramSha256 => self.authenticate_with_scram_sha256(username, passwd),
+           auth                        =>
+               Err(Error::Config(format!("{:?} does not authenticate with a password", auth))),
+       }
+    }
 
-            debug!("here is the response: {}", response);
-            if tokens.contains(&LOGIN) {
-                auths.push(Authentication::Login);
-            }
+    fn authenticate_with_plain(&mut self, username: &str, passwd: &[u8]) -> Result<Reply, Error> {
+       debug!("Authenticating with PLAIN");
+       let mut credentials = vec![0u8];
+       credentials.extend_from_slice(username.as_bytes());
+       credentials.push(0);
+       credentials.extend_from_slice(passwd);
+       self.send_or_err(format!("{} {} {}\r\n", AUTH, PLAIN, encode(&credentials)).as_bytes(),
+           &|reply| reply.is_positive_completion(),
+           "Invalid username or password").map_err(Error::into_auth)
+    }
 
-            if tokens.contains(&XOAUTH2) {
-                auths.push(Authentication::XAuth2);
-            }
-        } else {
-            return Err(format!("Bad reply from server, {}", response))
-        }
+    fn authenticate_with_cram_md5(&mut self, username: &str, passwd: &[u8]) -> Result<Reply, Error> {
+       debug!("Authenticating with CRAM-MD5");
+       let challenge = self.send_or_err(format!("{} {}\r\n", AUTH, CRAM_MD5).as_bytes(),
+           &|reply| reply.is_positive_intermediate(),
+           "The server refused to start CRAM-MD5 authentication")?;
+       let challenge = decode_challenge(&challenge)?;
+       let response = sasl::cram_md5_response(username, passwd, &challenge);
+       self.send_or_err(format!("{}\r\n", encode(&response)).as_bytes(),
+           &|reply| reply.is_positive_completion(),
+           "Invalid username or password").map_err(Error::into_auth)
+    }
 
-        if auths.is_empty() {
-            auths.push(Authentication::None)
-        }
+    fn authenticate_with_scram_sha256(&mut self, username: &str, passwd: &[u8])
+            -> Result<Reply, Error> {
+       debug!("Authenticating with SCRAM-SHA-256");
+       let client_first_bare = sasl::scram_client_first_bare(username, &sasl::scram_nonce()?);
+       let server_first = self.send_or_err(
+           format!("{} {} {}\r\n", AUTH, SCRAM_SHA_256,
+                   encode(&format!("n,,{}", client_first_bare))).as_bytes(),
+           &|reply| reply.is_positive_intermediate(),
+           "The server refused to start SCRAM-SHA-256 authentication")?;
+       let server_first = String::from_utf8(decode_challenge(&server_first)?)
+           .map_err(|_| Error::Protocol("The server sent an invalid SCRAM challenge".to_string()))?;
+
+       let proof = match sasl::scram_client_final(&client_first_bare, &server_first, passwd) {
+           Ok(proof)  => proof,
+           Err(error) => {
+               // Cancel the exchange, RFC 4954 section 4
+               let _ = self.recieve_after(b"*\r\n");
+               return Err(error);
+           },
+       };
 
-        debug!("{:?}", auths);
+       let server_final = self.send_or_err(
+           format!("{}\r\n", encode(&proof.client_final)).as_bytes(),
+           &|reply| reply.is_positive_intermediate() || reply.is_positive_completion(),
+           "Invalid username or password").map_err(Error::into_auth)?;
 
-        Ok(auths)
+       let verified = scram_verified(&server_final, &proof.server_signature);
+       if server_final.is_positive_intermediate() {
+           if !verified {
+               let _ = self.recieve_after(b"*\r\n");
+               return Err(scram_unverified());
+           }
+           self.send_or_err(b"\r\n",
+               &|reply| reply.is_positive_completion(),
+               "Invalid username or password").map_err(Error::into_auth)
+       } else if verified {
+           Ok(server_final)
+       } else {
+           Err(scram_unverified())
+       }
     }
 
-    fn authenticate_with_login(&mut self, username: &[u8], passwd: &[u8]) -> Result<String, String> {
-       self.send(format!("{} {}\n", AUTH, LOGIN).as_bytes());
-       let response = self.recieve()?;
-       debug!("{}", &response);
-       self.send(&encode(&username).as_bytes());
-       self.send(b"\n");
-       let response = self.recieve()?;
-       debug!("{}", &response);
-       self.send(&encode(&passwd).as_bytes());
-       self.send_or_err(b"\n",
-           &|res| is_ok(res, &"235"),
-           "Invalid username or password")
+    fn authenticate_with_xoauth2(&mut self, username: &str, token: &str) -> Result<Reply, Error> {
+       debug!("Authenticating with XOAUTH2");
+       let response = format!("{} {} {}\r\n", AUTH, XOAUTH2,
+                              encode(&xoauth2_response(username, token)));
+       self.send_or_err_with_sasl_error(response.as_bytes(), b"\r\n",
+           "Invalid username or access token")
     }
 
-    fn send_mail(&mut self, from: &str, recipients: &[&str], body: &[u8]) -> Result<String, String> {
-       let _ = self.send_or_err(
-          format!("{} {}:<{}>\r\n", MAIL, FROM, from).as_bytes(),
-           &|res| is_ok(res, &"250"),
-           &format!("Cannot send email from {}", from))?;
+    fn authenticate_with_oauthbearer(&mut self, username: &str, host: &str, port: u16,
+                                     token: &str) -> Result<Reply, Error> {
+       debug!("Authenticating with OAUTHBEARER");
+       let response = format!("{} {} {}\r\n", AUTH, OAUTHBEARER,
+                              encode(&oauthbearer_response(username, host, port, token)));
+       // RFC 7628 section 3.2.3, the client acknowledges the error
+       // challenge with a single %x01
+       self.send_or_err_with_sasl_error(response.as_bytes(), b"AQ==\r\n",
+           "Invalid username or access token")
+    }
 
-       for recipient in recipients.iter() {
-          let _ = self.send_or_err(
-              format!("{} {}:<{}>\r\n", RCPT, TO, recipient).as_bytes(),
-              &|res| is_ok(res, &"250"),
-              &format!("Cannot send email to {}", recipient))?;
+    fn send_or_err_with_sasl_error(&mut self, msg: &[u8], acknowledgement: &[u8],
+                                   on_failure_msg: &str) -> Result<Reply, Error> {
+       let reply = self.recieve_after(msg)?;
+       if reply.is_positive_completion() {
+           Ok(reply)
+       } else if reply.is_positive_intermediate() {
+           // The server sends a base64 encoded JSON error as a challenge,
+           // and only tells the final verdict after the client responds
+           let reply = self.recieve_after(acknowledgement)?;
+           Err(Error::reply(on_failure_msg, &reply).into_auth())
+       } else {
+           Err(Error::reply(on_failure_msg, &reply).into_auth())
        }
+    }
+
+    /// Sends the email with BDAT when the server supports CHUNKING, and
+    /// with DATA otherwise. If the server supports BINARYMIME as well, the
+    /// body is sent exactly as it is. The delivery status notifications are
+    /// only requested if the server supports DSN. Internationalized
+    /// addresses are sent as they are with SMTPUTF8, otherwise their domains
+    /// are converted to punycode.
+    ///
+    /// The message is sent to the accepted recipients, even if the others
+    /// are rejected.
+    fn send_mail(&mut self, capabilities: &Capabilities, from: &str, recipients: &[&str],
+                 dsn: &Dsn, body: &[u8]) -> Result<Delivery, Error> {
+       let envelope = Envelope::new(capabilities, from, recipients, dsn, body)?;
+
+       let replies = if capabilities.pipelining {
+           self.send_envelope_pipelined(&envelope)?
+       } else {
+           self.send_envelope(&envelope)?
+       };
+       let recipients = recipient_replies(recipients, replies);
+
+       if !recipients.iter().any(|recipient| recipient.is_accepted()) {
+           let _ = self.recieve_after(format!("{}\r\n", RSET).as_bytes());
+           return Ok(Delivery { recipients, reply: None });
+       }
+
+       let reply = match envelope.transfer {
+           Transfer::Binary => self.send_chunks(body)?,
+           Transfer::Chunks => self.send_chunks(&crlf_line_endings(body))?,
+           Transfer::Data   => self.send_data(body)?,
+       };
+       Ok(Delivery { recipients, reply: Some(reply) })
+    }
 
+    /// Aborts the current mail transaction, if any, so that the connection
+    /// can be used for the next message.
+    fn reset(&mut self) -> Result<Reply, Error> {
+       self.send_or_err(format!("{}\r\n", RSET).as_bytes(),
+              &|reply| reply.is_positive_completion(),
+              "Cannot reset the mail transaction")
+    }
+
+    /// Checks that the server is still there, before reusing an idle
+    /// connection.
+    fn noop(&mut self) -> Result<Reply, Error> {
+       self.send_or_err(format!("{}\r\n", NOOP).as_bytes(),
+              &|reply| reply.is_positive_completion(),
+              "The connection is not usable anymore")
+    }
+
+    /// Ends the session and closes the connection, even if the server does
+    /// not reply to QUIT.
+    fn quit(&mut self) -> Result<Reply, Error> {
+       let reply = self.send_or_err(format!("{}\r\n", QUIT).as_bytes(),
+              &|reply| reply.is_positive_completion(),
+              "The server did not close the session cleanly");
+       self.close();
+       reply
+    }
+
+    fn send_data(&mut self, body: &[u8]) -> Result<Reply, Error> {
        let _ = self.send_or_err(format!("{}\r\n", DATA).as_bytes(),
-              &|res| is_ok(res, &"354"),
+              &|reply| reply.is_positive_intermediate(),
               "Cannot start sending email")?;
 
-       self.send(body);
-       self.send_or_err(b"\r\n.\r\n",
-           &|res| is_ok(res, &"250"),
-           "Failed to send email")
+       let mut encoder = DataEncoder::new(&mut *self);
+       encoder.write_all(body)
+           .and_then(|_| encoder.finish())
+           .map_err(|e| match e.kind() {
+               ErrorKind::InvalidData => Error::Protocol(format!("Failed to send email: {}", e)),
+               _                      => Error::Io(format!("Failed to send email: {}", e)),
+           })?;
+
+       let reply = self.recieve()?;
+       debug!("{}", &reply);
+       if reply.is_positive_completion() {
+           Ok(reply)
+       } else {
+           Err(Error::reply("Failed to send email", &reply))
+       }
+    }
+
+    /// Sends the body in BDAT chunks (RFC 3030), waiting for the reply of
+    /// every chunk before sending the next one.
+    fn send_chunks(&mut self, body: &[u8]) -> Result<Reply, Error> {
+       let chunks: Vec<&[u8]> = if body.is_empty() {
+           vec![body]
+       } else {
+           body.chunks(CHUNK_SIZE).collect()
+       };
+
+       let count = chunks.len();
+       for (index, chunk) in chunks.into_iter().enumerate() {
+           let last = index + 1 == count;
+           if last {
+               self.send(format!("{} {} {}\r\n", BDAT, chunk.len(), LAST).as_bytes());
+           } else {
+               self.send(format!("{} {}\r\n", BDAT, chunk.len()).as_bytes());
+           }
+           self.send(chunk);
+
+           let reply = self.recieve()?;
+           debug!("{}", &reply);
+           if !reply.is_positive_completion() {
+               return Err(Error::reply("Failed to send email", &reply));
+           }
+           if last {
+               return Ok(reply);
+           }
+       }
+       unreachable!("There is always a last chunk")
+    }
+
+    /// Sends MAIL FROM and then RCPT TO of every recipient, and returns
+    /// the replies to the recipients.
+    fn send_envelope(&mut self, envelope: &Envelope) -> Result<Vec<Reply>, Error> {
+       let _ = self.send_or_err(envelope.mail_from.as_bytes(),
+           &|reply| reply.is_positive_completion(),
+           &format!("Cannot send email from {}", envelope.from))?;
+
+       let mut replies = Vec::new();
+       for (recipient, rcpt_to) in envelope.recipients.iter().zip(&envelope.rcpt_to) {
+          let reply = self.recieve_after(rcpt_to.as_bytes())?;
+          debug!("{}", &reply);
+          if !reply.is_positive_completion() {
+              warn!("Cannot send email to {}: {}", recipient, reply);
+          }
+          replies.push(reply);
+       }
+       Ok(replies)
+    }
+
+    /// Sends MAIL FROM and all the RCPT TO commands in one batch, and then
+    /// matches the replies back to the commands in order (RFC 2920). DATA
+    /// is left out of the batch, so that the transaction can still be
+    /// abandoned if every recipient is rejected.
+    fn send_envelope_pipelined(&mut self, envelope: &Envelope) -> Result<Vec<Reply>, Error> {
+       debug!("Pipelining the envelope of {} recipients", envelope.recipients.len());
+       let batch = envelope.rcpt_to.concat();
+       self.send(format!("{}{}", envelope.mail_from, batch).as_bytes());
+
+       let mail_reply = self.recieve()?;
+       debug!("{}", &mail_reply);
+
+       // Every command gets a reply, even when MAIL FROM is rejected
+       let mut replies = Vec::new();
+       for recipient in envelope.recipients.iter() {
+           let reply = self.recieve()?;
+           debug!("{}", &reply);
+           if !reply.is_positive_completion() {
+               warn!("Cannot send email to {}: {}", recipient, reply);
+           }
+           replies.push(reply);
+       }
+
+       if mail_reply.is_positive_completion() {
+           Ok(replies)
+       } else {
+           let _ = self.recieve_after(format!("{}\r\n", RSET).as_bytes());
+           Err(Error::reply(&format!("Cannot send email from {}", envelope.from), &mail_reply))
+       }
     }
 
     fn send_or_err(&mut self, msg: &[u8],
-                      check: &Fn(&str) -> bool,
-                      on_failure_msg: &str) -> Result<String, String> {
+                      check: &dyn Fn(&Reply) -> bool,
+                      on_failure_msg: &str) -> Result<Reply, Error> {
+       let reply = self.recieve_after(msg)?;
+       if check(&reply) {
+           Ok(reply)
+       } else {
+           Err(Error::reply(on_failure_msg, &reply))
+       }
+    }
+
+    fn recieve_after(&mut self, msg: &[u8]) -> Result<Reply, Error> {
        self.send(msg);
-       let response = self.recieve()?;
-       debug!("{}", &response);
-       let res =  true_or_err(
-           check(&response),
-           on_failure_msg);
-       res.map(|_| response.to_string())
+       let reply = self.recieve()?;
+       debug!("{}", &reply);
+       Ok(reply)
     }
 
-    fn recieve(&mut self) -> Result<String, String> {
-        let mut aggregated = Vec::new();
+    /// Reads a complete reply, i.e. up to and including the line that
+    /// has a space after the reply code.
+    fn recieve(&mut self) -> Result<Reply, Error> {
+        let mut lines = Vec::new();
         loop {
-            let mut response = [0; 10];
-            let res = self.read(&mut response);
-            match res {
-                Err(_)   =>
-                    break,
-                Ok(0)    =>
-                    break,
-                Ok(n)    => {
-                    aggregated.extend(&response[0..n]);
-                    if n < 10 {
-                        break
-                    }
-                },
+            let line = self.read_reply_line()?;
+            let (_, last, _) = reply::parse_line(&line)?;
+            lines.push(line);
+            if last {
+                break;
             }
         }
 
-        let response = std::str::from_utf8(&aggregated.as_slice());
-        let res =
-            if response.is_err() {
-                return Err("Cannot decode SMTP server's resposne".to_string())
-            } else {
-                response.unwrap()
-            };
-        if RE.is_match(&res) {
-            Ok(res.to_string())
+        let lines: Vec<&str> = lines.iter().map(|line| line.as_str()).collect();
+        Reply::parse(&lines)
+    }
+
+    /// Reads a single line, anything after it stays in the buffer of the
+    /// stream for the next reply.
+    fn read_reply_line(&mut self) -> Result<String, Error> {
+        let mut line = Vec::new();
+        let limit = MAX_REPLY_LINE_LENGTH as u64 + 1;
+        if let Err(e) = Read::take(&mut *self, limit).read_until(b'\n', &mut line) {
+            return Err(Error::Io(format!("Cannot read the reply of the server: {}", e)));
+        }
+
+        if line.last() == Some(&b'\n') {
+            line.pop();
+        } else if line.len() as u64 == limit {
+            return Err(Error::Protocol("The reply of the server is too long".to_string()));
         } else {
-            Err(format!("Something went wrong, {}", res.to_string()))
+            return Err(Error::Io("The server closed the connection".to_string()));
+        }
+        if line.last() == Some(&b'\r') {
+            line.pop();
         }
+
+        String::from_utf8(line)
+            .map_err(|_| Error::Protocol("Cannot decode SMTP server's resposne".to_string()))
     }
 
     fn send(&mut self, msg: &[u8]) {
-        let _ = self.write(msg);
+        let _ = self.write_all(msg);
     }
 }
 
-impl Raven for TlsStream<TcpStream> {
-    fn create_connection(host: &str, port: u16,
-                         timeout: Duration,
-                         cert_root: Option<String>) -> Result<Self, String> {
-        debug!("Securing connection with {}", host);
-        let mut connector_builder = TlsConnector::builder();
+/// A plain connection that can be upgraded to TLS in the middle of an
+/// SMTP session, as described in RFC 3207.
+pub trait StartTls: Raven {
+    fn start_tls(self, host: &str, cert_root: Option<String>) -> Result<BufTlsStream, Error>;
+}
 
-        cert_root.iter().for_each(|cert_root| {
-            let f = File::open(cert_root);
-            if f.is_err() {
-                error!("Certificate file not found at: {}", cert_root);
-            }
+impl StartTls for BufTcpStream {
+    fn start_tls(mut self, host: &str, cert_root: Option<String>)
+            -> Result<BufTlsStream, Error> {
+        debug!("Checking if TLS is supported");
+        let _ = self.send_or_err(
+            format!("{}\r\n", STARTTLS).as_bytes(),
+            &|reply| reply.is_positive_completion(),
+            "Cannot start a TLS connection")?;
+        // Whatever follows the reply was sent in plain text, and would be
+        // taken as if it came over TLS, RFC 3207 section 6
+        if self.has_unread_data() {
+            return Err(Error::Tls(format!(
+                "{} sent more than the reply to STARTTLS, refusing to upgrade", host)));
+        }
 
-            let mut f = f.unwrap();
+        let connector = tls_connector(host, cert_root)?;
 
-            let mut contents: Vec<u8> = Vec::new();
-            let res = f.read_to_end(&mut contents);
-            if res.is_err() {
-                error!("Something went wrong reading the cert file: {}", cert_root);
-            }
-            let cert = Certificate::from_pem(contents.as_slice());
-            if cert.is_err() {
-                error!("Invalid certificate format, only pem is supported: {}", cert_root);
-            }
-            connector_builder.add_root_certificate(cert.unwrap());
-        });
+        debug!("Upgrading the connection with {} to TLS", host);
+        connector.connect(host, self.into_inner())
+            .map(BufStream::new)
+            .map_err(|e| handshake_error(host, e))
+    }
+}
 
-        let connector = connector_builder.build();
+impl Raven for BufTlsStream {
+    fn create_connection(host: &str, port: u16,
+                         timeout: Duration,
+                         cert_root: Option<String>) -> Result<Self, Error> {
+        debug!("Securing connection with {}", host);
+        let connector = tls_connector(host, cert_root.clone())?;
 
         debug!("Securing connection with {} on port {}", host, port);
-        let stream = TcpStream::create_connection(host, port, timeout, cert_root)?;
-
-        if connector.is_ok() {
-            debug!("Establishing TLS connection with {}", host);
-            let res = connector.unwrap().connect(host, stream);
-            if res.is_ok() {
-                Ok(res.unwrap())
-            } else {
-                Err(format!("Establishing TLS connection with {} failed", host))
-            }
-        } else {
-            Err(format!("Establishing TLS connection with {} failed", host))
-        }
+        let stream = BufTcpStream::create_connection(host, port, timeout, cert_root)?;
+
+        debug!("Establishing TLS connection with {}", host);
+        connector.connect(host, stream.into_inner())
+            .map(BufStream::new)
+            .map_err(|e| handshake_error(host, e))
     }
 }
 
-impl Raven for TcpStream {
+impl Raven for BufTcpStream {
     fn create_connection(host: &str, port: u16,
                          timeout: Duration,
-                         _cert_root: Option<String>) -> Result<Self, String> {
+                         _cert_root: Option<String>) -> Result<Self, Error> {
         debug!("Openning connection with {}", host);
         let ips = get_ip_address(host)?;
-        let ip = ips.first();
-        if ip.is_none() {
-            return Err(format!("Cannot resolve the host {}", host));
-        }
-
-        let ip = ip.unwrap();
+        let ip = match ips.first() {
+            Some(ip) => ip,
+            None     => return Err(Error::Dns(format!("Cannot resolve the host {}", host))),
+        };
 
         if let Ok(stream) = TcpStream::connect(format!("{}:{}", ip, port)) {
             let _ = stream.set_read_timeout(Some(timeout));
-            Ok(stream)
+            Ok(BufStream::new(stream))
         } else {
-            Err(format!("Cannot establish TCP connection with {}", host))
+            Err(Error::Io(format!("Cannot establish TCP connection with {}", host)))
+        }
+    }
+}
+
+const MAX_REPLY_LINE_LENGTH: usize = 64 * 1024;
+
+/// The size of the BDAT chunks
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+fn tls_connector(host: &str, cert_root: Option<String>) -> Result<TlsConnector, Error> {
+    let mut connector_builder = TlsConnector::builder();
+
+    if let Some(cert_root) = cert_root {
+        let mut f = File::open(&cert_root)
+            .map_err(|_| Error::Config(format!("Certificate file not found at: {}", cert_root)))?;
+
+        let mut contents: Vec<u8> = Vec::new();
+        f.read_to_end(&mut contents)
+            .map_err(|_| Error::Config(
+                format!("Something went wrong reading the cert file: {}", cert_root)))?;
+        let cert = Certificate::from_pem(contents.as_slice())
+            .map_err(|_| Error::Config(
+                format!("Invalid certificate format, only pem is supported: {}", cert_root)))?;
+        connector_builder.add_root_certificate(cert);
+    }
+
+    connector_builder.build()
+        .map_err(|e| Error::Tls(format!("Establishing TLS connection with {} failed: {}", host, e)))
+}
+
+/// The failed TLS handshake with the host. The connection failing
+/// underneath, i.e. reset, closed or timed out, is an I/O error that may not
+/// happen again, unlike the certificates that are not trusted.
+pub(crate) fn tls_error(host: &str, error: &(dyn std::error::Error + 'static)) -> Error {
+    let message = format!("Establishing TLS connection with {} failed: {}", host, error);
+    // OpenSSL tells about the closed connection without an io::Error
+    if message.to_lowercase().contains("unexpected eof") {
+        return Error::Io(message);
+    }
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if error.is::<io::Error>() {
+            return Error::Io(message);
         }
+        source = error.source();
     }
+    Error::Tls(message)
 }
 
-fn true_or_err(flag: bool, error_message: &str) -> Result<(), String> {
-    if ! flag {
-        Err(error_message.to_string())
-    } else {
-        Ok(())
+fn handshake_error<S>(host: &str, error: HandshakeError<S>) -> Error {
+    match error {
+        HandshakeError::Failure(error) => tls_error(host, &error),
+        // The read timeout of the socket expired
+        HandshakeError::WouldBlock(_)  =>
+            Error::Io(format!("Establishing TLS connection with {} timed out", host)),
     }
 }
 
-fn get_ip_address(host: &str) -> Result<Vec<IpAddr>, String> {
-    let res = (host, 0).to_socket_addrs()
-        .map(|iter|
-             iter.map(|socket_address| socket_address.ip()).collect());
-    if res.is_err() {
-        Err(format!("Cannot resolve host {}", host))
-    } else {
-        Ok(res.unwrap())
+/// Whether the reply carries the server-final-message of SCRAM with the
+/// signature, RFC 5802 section 3, either as the challenge or along with 235
+fn scram_verified(reply: &Reply, server_signature: &[u8]) -> bool {
+    reply.lines.iter()
+        .flat_map(|line| line.split_whitespace())
+        .filter_map(|word| String::from_utf8(decode(word).ok()?).ok())
+        .any(|message| message.strip_prefix("v=").and_then(|v| decode(v).ok()).as_deref()
+             == Some(server_signature))
+}
+
+fn scram_unverified() -> Error {
+    Error::Auth("The server failed to prove it knows the password".to_string())
+}
+
+fn decode_challenge(reply: &Reply) -> Result<Vec<u8>, Error> {
+    let challenge = reply.lines.first().map(|line| line.trim()).unwrap_or("");
+    decode(challenge)
+        .map_err(|_| Error::Protocol("The server sent an invalid challenge".to_string()))
+}
+
+fn parse_ehlo(reply: &Reply) -> Capabilities {
+    let mut capabilities = Capabilities::default();
+
+    // The first line is the greeting, the rest are the extensions
+    for line in reply.lines.iter().skip(1) {
+        let mut keywords = line.split_whitespace();
+        let extension = keywords.next().unwrap_or("").to_uppercase();
+        if extension == STARTTLS {
+            capabilities.starttls = true;
+        } else if extension == PIPELINING {
+            capabilities.pipelining = true;
+        } else if extension == CHUNKING {
+            capabilities.chunking = true;
+        } else if extension == BINARYMIME {
+            capabilities.binarymime = true;
+        } else if extension == SMTPUTF8 {
+            capabilities.smtputf8 = true;
+        } else if extension == EIGHTBITMIME {
+            capabilities.eightbitmime = true;
+        } else if extension == DSN {
+            capabilities.dsn = true;
+        } else if extension == SIZE {
+            capabilities.size = Some(keywords.next()
+                .and_then(|limit| limit.parse().ok())
+                .unwrap_or(0));
+        } else if extension == AUTH || extension.starts_with("AUTH=") {
+            // Some old servers advertise the mechanisms as AUTH=LOGIN PLAIN
+            let first = extension.get(5..).filter(|first| !first.is_empty());
+            for auth in first.into_iter().chain(keywords).filter_map(Authentication::from_name) {
+                if !capabilities.auths.contains(&auth) {
+                    capabilities.auths.push(auth);
+                }
+            }
+        }
     }
+
+    if capabilities.auths.is_empty() {
+        capabilities.auths.push(Authentication::None)
+    }
+
+    capabilities
 }
 
-lazy_static! {
-    static ref RE: Regex = Regex::new(r"(?m)^\d{3} .*$").unwrap();
+fn xoauth2_response(username: &str, token: &str) -> String {
+    format!("user={}\x01auth=Bearer {}\x01\x01", username, token)
 }
 
-fn tokenize(response: &str) -> Vec<&str> {
-    response.split(&|ch| ch == ' ' || ch == '-').collect::<Vec<&str>>()
+fn oauthbearer_response(username: &str, host: &str, port: u16, token: &str) -> String {
+    format!("n,a={},\x01host={}\x01port={}\x01auth=Bearer {}\x01\x01",
+            username.replace('=', "=3D").replace(',', "=2C"), host, port, token)
 }
 
-fn is_ok(response: &str, code: &str) -> bool {
-    tokenize(response).get(0) == Some(&code)
+fn get_ip_address(host: &str) -> Result<Vec<IpAddr>, Error> {
+    (host, 0).to_socket_addrs()
+        .map(|iter|
+             iter.map(|socket_address| socket_address.ip()).collect())
+        .map_err(|e| Error::Dns(format!("Cannot resolve host {}: {}", host, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transparency::MAX_LINE_LENGTH;
+    use std::collections::VecDeque;
+
+    /// Serves each scripted server reply from its own `read` calls, so
+    /// that consecutive replies are not merged into one.
+    struct MockStream {
+        replies: VecDeque<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl MockStream {
+        fn new(replies: &[&[u8]]) -> Self {
+            MockStream {
+                replies: replies.iter().map(|reply| reply.to_vec()).collect(),
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.replies.front_mut() {
+                None        => Ok(0),
+                Some(reply) => {
+                    let n = std::cmp::min(buf.len(), reply.len());
+                    buf[..n].copy_from_slice(&reply[..n]);
+                    reply.drain(..n);
+                    if reply.is_empty() {
+                        self.replies.pop_front();
+                    }
+                    Ok(n)
+                },
+            }
+        }
+    }
+
+    impl BufRead for MockStream {
+        fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+            Ok(self.replies.front().map_or(&[], |reply| reply.as_slice()))
+        }
+
+        fn consume(&mut self, amt: usize) {
+            if let Some(reply) = self.replies.front_mut() {
+                reply.drain(..amt);
+                if reply.is_empty() {
+                    self.replies.pop_front();
+                }
+            }
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Stream for MockStream {
+        fn close(&mut self) {}
+    }
+
+    impl Raven for MockStream {
+        fn create_connection(_host: &str, _port: u16, _timeout: Duration,
+                             _cert_root: Option<String>) -> Result<Self, Error> {
+            Err(Error::Io("Mock streams cannot connect".to_string()))
+        }
+    }
+
+    impl Stream for BufStream<MockStream> {
+        fn close(&mut self) {}
+    }
+
+    impl Raven for BufStream<MockStream> {
+        fn create_connection(_host: &str, _port: u16, _timeout: Duration,
+                             _cert_root: Option<String>) -> Result<Self, Error> {
+            Err(Error::Io("Mock streams cannot connect".to_string()))
+        }
+    }
+
+    #[test]
+    fn test_replies_that_arrive_together() {
+        let mut stream = BufStream::new(MockStream::new(&[
+            b"250 2.0.0 Ok\r\n250-2.0.0 Ok\r\n250 2.0.0 Still ok\r\n221 2.0.0 Bye\r\n"]));
+        assert!(stream.noop().is_ok());
+        assert_eq!(vec!["2.0.0 Ok", "2.0.0 Still ok"], stream.reset().unwrap().lines);
+        assert!(stream.has_unread_data());
+        assert_eq!(221, stream.quit().unwrap().code);
+        assert!(!stream.has_unread_data());
+        assert_eq!(b"NOOP\r\nRSET\r\nQUIT\r\n".to_vec(), stream.get_mut().output);
+    }
+
+    #[test]
+    fn test_reply_lines_are_limited() {
+        let mut line = b"250 ".to_vec();
+        line.resize(MAX_REPLY_LINE_LENGTH, b'a');
+        line.extend_from_slice(b"\n");
+        assert!(MockStream::new(&[&line]).noop().is_ok());
+
+        line.insert(line.len() - 1, b'a');
+        assert_eq!(Err(Error::Protocol("The reply of the server is too long".to_string())),
+                   MockStream::new(&[&line]).noop());
+        assert_eq!(Err(Error::Io("The server closed the connection".to_string())),
+                   MockStream::new(&[b"250 Ok"]).noop());
+    }
+
+    #[test]
+    fn test_session_commands() {
+        let mut stream = MockStream::new(&[
+            b"250 2.0.0 Ok\r\n", b"250 2.0.0 Ok\r\n", b"221 2.0.0 Bye\r\n"]);
+        assert!(stream.noop().is_ok());
+        assert!(stream.reset().is_ok());
+        assert_eq!(221, stream.quit().unwrap().code);
+        assert_eq!(b"NOOP\r\nRSET\r\nQUIT\r\n".to_vec(), stream.output);
+    }
+
+    #[test]
+    fn test_noop_on_a_closed_connection() {
+        let mut stream = MockStream::new(&[b"421 4.4.2 Idle for too long\r\n"]);
+        assert!(stream.noop().unwrap_err().is_transient());
+        assert_eq!(Err(Error::Io("The server closed the connection".to_string())),
+                   stream.noop());
+    }
+
+    #[test]
+    fn test_ehlo_detects_starttls_in_multiline_reply() {
+        let mut stream = MockStream::new(&[
+            b"250-smtp.example.com\r\n250-STARTTLS\r\n250-AUTH LOGIN XOAUTH2\r\n250 SIZE 1024\r\n"]);
+        let capabilities = stream.ehlo("smtp.example.com").unwrap();
+        assert!(capabilities.starttls);
+        assert_eq!(vec![Authentication::Login, Authentication::XAuth2], capabilities.auths);
+        assert_eq!(b"EHLO rusmtp.amanj.me\r\n".to_vec(), stream.output);
+    }
+
+    #[test]
+    fn test_hand_shake_without_starttls() {
+        let mut stream = MockStream::new(&[
+            b"220 smtp.example.com ready\r\n",
+            b"250-smtp.example.com\r\n250 8BITMIME\r\n"]);
+        let capabilities = stream.hand_shake("smtp.example.com").unwrap();
+        assert!(!capabilities.starttls);
+        assert_eq!(vec![Authentication::None], capabilities.auths);
+    }
+
+    #[test]
+    fn test_parse_auth_capability_line() {
+        let capabilities = parse_ehlo(&Reply::parse(&[
+            "250-smtp.example.com greets you", "250-AUTH=LOGIN",
+            "250-AUTH PLAIN CRAM-MD5 scram-sha-256 GSSAPI", "250 8BITMIME"]).unwrap());
+        assert!(!capabilities.starttls);
+        assert_eq!(vec![Authentication::Login, Authentication::Plain,
+                        Authentication::CramMd5, Authentication::ScramSha256],
+                   capabilities.auths);
+    }
+
+    #[test]
+    fn test_greeting_line_is_not_a_capability() {
+        let capabilities = parse_ehlo(&Reply::parse(&["250 AUTH.example.com"]).unwrap());
+        assert_eq!(vec![Authentication::None], capabilities.auths);
+    }
+
+    #[test]
+    fn test_negotiate_strongest_mechanism() {
+        let offered = [Authentication::Login, Authentication::Plain, Authentication::CramMd5];
+        assert_eq!(Some(Authentication::CramMd5),
+                   negotiate(&offered, &Authentication::PASSWORD_MECHANISMS));
+        assert_eq!(Some(Authentication::Plain),
+                   negotiate(&offered, &[Authentication::ScramSha256, Authentication::Plain]));
+        assert_eq!(None, negotiate(&offered, &[Authentication::ScramSha256]));
+    }
+
+    #[test]
+    fn test_plain_authentication() {
+        let mut stream = MockStream::new(&[b"235 2.7.0 Accepted\r\n"]);
+        assert!(stream.authenticate_with_password(Authentication::Plain, "tim", b"tanstaaf").is_ok());
+        assert_eq!(format!("AUTH PLAIN {}\r\n", encode("\0tim\0tanstaaf")).into_bytes(),
+                   stream.output);
+    }
+
+    #[test]
+    fn test_cram_md5_authentication() {
+        let challenge = format!("334 {}\r\n", encode("<1896.697170952@postoffice.reston.mci.net>"));
+        let mut stream = MockStream::new(&[challenge.as_bytes(), b"235 2.7.0 Accepted\r\n"]);
+        assert!(stream.authenticate_with_password(
+            Authentication::CramMd5, "tim", b"tanstaaftanstaaf").is_ok());
+        let expected = format!("AUTH CRAM-MD5\r\n{}\r\n",
+                               encode("tim b913a602c7eda7a495b4e6e7334d3890"));
+        assert_eq!(expected.into_bytes(), stream.output);
+    }
+
+    #[test]
+    fn test_scram_server_signature_is_required() {
+        // RFC 7677 section 3
+        let signature = decode("6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=").unwrap();
+        let verifier = encode("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=");
+        let reply = |line: &str| Reply::parse(&[line]).unwrap();
+
+        assert!(scram_verified(&reply(&format!("334 {}", verifier)), &signature));
+        assert!(scram_verified(&reply(&format!("235 2.7.0 {}", verifier)), &signature));
+        assert!(!scram_verified(&reply("235 2.7.0 Accepted"), &signature));
+        assert!(!scram_verified(&reply(&format!("334 {}", encode("v=AAAA"))), &signature));
+        assert!(!scram_verified(&reply(&format!("235 {}", encode("e=other-error"))), &signature));
+    }
+
+    #[test]
+    fn test_xoauth2_authentication() {
+        let mut stream = MockStream::new(&[b"235 2.7.0 Accepted\r\n"]);
+        assert!(stream.authenticate_with_xoauth2("someone@example.com", "ya29.token").is_ok());
+        let expected = format!("AUTH XOAUTH2 {}\r\n",
+            encode("user=someone@example.com\x01auth=Bearer ya29.token\x01\x01"));
+        assert_eq!(expected.into_bytes(), stream.output);
+    }
+
+    #[test]
+    fn test_oauthbearer_authentication_failure() {
+        let mut stream = MockStream::new(&[
+            b"334 eyJzdGF0dXMiOiI0MDEifQ==\r\n",
+            b"535 5.7.8 Authentication failed\r\n"]);
+        let res = stream.authenticate_with_oauthbearer(
+            "someone@example.com", "smtp.example.com", 587, "token");
+        assert_eq!(Err(Error::Auth("Invalid username or access token: \
+                                    535 5.7.8 Authentication failed".to_string())), res);
+        assert!(stream.output.ends_with(b"AQ==\r\n"));
+    }
+
+    #[test]
+    fn test_oauthbearer_response_escapes_username() {
+        assert_eq!("n,a=a=2Cb=3Dc,\x01host=h\x01port=25\x01auth=Bearer t\x01\x01",
+                   oauthbearer_response("a,b=c", "h", 25, "t"));
+    }
+
+    #[test]
+    fn test_reply_split_across_segments() {
+        let mut stream = MockStream::new(&[
+            b"250-smtp.exa", b"mple.com\r\n250-AUTH PL", b"AIN\r", b"\n250 STARTTLS\r\n",
+            b"220 next reply\r\n"]);
+        let capabilities = stream.ehlo("smtp.example.com").unwrap();
+        assert!(capabilities.starttls);
+        assert_eq!(vec![Authentication::Plain], capabilities.auths);
+        assert_eq!(220, stream.recieve().unwrap().code);
+    }
+
+    #[test]
+    fn test_failure_carries_the_reply() {
+        let mut stream = MockStream::new(&[b"550 5.1.1 <nobody@example.com>: no such user\r\n"]);
+        let res = stream.send_or_err(b"RCPT TO:<nobody@example.com>\r\n",
+            &|reply| reply.is_positive_completion(),
+            "Cannot send email to nobody@example.com");
+        let error = res.unwrap_err();
+        assert_eq!(Some(550), error.code());
+        assert!(!error.is_transient());
+        assert_eq!("Cannot send email to nobody@example.com: \
+                    550 5.1.1 <nobody@example.com>: no such user", error.to_string());
+    }
+
+    #[test]
+    fn test_send_mail_encodes_the_body() {
+        let mut stream = MockStream::new(&[
+            b"250 2.1.0 Ok\r\n", b"250 2.1.5 Ok\r\n", b"354 End data with <CR><LF>.<CR><LF>\r\n",
+            b"250 2.0.0 Ok: queued\r\n"]);
+        let delivery = stream.send_mail(&Capabilities::default(),
+                                        "me@example.com", &["you@example.com"], &Dsn::default(),
+                                        b"Subject: hi\n\n.\nbye").unwrap();
+        assert_eq!(250, delivery.reply.unwrap().code);
+        assert_eq!(b"MAIL FROM:<me@example.com>\r\nRCPT TO:<you@example.com>\r\nDATA\r\n\
+                     Subject: hi\r\n\r\n..\r\nbye\r\n.\r\n".to_vec(), stream.output);
+    }
+
+    #[test]
+    fn test_send_mail_rejects_long_lines_before_the_transaction() {
+        let mut stream = MockStream::new(&[]);
+        let body = vec![b'x'; MAX_LINE_LENGTH + 1];
+        assert!(stream.send_mail(&Capabilities::default(), "me@example.com",
+                                 &["you@example.com"], &Dsn::default(), &body).is_err());
+        assert!(stream.output.is_empty());
+    }
+
+    #[test]
+    fn test_pipelined_envelope() {
+        let mut stream = MockStream::new(&[
+            b"250 2.1.0 Ok\r\n", b"250 2.1.5 Ok\r\n", b"250 2.1.5 Ok\r\n",
+            b"354 go ahead\r\n", b"250 2.0.0 queued\r\n"]);
+        let capabilities = Capabilities { pipelining: true, ..Capabilities::default() };
+        assert!(stream.send_mail(&capabilities, "me@example.com",
+                                 &["a@example.com", "b@example.com"],
+                                 &Dsn::default(), b"hi").is_ok());
+        assert!(stream.output.starts_with(
+            b"MAIL FROM:<me@example.com>\r\nRCPT TO:<a@example.com>\r\n\
+              RCPT TO:<b@example.com>\r\nDATA\r\n"));
+    }
+
+    #[test]
+    fn test_pipelined_envelope_reports_every_rejection() {
+        let mut stream = MockStream::new(&[
+            b"250 2.1.0 Ok\r\n", b"550 5.1.1 unknown\r\n", b"250 2.1.5 Ok\r\n",
+            b"452 4.2.2 mailbox full\r\n", b"354 go ahead\r\n", b"250 2.0.0 queued\r\n"]);
+        let capabilities = Capabilities { pipelining: true, ..Capabilities::default() };
+        let delivery = stream.send_mail(&capabilities, "me@example.com",
+                                        &["a@example.com", "b@example.com", "c@example.com"],
+                                        &Dsn::default(), b"hi").unwrap();
+        assert_eq!(250, delivery.reply.unwrap().code);
+        let rejected: Vec<(&str, u16)> = delivery.recipients.iter()
+            .filter(|recipient| !recipient.is_accepted())
+            .map(|recipient| (recipient.recipient.as_str(), recipient.reply.code))
+            .collect();
+        assert_eq!(vec![("a@example.com", 550), ("c@example.com", 452)], rejected);
+        assert!(stream.output.ends_with(b"RCPT TO:<c@example.com>\r\nDATA\r\nhi\r\n.\r\n"));
+    }
+
+    #[test]
+    fn test_pipelined_envelope_with_rejected_sender() {
+        let mut stream = MockStream::new(&[
+            b"553 5.7.1 not yours\r\n", b"503 5.5.1 no MAIL\r\n", b"250 2.0.0 Ok\r\n"]);
+        let capabilities = Capabilities { pipelining: true, ..Capabilities::default() };
+        assert_eq!(Err("Cannot send email from me@example.com: 553 5.7.1 not yours".to_string()),
+                   stream.send_mail(&capabilities, "me@example.com", &["a@example.com"],
+                                    &Dsn::default(), b"hi").map_err(|e| e.to_string()));
+        assert!(stream.output.ends_with(b"RCPT TO:<a@example.com>\r\nRSET\r\n"));
+    }
+
+    #[test]
+    fn test_every_recipient_rejected() {
+        let mut stream = MockStream::new(&[
+            b"250 2.1.0 Ok\r\n", b"550 5.1.1 unknown\r\n", b"250 2.0.0 Ok\r\n"]);
+        let delivery = stream.send_mail(&Capabilities::default(), "me@example.com",
+                                        &["a@b\u{fc}cher.de"], &Dsn::default(), b"hi").unwrap();
+        assert_eq!(None, delivery.reply);
+        assert_eq!(1, delivery.rejected().len());
+        // The recipients are reported as they were requested, not in punycode
+        assert_eq!("a@b\u{fc}cher.de", delivery.rejected()[0].recipient);
+        assert!(stream.output.ends_with(b"RCPT TO:<a@xn--bcher-kva.de>\r\nRSET\r\n"));
+    }
+
+    #[test]
+    fn test_bdat_with_binarymime() {
+        let mut stream = MockStream::new(&[
+            b"250 2.1.0 Ok\r\n", b"250 2.1.5 Ok\r\n", b"250 2.0.0 queued\r\n"]);
+        let capabilities = Capabilities { chunking: true, binarymime: true,
+                                          ..Capabilities::default() };
+        let body = b".\n\xff\x00binary\r";
+        assert!(stream.send_mail(&capabilities, "me@example.com",
+                                 &["you@example.com"], &Dsn::default(), body).is_ok());
+        let mut expected = b"MAIL FROM:<me@example.com> BODY=BINARYMIME\r\n\
+                             RCPT TO:<you@example.com>\r\nBDAT 11 LAST\r\n".to_vec();
+        expected.extend_from_slice(body);
+        assert_eq!(expected, stream.output);
+    }
+
+    #[test]
+    fn test_bdat_in_chunks() {
+        let mut stream = MockStream::new(&[
+            b"250 2.1.0 Ok\r\n", b"250 2.1.5 Ok\r\n",
+            b"250 2.0.0 chunk\r\n", b"250 2.0.0 queued\r\n"]);
+        let capabilities = Capabilities { chunking: true, ..Capabilities::default() };
+        // 174763 lines of "line\r\n" are two octets longer than a chunk
+        let body = b"line\n".repeat(174_763);
+        assert!(stream.send_mail(&capabilities, "me@example.com",
+                                 &["you@example.com"], &Dsn::default(), &body).is_ok());
+
+        let output = String::from_utf8(stream.output).unwrap();
+        assert!(output.starts_with("MAIL FROM:<me@example.com>\r\n"));
+        assert!(output.contains(&format!("BDAT {}\r\nline\r\n", CHUNK_SIZE)));
+        assert!(output.ends_with("\r\nlineBDAT 2 LAST\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_bdat_failure() {
+        let mut stream = MockStream::new(&[
+            b"250 2.1.0 Ok\r\n", b"250 2.1.5 Ok\r\n", b"552 5.3.4 too big\r\n"]);
+        let capabilities = Capabilities { chunking: true, ..Capabilities::default() };
+        assert_eq!(Err("Failed to send email: 552 5.3.4 too big".to_string()),
+                   stream.send_mail(&capabilities, "me@example.com",
+                                    &["you@example.com"], &Dsn::default(), b"")
+                       .map_err(|e| e.to_string()));
+        assert!(stream.output.ends_with(b"BDAT 0 LAST\r\n"));
+    }
+
+    #[test]
+    fn test_size_extension() {
+        let reply = Reply::parse(&["250-example.com", "250-SIZE 1000", "250 8BITMIME"]).unwrap();
+        assert_eq!(Some(1000), parse_ehlo(&reply).size);
+        let reply = Reply::parse(&["250-example.com", "250 SIZE"]).unwrap();
+        assert_eq!(Some(0), parse_ehlo(&reply).size);
+        assert!(parse_ehlo(&reply).accepts_size(usize::MAX));
+
+        let mut stream = MockStream::new(&[
+            b"250 2.1.0 Ok\r\n", b"250 2.1.5 Ok\r\n", b"354 go ahead\r\n",
+            b"250 2.0.0 queued\r\n"]);
+        let capabilities = Capabilities { size: Some(10), ..Capabilities::default() };
+        assert!(stream.send_mail(&capabilities, "me@example.com",
+                                 &["you@example.com"], &Dsn::default(), b"hello").is_ok());
+        assert!(stream.output.starts_with(b"MAIL FROM:<me@example.com> SIZE=5\r\n"));
+    }
+
+    #[test]
+    fn test_oversized_email_is_rejected_early() {
+        let mut stream = MockStream::new(&[]);
+        let capabilities = Capabilities { size: Some(4), ..Capabilities::default() };
+        assert!(!capabilities.accepts_size(5));
+        assert!(stream.send_mail(&capabilities, "me@example.com",
+                                 &["you@example.com"], &Dsn::default(), b"hello").is_err());
+        assert!(stream.output.is_empty());
+    }
+
+    #[test]
+    fn test_dsn_parameters() {
+        let mut stream = MockStream::new(&[
+            b"250 2.1.0 Ok\r\n", b"250 2.1.5 Ok\r\n", b"354 go ahead\r\n",
+            b"250 2.0.0 queued\r\n"]);
+        let capabilities = Capabilities { dsn: true, ..Capabilities::default() };
+        let dsn = Dsn {
+            notify: Some("FAILURE".to_string()),
+            ret: Some("HDRS".to_string()),
+            envid: Some("QQ314159".to_string()),
+        };
+        assert!(stream.send_mail(&capabilities, "me@example.com", &["you@example.com"],
+                                 &dsn, b"hi").is_ok());
+        assert!(stream.output.starts_with(
+            b"MAIL FROM:<me@example.com> RET=HDRS ENVID=QQ314159\r\n\
+              RCPT TO:<you@example.com> NOTIFY=FAILURE ORCPT=rfc822;you@example.com\r\n"));
+    }
+
+    #[test]
+    fn test_dsn_is_dropped_without_server_support() {
+        let mut stream = MockStream::new(&[
+            b"250 2.1.0 Ok\r\n", b"250 2.1.5 Ok\r\n", b"354 go ahead\r\n",
+            b"250 2.0.0 queued\r\n"]);
+        let dsn = Dsn { notify: Some("SUCCESS".to_string()), ..Dsn::default() };
+        assert!(stream.send_mail(&Capabilities::default(), "me@example.com",
+                                 &["you@example.com"], &dsn, b"hi").is_ok());
+        assert!(stream.output.starts_with(
+            b"MAIL FROM:<me@example.com>\r\nRCPT TO:<you@example.com>\r\nDATA\r\n"));
+    }
+
+    #[test]
+    fn test_smtputf8() {
+        let mut stream = MockStream::new(&[
+            b"250 2.1.0 Ok\r\n", b"250 2.1.5 Ok\r\n", b"354 go ahead\r\n",
+            b"250 2.0.0 queued\r\n"]);
+        let capabilities = Capabilities { smtputf8: true, eightbitmime: true,
+                                          ..Capabilities::default() };
+        assert!(stream.send_mail(&capabilities, "j\u{f6}rg@b\u{fc}cher.de", &["you@example.com"],
+                                 &Dsn::default(), "Gr\u{fc}\u{df}e".as_bytes()).is_ok());
+        assert!(stream.output.starts_with(
+            "MAIL FROM:<j\u{f6}rg@b\u{fc}cher.de> BODY=8BITMIME SMTPUTF8\r\n".as_bytes()));
+    }
+
+    #[test]
+    fn test_idn_without_smtputf8() {
+        let mut stream = MockStream::new(&[
+            b"250 2.1.0 Ok\r\n", b"250 2.1.5 Ok\r\n", b"354 go ahead\r\n",
+            b"250 2.0.0 queued\r\n"]);
+        assert!(stream.send_mail(&Capabilities::default(), "me@example.com",
+                                 &["you@b\u{fc}cher.de"], &Dsn::default(), b"hi").is_ok());
+        assert!(stream.output.starts_with(
+            b"MAIL FROM:<me@example.com>\r\nRCPT TO:<you@xn--bcher-kva.de>\r\n"));
+
+        let mut stream = MockStream::new(&[]);
+        assert!(stream.send_mail(&Capabilities::default(), "me@example.com",
+                                 &["j\u{f6}rg@example.com"], &Dsn::default(), b"hi").is_err());
+        assert!(stream.output.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_address_is_rejected() {
+        let mut stream = MockStream::new(&[]);
+        assert!(stream.send_mail(&Capabilities::default(), "me@example.com",
+                                 &["you@example.com>\r\nRSET"], &Dsn::default(),
+                                 b"hi").is_err());
+        assert!(stream.output.is_empty());
+    }
+
+    #[test]
+    fn test_hand_shake_rejects_bad_greeting() {
+        let mut stream = MockStream::new(&[b"554 go away\r\n"]);
+        assert!(stream.hand_shake("smtp.example.com").is_err());
+    }
+
+    #[test]
+    fn test_tls_handshake_that_fails_underneath_is_transient() {
+        use std::net::TcpListener;
+        use std::thread;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            // Never answers the first one, and closes the second one
+            let silent = listener.accept().unwrap();
+            drop(listener.accept().unwrap());
+            thread::sleep(Duration::from_secs(1));
+            drop(silent);
+        });
+        let timed_out = BufTlsStream::create_connection("localhost", port,
+                                                        Duration::from_millis(200), None);
+        let closed = BufTlsStream::create_connection("localhost", port,
+                                                     Duration::from_millis(200), None);
+        server.join().unwrap();
+        assert!(timed_out.err().unwrap().is_transient());
+        assert!(closed.err().unwrap().is_transient());
+
+        let reset = io::Error::new(ErrorKind::ConnectionReset, "Connection reset by peer");
+        assert_eq!(Error::Io("Establishing TLS connection with localhost failed: \
+                              Connection reset by peer".to_string()),
+                   tls_error("localhost", &reset));
+        let untrusted = Error::Config("certificate verify failed".to_string());
+        assert!(!tls_error("localhost", &untrusted).is_transient());
+    }
 }
@@ -0,0 +1,220 @@
+use crate::error::Error;
+use std::fmt;
+
+/// The class of a reply, i.e. the first digit of its code (RFC 5321, section 4.2.1)
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ReplyClass {
+    /// 2yz, the requested action has been completed
+    PositiveCompletion,
+    /// 3yz, the server waits for more information
+    PositiveIntermediate,
+    /// 4yz, the action was not taken, but it may succeed if retried later
+    TransientNegative,
+    /// 5yz, the action was not taken, and retrying will not help
+    PermanentNegative,
+}
+
+/// An enhanced mail system status code, as in 5.1.1 (RFC 3463)
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct EnhancedStatus {
+    pub class: u8,
+    pub subject: u16,
+    pub detail: u16,
+}
+
+impl EnhancedStatus {
+    fn parse(text: &str) -> Option<Self> {
+        let mut parts = text.split('.');
+        let class = parts.next()?.parse().ok()?;
+        let subject = parts.next()?.parse().ok()?;
+        let detail = parts.next()?.parse().ok()?;
+        if parts.next().is_some() || !(class == 2 || class == 4 || class == 5) {
+            return None;
+        }
+        Some(EnhancedStatus { class, subject, detail })
+    }
+}
+
+impl fmt::Display for EnhancedStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.class, self.subject, self.detail)
+    }
+}
+
+/// A complete, possibly multi-line, reply of an SMTP server
+#[derive(PartialEq, Debug, Clone)]
+pub struct Reply {
+    pub code: u16,
+    pub enhanced_status: Option<EnhancedStatus>,
+    /// The text of every line, without the reply code
+    pub lines: Vec<String>,
+}
+
+impl Reply {
+    pub fn class(&self) -> ReplyClass {
+        match self.code / 100 {
+            2 => ReplyClass::PositiveCompletion,
+            3 => ReplyClass::PositiveIntermediate,
+            4 => ReplyClass::TransientNegative,
+            _ => ReplyClass::PermanentNegative,
+        }
+    }
+
+    pub fn is_positive_completion(&self) -> bool {
+        self.class() == ReplyClass::PositiveCompletion
+    }
+
+    pub fn is_positive_intermediate(&self) -> bool {
+        self.class() == ReplyClass::PositiveIntermediate
+    }
+
+    pub fn is_transient(&self) -> bool {
+        self.class() == ReplyClass::TransientNegative
+    }
+
+    pub fn is_permanent(&self) -> bool {
+        self.class() == ReplyClass::PermanentNegative
+    }
+
+    /// The text of the reply, without the enhanced status code
+    pub fn message(&self) -> String {
+        let mut lines = self.lines.iter().map(|line| line.as_str());
+        let first = lines.next().map(|line| {
+            if self.enhanced_status.is_some() {
+                line.split_once(' ').map(|(_, text)| text).unwrap_or("")
+            } else {
+                line
+            }
+        });
+        first.into_iter().chain(lines).collect::<Vec<&str>>().join(" ")
+    }
+
+    /// The id that the server assigned to the accepted message, as in
+    /// "250 2.0.0 Ok: queued as 4F1A2B" or "250 OK id=1pX9Lm-0004"
+    pub fn queue_id(&self) -> Option<String> {
+        let message = self.message();
+        let mut words = message.split_whitespace();
+        while let Some(word) = words.next() {
+            let id = if word.eq_ignore_ascii_case("as") {
+                words.next()
+            } else {
+                word.strip_prefix("id=")
+            };
+            let id = id.map(|id| id.trim_end_matches(&['.', ',', ')'][..]));
+            if let Some(id) = id.filter(|id| !id.is_empty()) {
+                return Some(id.to_string());
+            }
+        }
+        None
+    }
+
+    /// Builds a reply out of its lines, without the line terminators
+    pub fn parse(lines: &[&str]) -> Result<Self, Error> {
+        let mut code = None;
+        let mut texts = Vec::new();
+        for (index, line) in lines.iter().enumerate() {
+            let (line_code, last, text) = parse_line(line)?;
+            if *code.get_or_insert(line_code) != line_code {
+                return Err(Error::Protocol(
+                    format!("Inconsistent reply codes in the reply: {}", line)));
+            }
+            if last != (index == lines.len() - 1) {
+                return Err(Error::Protocol(format!("Unexpected end of the reply: {}", line)));
+            }
+            texts.push(text.to_string());
+        }
+
+        let code = code.ok_or_else(|| Error::Protocol("Empty reply from the server".to_string()))?;
+        let enhanced_status = texts.first()
+            .and_then(|text| text.split(' ').next())
+            .and_then(EnhancedStatus::parse)
+            .filter(|status| u16::from(status.class) == code / 100);
+
+        Ok(Reply {
+            code,
+            enhanced_status,
+            lines: texts,
+        })
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.code, self.lines.join(" "))
+    }
+}
+
+/// Splits a reply line into its code, whether it is the last line of
+/// the reply, and its text
+pub(crate) fn parse_line(line: &str) -> Result<(u16, bool, &str), Error> {
+    let bytes = line.as_bytes();
+    if bytes.len() < 3 || !bytes[..3].iter().all(|b| b.is_ascii_digit())
+            || !(b'2'..=b'5').contains(&bytes[0]) {
+        return Err(Error::Protocol(format!("Malformed reply from the server: {}", line)));
+    }
+
+    let code = line[..3].parse().unwrap();
+    match bytes.get(3) {
+        None       => Ok((code, true, "")),
+        Some(b' ') => Ok((code, true, &line[4..])),
+        Some(b'-') => Ok((code, false, &line[4..])),
+        Some(_)    => Err(Error::Protocol(format!("Malformed reply from the server: {}", line))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_line_reply() {
+        let reply = Reply::parse(&["250 2.1.5 Recipient <a@b.c> ok"]).unwrap();
+        assert_eq!(250, reply.code);
+        assert_eq!(Some(EnhancedStatus { class: 2, subject: 1, detail: 5 }),
+                   reply.enhanced_status);
+        assert_eq!("Recipient <a@b.c> ok", reply.message());
+        assert!(reply.is_positive_completion());
+    }
+
+    #[test]
+    fn test_multi_line_reply() {
+        let reply = Reply::parse(&["250-smtp.example.com", "250-PIPELINING", "250 SIZE 10"])
+            .unwrap();
+        assert_eq!(vec!["smtp.example.com", "PIPELINING", "SIZE 10"], reply.lines);
+        assert_eq!(None, reply.enhanced_status);
+        assert_eq!("250 smtp.example.com PIPELINING SIZE 10", reply.to_string());
+    }
+
+    #[test]
+    fn test_reply_classes() {
+        assert_eq!(ReplyClass::PositiveIntermediate, Reply::parse(&["354 go ahead"]).unwrap().class());
+        assert!(Reply::parse(&["451 4.3.0 try later"]).unwrap().is_transient());
+        assert!(Reply::parse(&["550 5.1.1 no such user"]).unwrap().is_permanent());
+        assert!(Reply::parse(&["220"]).unwrap().is_positive_completion());
+    }
+
+    #[test]
+    fn test_enhanced_status_must_match_the_code() {
+        let reply = Reply::parse(&["250 5.0.0 odd"]).unwrap();
+        assert_eq!(None, reply.enhanced_status);
+        assert_eq!("5.0.0 odd", reply.message());
+    }
+
+    #[test]
+    fn test_queue_id() {
+        assert_eq!(Some("4F1A2B".to_string()),
+                   Reply::parse(&["250 2.0.0 Ok: queued as 4F1A2B"]).unwrap().queue_id());
+        assert_eq!(Some("1pX9Lm-0004".to_string()),
+                   Reply::parse(&["250 OK id=1pX9Lm-0004"]).unwrap().queue_id());
+        assert_eq!(None, Reply::parse(&["250 2.0.0 Ok"]).unwrap().queue_id());
+    }
+
+    #[test]
+    fn test_malformed_replies() {
+        assert!(Reply::parse(&[]).is_err());
+        assert!(Reply::parse(&["hello"]).is_err());
+        assert!(Reply::parse(&["250-first", "251 second"]).is_err());
+        assert!(Reply::parse(&["250 first", "250 second"]).is_err());
+        assert!(Reply::parse(&["250_first"]).is_err());
+    }
+}
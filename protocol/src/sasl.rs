@@ -0,0 +1,147 @@
+// The client side of the SASL mechanisms that need more than encoding
+// the credentials, see RFC 2195 (CRAM-MD5), RFC 5802 and RFC 7677
+// (SCRAM-SHA-256).
+
+use crate::error::Error;
+use base64::{encode, decode};
+use ring::{digest, hmac, pbkdf2};
+use ring::rand::{SecureRandom, SystemRandom};
+
+const MD5_BLOCK_SIZE: usize = 64;
+
+fn hmac_md5(key: &[u8], data: &[u8]) -> [u8; 16] {
+    let mut block = [0u8; MD5_BLOCK_SIZE];
+    if key.len() > MD5_BLOCK_SIZE {
+        block[..16].copy_from_slice(&md5::compute(key).0);
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = md5::Context::new();
+    inner.consume(block.iter().map(|b| b ^ 0x36).collect::<Vec<u8>>());
+    inner.consume(data);
+
+    let mut outer = md5::Context::new();
+    outer.consume(block.iter().map(|b| b ^ 0x5c).collect::<Vec<u8>>());
+    outer.consume(inner.compute().0);
+    outer.compute().0
+}
+
+/// The response to a CRAM-MD5 challenge, before base64 encoding
+pub(crate) fn cram_md5_response(username: &str, passwd: &[u8], challenge: &[u8]) -> String {
+    let digest: String = hmac_md5(passwd, challenge).iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("{} {}", username, digest)
+}
+
+pub(crate) fn scram_nonce() -> Result<String, Error> {
+    let mut nonce = [0u8; 18];
+    SystemRandom::new().fill(&mut nonce)
+        .map_err(|_| Error::Auth("Cannot generate a nonce".to_string()))?;
+    Ok(encode(&nonce))
+}
+
+/// The first message of SCRAM without the GS2 header, i.e. client-first-message-bare
+pub(crate) fn scram_client_first_bare(username: &str, nonce: &str) -> String {
+    format!("n={},r={}", username.replace('=', "=3D").replace(',', "=2C"), nonce)
+}
+
+pub(crate) struct ScramProof {
+    pub client_final: String,
+    pub server_signature: Vec<u8>,
+}
+
+/// Computes client-final-message from the server-first-message, and the
+/// server signature that the server has to prove in its final message.
+pub(crate) fn scram_client_final(client_first_bare: &str, server_first: &str,
+                                 passwd: &[u8]) -> Result<ScramProof, Error> {
+    let mut nonce = None;
+    let mut salt = None;
+    let mut iterations = None;
+    for attribute in server_first.split(',') {
+        if let Some(value) = attribute.strip_prefix("r=") {
+            nonce = Some(value);
+        } else if let Some(value) = attribute.strip_prefix("s=") {
+            salt = decode(value).ok();
+        } else if let Some(value) = attribute.strip_prefix("i=") {
+            iterations = value.parse::<u32>().ok();
+        }
+    }
+
+    let client_nonce = client_first_bare.split(",r=").nth(1).unwrap_or("");
+    let nonce = match nonce {
+        Some(nonce) if nonce.starts_with(client_nonce) && !client_nonce.is_empty() => nonce,
+        _ => return Err(Error::Protocol("The server sent an invalid SCRAM nonce".to_string())),
+    };
+    let salt = salt
+        .ok_or_else(|| Error::Protocol("The server sent an invalid SCRAM salt".to_string()))?;
+    let iterations = match iterations {
+        Some(iterations) if iterations > 0 => iterations,
+        _ => return Err(Error::Protocol(
+            "The server sent an invalid SCRAM iteration count".to_string())),
+    };
+
+    let mut salted_password = [0u8; 32];
+    pbkdf2::derive(&digest::SHA256, iterations, &salt, passwd, &mut salted_password);
+    let salted_password = hmac::SigningKey::new(&digest::SHA256, &salted_password);
+
+    let client_key = hmac::sign(&salted_password, b"Client Key");
+    let stored_key = digest::digest(&digest::SHA256, client_key.as_ref());
+
+    // "biws" is the base64 encoded GS2 header, "n,,"
+    let client_final_without_proof = format!("c=biws,r={}", nonce);
+    let auth_message = format!("{},{},{}", client_first_bare, server_first,
+                               client_final_without_proof);
+
+    let client_signature = hmac::sign(
+        &hmac::SigningKey::new(&digest::SHA256, stored_key.as_ref()),
+        auth_message.as_bytes());
+    let proof: Vec<u8> = client_key.as_ref().iter()
+        .zip(client_signature.as_ref().iter())
+        .map(|(key, signature)| key ^ signature)
+        .collect();
+
+    let server_key = hmac::sign(&salted_password, b"Server Key");
+    let server_signature = hmac::sign(
+        &hmac::SigningKey::new(&digest::SHA256, server_key.as_ref()),
+        auth_message.as_bytes());
+
+    Ok(ScramProof {
+        client_final: format!("{},p={}", client_final_without_proof, encode(&proof)),
+        server_signature: server_signature.as_ref().to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cram_md5_rfc2195_example() {
+        assert_eq!("tim b913a602c7eda7a495b4e6e7334d3890",
+                   cram_md5_response("tim", b"tanstaaftanstaaf",
+                       b"<1896.697170952@postoffice.reston.mci.net>"));
+    }
+
+    #[test]
+    fn test_scram_sha256_rfc7677_example() {
+        let client_first_bare = scram_client_first_bare("user", "rOprNGfwEbeRWgbNEkqO");
+        assert_eq!("n=user,r=rOprNGfwEbeRWgbNEkqO", client_first_bare);
+
+        let proof = scram_client_final(&client_first_bare,
+            "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+             s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096", b"pencil").unwrap();
+        assert_eq!("c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                    p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=", proof.client_final);
+        assert_eq!(decode("6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=").unwrap(),
+                   proof.server_signature);
+    }
+
+    #[test]
+    fn test_scram_rejects_foreign_nonce() {
+        let client_first_bare = scram_client_first_bare("user", "abc");
+        assert!(scram_client_final(&client_first_bare, "r=xyz,s=QSXCR+Q6sek8bf92,i=4096",
+                                   b"pencil").is_err());
+    }
+}
@@ -0,0 +1,189 @@
+// The transparency procedure of the DATA command, RFC 5321 section 4.5.2,
+// along with the line length limit of section 4.5.3.1.6.
+
+use std::io::{self, Write};
+
+/// The longest line that can be sent, not counting the CRLF
+pub const MAX_LINE_LENGTH: usize = 998;
+
+const BUFFER_SIZE: usize = 8 * 1024;
+
+/// Encodes the message for the DATA command as it is written: lines are
+/// terminated by CRLF, no matter if they end in a bare LF or a bare CR, and
+/// lines that start with a period are dot-stuffed.
+pub struct DataEncoder<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+    line_length: usize,
+    pending_cr: bool,
+}
+
+impl<W: Write> DataEncoder<W> {
+    pub fn new(inner: W) -> Self {
+        DataEncoder {
+            inner,
+            buffer: Vec::with_capacity(BUFFER_SIZE),
+            line_length: 0,
+            pending_cr: false,
+        }
+    }
+
+    fn end_line(&mut self) {
+        self.buffer.extend_from_slice(b"\r\n");
+        self.line_length = 0;
+    }
+
+    /// Terminates the last line, if needed, and writes the end of data
+    /// indicator.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.pending_cr || self.line_length > 0 {
+            self.end_line();
+        }
+        self.buffer.extend_from_slice(b".\r\n");
+        self.inner.write_all(&self.buffer)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for DataEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            if self.pending_cr {
+                self.pending_cr = false;
+                self.end_line();
+                if byte == b'\n' {
+                    continue;
+                }
+            }
+
+            match byte {
+                b'\r' => self.pending_cr = true,
+                b'\n' => self.end_line(),
+                _     => {
+                    if self.line_length == 0 && byte == b'.' {
+                        self.buffer.push(b'.');
+                        self.line_length += 1;
+                    }
+                    self.buffer.push(byte);
+                    self.line_length += 1;
+                    if self.line_length > MAX_LINE_LENGTH {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                            format!("Lines cannot be longer than {} octets", MAX_LINE_LENGTH)));
+                    }
+                },
+            }
+        }
+
+        if self.buffer.len() >= BUFFER_SIZE {
+            self.inner.write_all(&self.buffer)?;
+            self.buffer.clear();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.write_all(&self.buffer)?;
+        self.buffer.clear();
+        self.inner.flush()
+    }
+}
+
+/// Terminates every line with CRLF, for the messages that are sent with
+/// BDAT and so are not dot-stuffed
+pub fn crlf_line_endings(body: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(body.len());
+    let mut bytes = body.iter().peekable();
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            b'\r' => {
+                if bytes.peek() == Some(&&b'\n') {
+                    bytes.next();
+                }
+                result.extend_from_slice(b"\r\n");
+            },
+            b'\n' => result.extend_from_slice(b"\r\n"),
+            _     => result.push(byte),
+        }
+    }
+    result
+}
+
+/// The length of the longest line in the encoded message, not counting the
+/// line terminator
+pub fn longest_line(body: &[u8]) -> usize {
+    body.split(|&byte| byte == b'\n' || byte == b'\r')
+        .map(|line| line.len() + if line.first() == Some(&b'.') { 1 } else { 0 })
+        .max()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(chunks: &[&[u8]]) -> Vec<u8> {
+        let mut encoder = DataEncoder::new(Vec::new());
+        for chunk in chunks {
+            encoder.write_all(chunk).unwrap();
+        }
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_dot_stuffing() {
+        assert_eq!(b"..\r\n.\r\n".to_vec(), encode(&[b".\r\n"]));
+        assert_eq!(b"Hi\r\n..signature\r\n...\r\nend\r\n.\r\n".to_vec(),
+                   encode(&[b"Hi\r\n.signature\r\n..\r\nend\r\n"]));
+        assert_eq!(b"a.b\r\n.\r\n".to_vec(), encode(&[b"a.b"]));
+    }
+
+    #[test]
+    fn test_premature_end_of_data_is_escaped() {
+        assert_eq!(b"first\r\n..\r\nsecond\r\n.\r\n".to_vec(),
+                   encode(&[b"first\r\n.\r\nsecond"]));
+    }
+
+    #[test]
+    fn test_line_ending_normalization() {
+        assert_eq!(b"a\r\nb\r\nc\r\nd\r\n\r\n.\r\n".to_vec(),
+                   encode(&[b"a\nb\rc\r\nd\n\n"]));
+        assert_eq!(b"a\r\n\r\nb\r\n.\r\n".to_vec(), encode(&[b"a\r\rb"]));
+        assert_eq!(b"a\r\n\r\n.\r\n".to_vec(), encode(&[b"a\n\r"]));
+    }
+
+    #[test]
+    fn test_chunk_boundaries() {
+        assert_eq!(b"a\r\n..b\r\n.\r\n".to_vec(), encode(&[b"a\r", b"\n", b".", b"b"]));
+        assert_eq!(b"a\r\n..\r\n.\r\n".to_vec(), encode(&[b"a\r", b".\n"]));
+    }
+
+    #[test]
+    fn test_empty_body() {
+        assert_eq!(b".\r\n".to_vec(), encode(&[]));
+        assert_eq!(b".\r\n".to_vec(), encode(&[b""]));
+    }
+
+    #[test]
+    fn test_crlf_line_endings() {
+        assert_eq!(b"a\r\nb\r\n.c\r\n\r\n".to_vec(), crlf_line_endings(b"a\nb\r.c\r\n\r"));
+        assert_eq!(b"".to_vec(), crlf_line_endings(b""));
+    }
+
+    #[test]
+    fn test_line_length_limit() {
+        let longest = vec![b'x'; MAX_LINE_LENGTH];
+        let mut expected = longest.clone();
+        expected.extend_from_slice(b"\r\n.\r\n");
+        assert_eq!(expected, encode(&[&longest]));
+
+        let too_long = vec![b'x'; MAX_LINE_LENGTH + 1];
+        let mut encoder = DataEncoder::new(Vec::new());
+        assert!(encoder.write_all(&too_long).is_err());
+
+        assert_eq!(MAX_LINE_LENGTH + 1, longest_line(&too_long));
+        assert_eq!(3, longest_line(b"a\r\nabc\nab"));
+        assert_eq!(5, longest_line(b"a\r\n.abc"));
+        assert_eq!(0, longest_line(b""));
+    }
+}
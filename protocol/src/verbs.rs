@@ -14,5 +14,19 @@ pub const RCPT: &str = "RCPT";
 pub const TO: &str = "TO";
 pub const FROM: &str = "FROM";
 pub const LOGIN: &str = "LOGIN";
+pub const PLAIN: &str = "PLAIN";
+pub const CRAM_MD5: &str = "CRAM-MD5";
+pub const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
 pub const XOAUTH2: &str = "XOAUTH2";
+pub const OAUTHBEARER: &str = "OAUTHBEARER";
 pub const RSET: &str = "RSET";
+pub const PIPELINING: &str = "PIPELINING";
+pub const BDAT: &str = "BDAT";
+pub const LAST: &str = "LAST";
+pub const CHUNKING: &str = "CHUNKING";
+pub const BINARYMIME: &str = "BINARYMIME";
+pub const BODY: &str = "BODY";
+pub const SIZE: &str = "SIZE";
+pub const DSN: &str = "DSN";
+pub const SMTPUTF8: &str = "SMTPUTF8";
+pub const EIGHTBITMIME: &str = "8BITMIME";